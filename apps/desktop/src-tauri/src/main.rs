@@ -4,16 +4,20 @@
 
 use chrono::Utc;
 use notes_core::Document;
-use notes_oplog::{Operation, OperationType};
+use notes_oplog::{CompactionReport, OpLog, Operation, OperationType};
 use notes_plugin_host::PluginHost;
-use notes_store::{DocumentSummary, Store};
-use notes_sync::{DeviceIdentity, SyncService, TrustStore, TrustedDevice};
+use notes_store::{
+    Attachment, ConflictChoice, ConflictRecord, DocumentSummary, DocumentVersion, Link, OpOutcome,
+    Outcome, RebuildReport, ReindexReport, SearchResult, Store, StoreError, VaultWatcher,
+    VersionDiff,
+};
+use notes_sync::{
+    DeviceIdentity, NoiseTransport, PairingCode, PendingPairing, RejectReason, RejectedOp, SyncAck,
+    SyncError, SyncReport, SyncService, TrustStore, TrustedDevice,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,6 +31,10 @@ struct AppConfig {
     auto_sync_enabled: bool,
     #[serde(default)]
     transport_secret: Option<String>, // hex-encoded 32-byte PSK
+    #[serde(default)]
+    legacy_psk_transport: bool, // sync over the PSK transport instead of Noise
+    #[serde(default = "default_trash_retention_days")]
+    trash_retention_days: u32,
 }
 
 impl Default for AppConfig {
@@ -37,91 +45,12 @@ impl Default for AppConfig {
             sync_port: default_sync_port(),
             auto_sync_enabled: default_auto_sync(),
             transport_secret: None,
+            legacy_psk_transport: false,
+            trash_retention_days: default_trash_retention_days(),
         }
     }
 }
 
-#[derive(Clone)]
-struct OpLogStore {
-    path: PathBuf,
-    entries: Vec<Operation>,
-    seen: HashSet<String>,
-}
-
-impl OpLogStore {
-    /// Load op-log from disk and prime the dedup set; falls back to an empty log.
-    fn load(path: PathBuf) -> Self {
-        if path.exists() {
-            if let Ok(mut file) = File::open(&path) {
-                let mut buf = String::new();
-                if file.read_to_string(&mut buf).is_ok() {
-                    if let Ok(entries) = serde_json::from_str::<Vec<Operation>>(&buf) {
-                        let seen = entries.iter().map(|o| o.key()).collect();
-                        return Self {
-                            path,
-                            entries,
-                            seen,
-                        };
-                    }
-                }
-            }
-        }
-        Self {
-            path,
-            entries: Vec::new(),
-            seen: HashSet::new(),
-        }
-    }
-
-    /// Persist op-log to disk.
-    fn save(&self) -> Result<(), String> {
-        if let Some(parent) = self.path.parent() {
-            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
-        }
-        let data = serde_json::to_string_pretty(&self.entries).map_err(|e| e.to_string())?;
-        let mut file = File::create(&self.path).map_err(|e| e.to_string())?;
-        file.write_all(data.as_bytes()).map_err(|e| e.to_string())
-    }
-
-    /// Add a single op if not already seen; returns whether it was inserted.
-    fn add(&mut self, op: Operation) -> Result<bool, String> {
-        let key = op.key();
-        if self.seen.contains(&key) {
-            return Ok(false);
-        }
-        self.seen.insert(key);
-        self.entries.push(op);
-        self.save()?;
-        Ok(true)
-    }
-
-    fn merge(&mut self, incoming: &[Operation]) -> Result<Vec<Operation>, String> {
-        let mut accepted = Vec::new();
-        for op in incoming {
-            let key = op.key();
-            if self.seen.contains(&key) {
-                continue;
-            }
-            self.seen.insert(key);
-            self.entries.push(op.clone());
-            accepted.push(op.clone());
-        }
-        if !accepted.is_empty() {
-            self.save()?;
-        }
-        Ok(accepted)
-    }
-
-    /// Return all known ops (for sync payloads).
-    fn entries(&self) -> Vec<Operation> {
-        self.entries.clone()
-    }
-
-    fn contains(&self, op: &Operation) -> bool {
-        self.seen.contains(&op.key())
-    }
-}
-
 struct AppState {
     store: Arc<Mutex<Store>>,
     plugins: Mutex<PluginHost>,
@@ -129,12 +58,14 @@ struct AppState {
     config_path: PathBuf,
     device_identity: DeviceIdentity,
     trust_store: Arc<Mutex<TrustStore>>,
-    op_log: Arc<Mutex<OpLogStore>>,
+    op_log: Arc<Mutex<OpLog>>,
     auto_sync_enabled: Arc<std::sync::atomic::AtomicBool>,
     sync_events: Arc<Mutex<Vec<SyncEvent>>>,
     discovery_port: Arc<std::sync::atomic::AtomicU16>,
     sync_port: Arc<std::sync::atomic::AtomicU16>,
     psk: Arc<Mutex<Option<[u8; 32]>>>,
+    pending_pairings: Arc<Mutex<Vec<PendingPairing>>>,
+    vault_watcher: Mutex<Option<VaultWatcher>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -144,6 +75,23 @@ struct PeerView {
     public_key: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+struct PairingView {
+    device_id: String,
+    public_key: String,
+    code: PairingCode,
+}
+
+impl From<&PendingPairing> for PairingView {
+    fn from(pending: &PendingPairing) -> Self {
+        Self {
+            device_id: pending.device_id().to_string(),
+            public_key: pending.public_key().to_string(),
+            code: pending.code().clone(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NetworkSettings {
     discovery_port: u16,
@@ -199,6 +147,10 @@ fn create_document(
         "body": req.body,
     });
 
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let clock = store
+        .next_clock(&document_id, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
     let mut op = Operation {
         op_id: ulid::Ulid::new().to_string(),
         device_id: state.device_identity.device_id.clone(),
@@ -208,14 +160,14 @@ fn create_document(
         payload,
         before_hash: None,
         after_hash: None,
+        clock,
     };
 
-    let mut store = state.store.lock().map_err(|e| e.to_string())?;
     let res = store.apply(op.clone()).map_err(|e| e.to_string())?;
     if let Some(doc) = res.clone() {
         op.after_hash = Some(doc.hash_content());
         if let Ok(mut log) = state.op_log.lock() {
-            let _ = log.add(op);
+            let _ = log.append(op);
         }
         return Ok(doc);
     }
@@ -246,6 +198,15 @@ fn search_documents(
     store.search_documents(&query).map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+fn full_text_search(
+    state: tauri::State<AppState>,
+    query: String,
+) -> Result<Vec<SearchResult>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.search(&query).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn get_document(state: tauri::State<AppState>, id: String) -> Result<Document, String> {
     let store = state.store.lock().map_err(|e| e.to_string())?;
@@ -255,6 +216,27 @@ fn get_document(state: tauri::State<AppState>, id: String) -> Result<Document, S
         .ok_or_else(|| "not found".into())
 }
 
+#[tauri::command]
+fn get_backlinks(
+    state: tauri::State<AppState>,
+    id: String,
+) -> Result<Vec<DocumentSummary>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.backlinks(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_outgoing_links(state: tauri::State<AppState>, id: String) -> Result<Vec<Link>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.outgoing_links(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_unresolved_links(state: tauri::State<AppState>) -> Result<Vec<Link>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.unresolved_links().map_err(|e| e.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateDocRequest {
     pub id: String,
@@ -278,6 +260,9 @@ fn update_document(
         .map_err(|e| e.to_string())?
         .map(|d| d.hash_content())
         .or(req.before_hash.clone());
+    let clock = store
+        .next_clock(&req.id, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
     let mut op = Operation {
         op_id: ulid::Ulid::new().to_string(),
         device_id: state.device_identity.device_id.clone(),
@@ -287,6 +272,7 @@ fn update_document(
         payload,
         before_hash,
         after_hash: None,
+        clock,
     };
 
     let doc = store
@@ -294,34 +280,208 @@ fn update_document(
         .map_err(|e| e.to_string())?;
     op.after_hash = Some(doc.hash_content());
     if let Ok(mut log) = state.op_log.lock() {
-        let _ = log.add(op);
+        let _ = log.append(op);
     }
     Ok(doc)
 }
 
 #[tauri::command]
 fn delete_document(state: tauri::State<AppState>, id: String) -> Result<(), String> {
-    let store = state.store.lock().map_err(|e| e.to_string())?;
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
     let before_hash = store
         .load_document(&id)
         .map_err(|e| e.to_string())?
         .map(|d| d.hash_content());
-    store.delete_document(&id).map_err(|e| e.to_string())?;
+    let clock = store
+        .next_clock(&id, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
+    let op = Operation {
+        op_id: ulid::Ulid::new().to_string(),
+        device_id: state.device_identity.device_id.clone(),
+        timestamp: Utc::now().to_rfc3339(),
+        op_type: OperationType::DeleteDocument,
+        document_id: id,
+        payload: serde_json::Value::Null,
+        before_hash,
+        after_hash: None,
+        clock,
+    };
+    store.apply(op.clone()).map_err(|e| e.to_string())?;
     if let Ok(mut log) = state.op_log.lock() {
-        let _ = log.add(Operation {
-            op_id: ulid::Ulid::new().to_string(),
-            device_id: state.device_identity.device_id.clone(),
-            timestamp: Utc::now().to_rfc3339(),
-            op_type: OperationType::DeleteDocument,
-            document_id: id,
-            payload: serde_json::Value::Null,
-            before_hash,
-            after_hash: None,
-        });
+        let _ = log.append(op);
     }
     Ok(())
 }
 
+#[tauri::command]
+fn list_trash(state: tauri::State<AppState>) -> Result<Vec<TrashEntry>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.list_trash().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn restore_from_trash(state: tauri::State<AppState>, id: String) -> Result<Document, String> {
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let (doc, op) = store
+        .restore(&id, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
+    if let Ok(mut log) = state.op_log.lock() {
+        let _ = log.append(op);
+    }
+    Ok(doc)
+}
+
+#[tauri::command]
+fn get_trash_retention_days(state: tauri::State<AppState>) -> Result<u32, String> {
+    Ok(state
+        .config
+        .lock()
+        .map_err(|e| e.to_string())?
+        .trash_retention_days)
+}
+
+/// Change how long deleted notes stay in the trash and purge what is now past it.
+#[tauri::command]
+fn set_trash_retention_days(state: tauri::State<AppState>, days: u32) -> Result<usize, String> {
+    {
+        let mut cfg = state.config.lock().map_err(|e| e.to_string())?;
+        cfg.trash_retention_days = days;
+        save_config(&state.config_path, &cfg).map_err(|e| e.to_string())?;
+    }
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    purge_expired_trash(&store, days).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_conflicts(state: tauri::State<AppState>) -> Result<Vec<ConflictRecord>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store.list_conflicts().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn resolve_conflict(
+    state: tauri::State<AppState>,
+    conflict_id: i64,
+    choice: ConflictChoice,
+) -> Result<Option<Document>, String> {
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let (doc, op) = store
+        .resolve_conflict(conflict_id, choice, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
+    if let Ok(mut log) = state.op_log.lock() {
+        let _ = log.append(op);
+    }
+    Ok(doc)
+}
+
+#[tauri::command]
+fn get_document_history(
+    state: tauri::State<AppState>,
+    id: String,
+) -> Result<Vec<DocumentVersion>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    let log = state.op_log.lock().map_err(|e| e.to_string())?;
+    Ok(store.document_history(&id, log.entries()))
+}
+
+#[tauri::command]
+fn get_document_version(
+    state: tauri::State<AppState>,
+    id: String,
+    op_key: String,
+) -> Result<Document, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    let log = state.op_log.lock().map_err(|e| e.to_string())?;
+    store
+        .document_version(&id, log.entries(), &op_key)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn diff_document_versions(
+    state: tauri::State<AppState>,
+    id: String,
+    from_key: String,
+    to_key: String,
+) -> Result<VersionDiff, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    let log = state.op_log.lock().map_err(|e| e.to_string())?;
+    store
+        .diff_versions(&id, log.entries(), &from_key, &to_key)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn restore_document_version(
+    state: tauri::State<AppState>,
+    id: String,
+    op_key: String,
+) -> Result<Document, String> {
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let mut log = state.op_log.lock().map_err(|e| e.to_string())?;
+    let (doc, op) = store
+        .restore_version(
+            &id,
+            log.entries(),
+            &op_key,
+            &state.device_identity.device_id,
+        )
+        .map_err(|e| e.to_string())?;
+    log.append(op).map_err(|e| e.to_string())?;
+    Ok(doc)
+}
+
+#[tauri::command]
+fn attach_file(
+    state: tauri::State<AppState>,
+    document_id: String,
+    name: String,
+    data: Vec<u8>,
+) -> Result<Attachment, String> {
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let (attachment, op) = store
+        .attach_file(&document_id, &name, &data, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
+    if let Ok(mut log) = state.op_log.lock() {
+        let _ = log.append(op);
+    }
+    Ok(attachment)
+}
+
+#[tauri::command]
+fn detach_file(
+    state: tauri::State<AppState>,
+    document_id: String,
+    hash: String,
+) -> Result<(), String> {
+    let mut store = state.store.lock().map_err(|e| e.to_string())?;
+    let op = store
+        .detach_file(&document_id, &hash, &state.device_identity.device_id)
+        .map_err(|e| e.to_string())?;
+    if let Ok(mut log) = state.op_log.lock() {
+        let _ = log.append(op);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn list_attachments(
+    state: tauri::State<AppState>,
+    document_id: String,
+) -> Result<Vec<Attachment>, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    store
+        .list_attachments(&document_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_attachment_path(state: tauri::State<AppState>, hash: String) -> Result<String, String> {
+    let store = state.store.lock().map_err(|e| e.to_string())?;
+    let path = store.blob_path(&hash).map_err(|e| e.to_string())?;
+    Ok(path.to_string_lossy().to_string())
+}
+
 #[tauri::command]
 fn get_vault_root(state: tauri::State<AppState>) -> Result<String, String> {
     let store = state.store.lock().map_err(|e| e.to_string())?;
@@ -330,14 +490,51 @@ fn get_vault_root(state: tauri::State<AppState>) -> Result<String, String> {
 
 #[tauri::command]
 fn set_vault_root(state: tauri::State<AppState>, path: String) -> Result<String, String> {
-    let mut store = state.store.lock().map_err(|e| e.to_string())?;
-    *store = Store::with_root(&path).map_err(|e| e.to_string())?;
+    let root = {
+        let mut store = state.store.lock().map_err(|e| e.to_string())?;
+        *store = Store::with_root(&path).map_err(|e| e.to_string())?;
+        store.root_path()
+    };
     {
         let mut cfg = state.config.lock().map_err(|e| e.to_string())?;
         cfg.vault_root = path.clone();
         save_config(&state.config_path, &cfg).map_err(|e| e.to_string())?;
     }
-    Ok(store.root_path().to_string_lossy().to_string())
+    *state.vault_watcher.lock().map_err(|e| e.to_string())? = start_vault_watcher(
+        state.store.clone(),
+        state.op_log.clone(),
+        state.device_identity.device_id.clone(),
+    );
+    Ok(root.to_string_lossy().to_string())
+}
+
+/// Turn edits made to the vault by other programs into ops, first for whatever changed while
+/// we weren't watching, then as files change. Returns `None` if the vault can't be watched.
+fn start_vault_watcher(
+    store: Arc<Mutex<Store>>,
+    op_log: Arc<Mutex<OpLog>>,
+    device_id: String,
+) -> Option<VaultWatcher> {
+    let (root, missed) = {
+        let mut store = store.lock().ok()?;
+        (store.root_path(), store.scan_external_changes(&device_id))
+    };
+    if let (Ok(ops), Ok(mut log)) = (missed, op_log.lock()) {
+        let _ = log.merge(&ops);
+    }
+    let on_change = move |ids: std::collections::BTreeSet<String>| {
+        let ops: Vec<Operation> = match store.lock() {
+            Ok(mut store) => ids
+                .iter()
+                .filter_map(|id| store.ingest_external_change(id, &device_id).ok().flatten())
+                .collect(),
+            Err(_) => return,
+        };
+        if let Ok(mut log) = op_log.lock() {
+            let _ = log.merge(&ops);
+        }
+    };
+    VaultWatcher::start(root, std::time::Duration::from_millis(500), on_change).ok()
 }
 
 #[tauri::command]
@@ -371,74 +568,293 @@ fn discover_peers(state: tauri::State<AppState>) -> Result<Vec<PeerView>, String
         .collect())
 }
 
+/// Let sync sessions exchange attachment blobs straight from the vault's attachment directory
+/// and tell peers which ops the op-log has compacted away.
+fn session_transport(
+    net: notes_sync::NetTransport,
+    store: &Mutex<Store>,
+    op_log: &Mutex<OpLog>,
+) -> notes_sync::NetTransport {
+    let net = match store.lock() {
+        Ok(store) => net.with_blob_dir(store.attachments_dir()),
+        Err(_) => net,
+    };
+    match op_log.lock() {
+        Ok(log) => net.with_checkpoint(log.checkpoint().clone()),
+        Err(_) => net,
+    }
+}
+
+/// Drop the history the peer already folded into snapshots we hold too.
+fn adopt_peer_checkpoint(op_log: &Mutex<OpLog>, report: &SyncReport) {
+    if report.peer_checkpoint.is_empty() {
+        return;
+    }
+    if let Ok(mut log) = op_log.lock() {
+        let _ = log.adopt_checkpoint(&report.peer_checkpoint);
+    }
+}
+
 fn start_sync_listener(
     store: Arc<Mutex<Store>>,
-    op_log: Arc<Mutex<OpLogStore>>,
-    trust_path: PathBuf,
+    op_log: Arc<Mutex<OpLog>>,
+    device_identity: DeviceIdentity,
     sync_port: Arc<std::sync::atomic::AtomicU16>,
     psk: Arc<Mutex<Option<[u8; 32]>>>,
     sync_events: Arc<Mutex<Vec<SyncEvent>>>,
+    pending_pairings: Arc<Mutex<Vec<PendingPairing>>>,
 ) {
-    // Runs a tiny TCP listener loop that decrypts/verifies incoming envelopes, checks trust,
-    // applies only new ops to the store, and persists them to the op-log.
-    std::thread::spawn(move || loop {
-        let port = sync_port.load(std::sync::atomic::Ordering::Relaxed);
-        let psk_copy = psk.lock().ok().and_then(|p| p.clone());
-        let transport = notes_sync::NetTransport::new_with_psk(0, port, psk_copy);
-        let res = transport.serve_once(&trust_path, |peer_addr, incoming_ops| {
-            if let (Ok(mut log), Ok(mut store_guard)) = (op_log.lock(), store.lock()) {
-                let fresh: Vec<_> = incoming_ops
-                    .into_iter()
-                    .filter(|op| !log.contains(op))
-                    .collect();
-                let mut applied = Vec::new();
-                for op in fresh {
-                    if store_guard.apply(op.clone()).is_ok() {
-                        applied.push(op);
-                    } else {
+    // Keeps a listener bound on the sync port (rebinding when the port changes) and serves
+    // sync sessions: pushed ops are applied and acked, then the peer is sent what it lacks.
+    // Pairing requests are parked until the user compares the code and confirms.
+    std::thread::spawn(move || {
+        let trust_path = resolve_trust_path();
+        let mut bound_port = None;
+        let mut listener = None;
+        loop {
+            let port = sync_port.load(std::sync::atomic::Ordering::Relaxed);
+            let psk_copy = psk.lock().ok().and_then(|p| p.clone());
+            let transport = session_transport(
+                notes_sync::NetTransport::new_with_psk(0, port, psk_copy),
+                &store,
+                &op_log,
+            );
+            if bound_port != Some(port) {
+                bound_port = Some(port);
+                listener = match transport.listen() {
+                    Ok(l) => Some(l),
+                    Err(e) => {
+                        record_sync_event(
+                            &sync_events,
+                            SyncEvent {
+                                timestamp: Utc::now().to_rfc3339(),
+                                direction: "incoming".into(),
+                                peer: format!("0.0.0.0:{}", port),
+                                status: "error".into(),
+                                detail: Some(e.to_string()),
+                            },
+                        );
+                        None
+                    }
+                };
+            }
+            if let Some(listener) = &listener {
+                let local_ops = || {
+                    op_log
+                        .lock()
+                        .map(|l| l.entries().to_vec())
+                        .unwrap_or_default()
+                };
+                let res = transport.serve_once(
+                    listener,
+                    &trust_path,
+                    &device_identity,
+                    local_ops,
+                    |peer_addr, incoming_ops| {
+                        let ack = apply_incoming(&store, &op_log, incoming_ops);
                         record_sync_event(
                             &sync_events,
                             SyncEvent {
                                 timestamp: Utc::now().to_rfc3339(),
                                 direction: "incoming".into(),
                                 peer: peer_addr.to_string(),
-                                status: "apply_failed".into(),
-                                detail: Some("store rejected op".into()),
+                                status: ack_status(&ack).into(),
+                                detail: Some(describe_ack(&ack)),
+                            },
+                        );
+                        ack
+                    },
+                );
+                match res {
+                    Ok(Some(pending)) => {
+                        record_sync_event(
+                            &sync_events,
+                            SyncEvent {
+                                timestamp: Utc::now().to_rfc3339(),
+                                direction: "incoming".into(),
+                                peer: pending.device_id().to_string(),
+                                status: "pairing_request".into(),
+                                detail: Some(pending.code().digits.clone()),
                             },
                         );
+                        park_pairing(&pending_pairings, pending);
                     }
-                }
-                if !applied.is_empty() {
-                    let _ = log.merge(&applied);
-                    record_sync_event(
+                    Ok(None) => {}
+                    Err(e) => record_sync_event(
                         &sync_events,
                         SyncEvent {
                             timestamp: Utc::now().to_rfc3339(),
                             direction: "incoming".into(),
-                            peer: peer_addr.to_string(),
-                            status: "applied".into(),
-                            detail: Some(format!("{} ops", applied.len())),
+                            peer: format!("0.0.0.0:{}", port),
+                            status: "error".into(),
+                            detail: Some(e.to_string()),
                         },
-                    );
+                    ),
                 }
             }
-        });
-        if let Err(e) = res {
-            record_sync_event(
-                &sync_events,
-                SyncEvent {
-                    timestamp: Utc::now().to_rfc3339(),
-                    direction: "incoming".into(),
-                    peer: format!("0.0.0.0:{}", port),
-                    status: "error".into(),
-                    detail: Some(e.to_string()),
-                },
-            );
+            std::thread::sleep(std::time::Duration::from_millis(200));
         }
-        std::thread::sleep(std::time::Duration::from_millis(200));
     });
 }
 
+/// Apply ops received from a peer and persist the accepted ones to the op-log. Ops already in
+/// the log count as accepted.
+fn apply_incoming(
+    store: &Mutex<Store>,
+    op_log: &Mutex<OpLog>,
+    incoming: Vec<Operation>,
+) -> SyncAck {
+    // store before op-log, the order the commands take them in
+    let (mut store, mut log) = match (store.lock(), op_log.lock()) {
+        (Ok(store), Ok(log)) => (store, log),
+        _ => {
+            return SyncAck::reject_all(
+                &incoming,
+                RejectReason::Invalid("state unavailable".into()),
+            )
+        }
+    };
+    let mut ack = SyncAck::default();
+    let (known, new): (Vec<Operation>, Vec<Operation>) =
+        incoming.into_iter().partition(|op| log.contains(op));
+    ack.accepted.extend(known.iter().map(|op| op.key()));
+    let report = match store.apply_batch_with_history(&new, log.entries()) {
+        Ok(report) => report,
+        Err(e) => {
+            ack.rejected
+                .extend(SyncAck::reject_all(&new, RejectReason::Invalid(e.to_string())).rejected);
+            return ack;
+        }
+    };
+    let mut applied = std::collections::HashSet::new();
+    for OpOutcome { op_key, outcome } in report.outcomes {
+        match outcome {
+            Outcome::Applied | Outcome::Duplicate => {
+                ack.accepted.push(op_key.clone());
+                applied.insert(op_key);
+            }
+            // kept in the op-log as history, but reported apart from real writes
+            Outcome::Superseded => {
+                ack.superseded.push(op_key.clone());
+                applied.insert(op_key);
+            }
+            Outcome::Conflict => ack.rejected.push(RejectedOp {
+                op_key,
+                reason: RejectReason::Conflict,
+            }),
+            Outcome::HashMismatch => ack.rejected.push(RejectedOp {
+                op_key,
+                reason: RejectReason::HashMismatch,
+            }),
+            Outcome::Rejected { reason } => ack.rejected.push(RejectedOp {
+                op_key,
+                reason: RejectReason::Invalid(reason),
+            }),
+        }
+    }
+    let applied: Vec<Operation> = new
+        .into_iter()
+        .filter(|op| applied.contains(&op.key()))
+        .collect();
+    if !applied.is_empty() {
+        let _ = log.merge(&applied);
+    }
+    ack
+}
+
+fn ack_status(ack: &SyncAck) -> &'static str {
+    if ack.rejected.is_empty() {
+        "applied"
+    } else if ack.accepted.is_empty() {
+        "rejected"
+    } else {
+        "partial"
+    }
+}
+
+fn describe_ack(ack: &SyncAck) -> String {
+    let mut out = format!(
+        "{} accepted, {} rejected",
+        ack.accepted.len(),
+        ack.rejected.len()
+    );
+    if !ack.superseded.is_empty() {
+        out.push_str(&format!(", {} superseded", ack.superseded.len()));
+    }
+    if !ack.rejected.is_empty() {
+        let reasons: Vec<String> = ack
+            .rejected
+            .iter()
+            .map(|r| format!("{}: {}", r.op_key, r.reason))
+            .collect();
+        out.push_str(&format!(" ({})", reasons.join(", ")));
+    }
+    out
+}
+
+fn report_status(report: &SyncReport) -> &'static str {
+    let rejected = report
+        .push_ack
+        .as_ref()
+        .map(|a| a.rejected.len())
+        .unwrap_or(0)
+        + report.pull_ack.rejected.len();
+    if rejected > 0 {
+        "partial"
+    } else if report.sent == 0
+        && report.received == 0
+        && report.blobs_sent == 0
+        && report.blobs_received == 0
+    {
+        "up_to_date"
+    } else {
+        "synced"
+    }
+}
+
+/// Run a two-way sync session with `target` over Noise (IK when its public key is known), or
+/// over the legacy PSK transport `net` when `legacy_psk` is set.
+fn sync_with_peer(
+    legacy_psk: bool,
+    net: notes_sync::NetTransport,
+    identity: &DeviceIdentity,
+    ops: Vec<Operation>,
+    target: &str,
+    peer_key: Option<&str>,
+    apply: impl FnMut(Vec<Operation>) -> SyncAck,
+) -> Result<SyncReport, SyncError> {
+    let trust_path = resolve_trust_path();
+    let trust = TrustStore::load_or_default(&trust_path)?;
+    if legacy_psk {
+        SyncService::new(net, identity.clone(), ops).sync(target, &trust, apply)
+    } else {
+        let mut transport = NoiseTransport::from_net(net, identity.clone(), trust_path);
+        if let Some(key) = peer_key {
+            transport = transport.with_peer_key(target, key);
+        }
+        SyncService::new(transport, identity.clone(), ops).sync(target, &trust, apply)
+    }
+}
+
+fn describe_report(report: &SyncReport) -> String {
+    let pushed = match &report.push_ack {
+        Some(ack) => format!("sent {}: {}", report.sent, describe_ack(ack)),
+        None => format!("sent {} (no ack)", report.sent),
+    };
+    let mut detail = format!(
+        "{pushed}; received {}: {}",
+        report.received,
+        describe_ack(&report.pull_ack)
+    );
+    if report.blobs_sent > 0 || report.blobs_received > 0 {
+        detail.push_str(&format!(
+            "; attachments sent {}, received {}",
+            report.blobs_sent, report.blobs_received
+        ));
+    }
+    detail
+}
+
 fn start_advertise_loop(
     discovery_port: Arc<std::sync::atomic::AtomicU16>,
     identity: DeviceIdentity,
@@ -459,7 +875,8 @@ fn start_advertise_loop(
 }
 
 fn start_auto_sync(
-    op_log: Arc<Mutex<OpLogStore>>,
+    store: Arc<Mutex<Store>>,
+    op_log: Arc<Mutex<OpLog>>,
     trust_store: Arc<Mutex<TrustStore>>,
     device_identity: DeviceIdentity,
     enabled: Arc<std::sync::atomic::AtomicBool>,
@@ -467,8 +884,9 @@ fn start_auto_sync(
     discovery_port: Arc<std::sync::atomic::AtomicU16>,
     sync_port: Arc<std::sync::atomic::AtomicU16>,
     sync_events: Arc<Mutex<Vec<SyncEvent>>>,
+    legacy_psk: bool,
 ) {
-    // Periodically discovers peers and pushes ops to trusted+auto-approved devices.
+    // Periodically discovers peers and syncs both ways with trusted+auto-approved devices.
     std::thread::spawn(move || loop {
         if !enabled.load(std::sync::atomic::Ordering::Relaxed) {
             std::thread::sleep(std::time::Duration::from_secs(2));
@@ -477,11 +895,18 @@ fn start_auto_sync(
         let disc_port = discovery_port.load(std::sync::atomic::Ordering::Relaxed);
         let sync_port_val = sync_port.load(std::sync::atomic::Ordering::Relaxed);
         let psk_copy = psk.lock().ok().and_then(|p| p.clone());
-        let transport = notes_sync::NetTransport::new_with_psk(disc_port, sync_port_val, psk_copy);
+        let transport = session_transport(
+            notes_sync::NetTransport::new_with_psk(disc_port, sync_port_val, psk_copy),
+            &store,
+            &op_log,
+        );
         let peers = transport
             .listen_discovery(std::time::Duration::from_millis(250))
             .unwrap_or_default();
-        let ops = op_log.lock().map(|l| l.entries()).unwrap_or_default();
+        let ops = op_log
+            .lock()
+            .map(|l| l.entries().to_vec())
+            .unwrap_or_default();
         for peer in peers {
             let trusted = peer
                 .device_id
@@ -497,17 +922,27 @@ fn start_auto_sync(
             if !trusted {
                 continue;
             }
-            let svc = SyncService::new(transport.clone(), device_identity.clone(), ops.clone());
-            let result = svc.pair_and_sync(&peer.addr.to_string());
+            let result = sync_with_peer(
+                legacy_psk,
+                transport.clone(),
+                &device_identity,
+                ops.clone(),
+                &peer.addr.to_string(),
+                peer.public_key.as_deref(),
+                |incoming| apply_incoming(&store, &op_log, incoming),
+            );
+            if let Ok(report) = &result {
+                adopt_peer_checkpoint(&op_log, report);
+            }
             match result {
-                Ok(_) => record_sync_event(
+                Ok(report) => record_sync_event(
                     &sync_events,
                     SyncEvent {
                         timestamp: Utc::now().to_rfc3339(),
                         direction: "outgoing".into(),
                         peer: peer.addr.to_string(),
-                        status: "synced".into(),
-                        detail: Some(format!("{} ops", ops.len())),
+                        status: report_status(&report).into(),
+                        detail: Some(describe_report(&report)),
                     },
                 ),
                 Err(e) => record_sync_event(
@@ -536,6 +971,62 @@ fn add_trusted_device(
     trust.add(device_id, public_key).map_err(|e| e.to_string())
 }
 
+/// Keep at most one pending pairing per device; a new request replaces the old one.
+fn park_pairing(pending_pairings: &Mutex<Vec<PendingPairing>>, pending: PendingPairing) {
+    if let Ok(mut list) = pending_pairings.lock() {
+        list.retain(|p| p.device_id() != pending.device_id());
+        list.push(pending);
+    }
+}
+
+#[tauri::command]
+fn start_pairing(state: tauri::State<AppState>, addr: String) -> Result<PairingView, String> {
+    let cfg = state.config.lock().map_err(|e| e.to_string())?.clone();
+    let transport = notes_sync::NetTransport::new(cfg.discovery_port, cfg.sync_port);
+    // discovered addresses carry the advertiser's ephemeral port; pair on the sync port
+    let host = addr
+        .parse::<std::net::SocketAddr>()
+        .map(|a| a.ip().to_string())
+        .unwrap_or(addr);
+    let pending = transport
+        .pair_with(&host, &state.device_identity)
+        .map_err(|e| e.to_string())?;
+    let view = PairingView::from(&pending);
+    park_pairing(&state.pending_pairings, pending);
+    Ok(view)
+}
+
+#[tauri::command]
+fn list_pairing_requests(state: tauri::State<AppState>) -> Result<Vec<PairingView>, String> {
+    let list = state.pending_pairings.lock().map_err(|e| e.to_string())?;
+    Ok(list.iter().map(PairingView::from).collect())
+}
+
+#[tauri::command]
+fn confirm_pairing(
+    state: tauri::State<AppState>,
+    device_id: String,
+    accept: bool,
+) -> Result<bool, String> {
+    let pending = {
+        let mut list = state.pending_pairings.lock().map_err(|e| e.to_string())?;
+        let idx = list
+            .iter()
+            .position(|p| p.device_id() == device_id)
+            .ok_or("no pending pairing for this device")?;
+        list.remove(idx)
+    };
+    // waiting for the other side can take a while, so don't hold the shared trust store
+    let mut trust = TrustStore::load_or_default(resolve_trust_path()).map_err(|e| e.to_string())?;
+    let paired = pending
+        .confirm(accept, &mut trust)
+        .map_err(|e| e.to_string())?;
+    if paired {
+        *state.trust_store.lock().map_err(|e| e.to_string())? = trust;
+    }
+    Ok(paired)
+}
+
 #[tauri::command]
 fn remove_trusted_device(state: tauri::State<AppState>, device_id: String) -> Result<(), String> {
     let mut trust = state.trust_store.lock().map_err(|e| e.to_string())?;
@@ -640,26 +1131,44 @@ fn list_sync_events(state: tauri::State<AppState>) -> Result<Vec<SyncEvent>, Str
 }
 
 #[tauri::command]
-fn sync_now(state: tauri::State<AppState>, target_device: String) -> Result<(), String> {
+fn sync_now(state: tauri::State<AppState>, target_device: String) -> Result<SyncReport, String> {
     let cfg = state.config.lock().map_err(|e| e.to_string())?.clone();
-    let transport =
-        notes_sync::NetTransport::new_with_psk(cfg.discovery_port, cfg.sync_port, psk_from_config(&cfg));
     let ops = state
         .op_log
         .lock()
         .map_err(|e| e.to_string())?
-        .entries();
-    let service = SyncService::new(transport, state.device_identity.clone(), ops.clone());
-    let result = service.pair_and_sync(&target_device);
+        .entries()
+        .to_vec();
+    let transport = session_transport(
+        notes_sync::NetTransport::new_with_psk(
+            cfg.discovery_port,
+            cfg.sync_port,
+            psk_from_config(&cfg),
+        ),
+        &state.store,
+        &state.op_log,
+    );
+    let result = sync_with_peer(
+        cfg.legacy_psk_transport,
+        transport,
+        &state.device_identity,
+        ops,
+        &target_device,
+        None,
+        |incoming| apply_incoming(&state.store, &state.op_log, incoming),
+    );
+    if let Ok(report) = &result {
+        adopt_peer_checkpoint(&state.op_log, report);
+    }
     match result {
-        Ok(_) => record_sync_event(
+        Ok(ref report) => record_sync_event(
             &state.sync_events,
             SyncEvent {
                 timestamp: Utc::now().to_rfc3339(),
                 direction: "outgoing".into(),
                 peer: target_device.clone(),
-                status: "synced".into(),
-                detail: Some(format!("{} ops", ops.len())),
+                status: report_status(report).into(),
+                detail: Some(describe_report(report)),
             },
         ),
         Err(ref e) => record_sync_event(
@@ -676,6 +1185,72 @@ fn sync_now(state: tauri::State<AppState>, target_device: String) -> Result<(),
     result.map_err(|e| e.to_string())
 }
 
+/// Fold each document's ops older than `older_than_days` into a single snapshot op.
+#[tauri::command]
+fn compact_op_log(
+    state: tauri::State<AppState>,
+    older_than_days: u32,
+) -> Result<CompactionReport, String> {
+    let horizon = Utc::now() - chrono::Duration::days(i64::from(older_than_days));
+    state
+        .op_log
+        .lock()
+        .map_err(|e| e.to_string())?
+        .compact(&horizon.to_rfc3339())
+        .map_err(|e| e.to_string())
+}
+
+/// Re-read every note file in the vault, picking up changes made by other tools.
+#[tauri::command]
+fn reindex_vault(state: tauri::State<AppState>) -> Result<ReindexReport, String> {
+    state
+        .store
+        .lock()
+        .map_err(|e| e.to_string())?
+        .reindex()
+        .map_err(|e| e.to_string())
+}
+
+/// Replay the op-log into a fresh directory and report where the live vault has drifted
+/// from it. The last few rebuilt vaults are kept so documents can be recovered from them.
+#[tauri::command]
+fn rebuild_vault(state: tauri::State<AppState>) -> Result<RebuildReport, String> {
+    let ops = state
+        .op_log
+        .lock()
+        .map_err(|e| e.to_string())?
+        .entries()
+        .to_vec();
+    let rebuilds = resolve_rebuild_dir();
+    let root = rebuilds.join(Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string());
+    let report = state
+        .store
+        .lock()
+        .map_err(|e| e.to_string())?
+        .rebuild_from_ops(&ops, root)
+        .map_err(|e| e.to_string());
+    prune_rebuilds(&rebuilds);
+    report
+}
+
+/// Delete all but the newest rebuilt vaults (their timestamped names sort by age).
+fn prune_rebuilds(dir: &Path) {
+    const KEEP_REBUILDS: usize = 3;
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut rebuilds: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|e| e.path())
+        .collect();
+    rebuilds.sort();
+    let excess = rebuilds.len().saturating_sub(KEEP_REBUILDS);
+    for old in &rebuilds[..excess] {
+        let _ = fs::remove_dir_all(old);
+    }
+}
+
 fn load_config(path: &PathBuf) -> Result<AppConfig, String> {
     if path.exists() {
         let raw = fs::read_to_string(path).map_err(|e| e.to_string())?;
@@ -693,7 +1268,7 @@ fn save_config(path: &PathBuf, cfg: &AppConfig) -> Result<(), String> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).map_err(|e| e.to_string())?;
     }
-    fs::write(path, raw).map_err(|e| e.to_string())
+    notes_core::write_atomic(path, raw).map_err(|e| e.to_string())
 }
 
 fn resolve_config_path() -> PathBuf {
@@ -730,7 +1305,43 @@ fn resolve_oplog_path() -> PathBuf {
     dirs::data_dir()
         .unwrap_or_else(|| dirs::config_dir().unwrap_or_else(|| PathBuf::from(".")))
         .join("notes-desktop")
-        .join("oplog.json")
+        .join("oplog.jsonl")
+}
+
+fn resolve_rebuild_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| dirs::config_dir().unwrap_or_else(|| PathBuf::from(".")))
+        .join("notes-desktop")
+        .join("rebuilds")
+}
+
+/// Open the op-log, importing the ops of a legacy `oplog.json` (one pretty-printed array)
+/// the first time. A log that can't be opened is moved aside to `oplog.jsonl.broken-<time>`
+/// and a fresh one started; the store's applied-op records keep sync idempotent meanwhile.
+fn open_op_log() -> Result<OpLog, String> {
+    let path = resolve_oplog_path();
+    let mut log = match OpLog::open(&path) {
+        Ok(log) => log,
+        Err(e) if path.exists() => {
+            let stamp = Utc::now().format("%Y%m%dT%H%M%S");
+            let aside = path.with_extension(format!("jsonl.broken-{stamp}"));
+            fs::rename(&path, &aside).map_err(|_| format!("failed to open op-log: {e}"))?;
+            OpLog::open(&path).map_err(|e| format!("failed to open op-log: {e}"))?
+        }
+        Err(e) => return Err(format!("failed to open op-log: {e}")),
+    };
+    let legacy = path.with_extension("json");
+    if log.is_empty() && legacy.exists() {
+        let ops = fs::read_to_string(&legacy)
+            .ok()
+            .and_then(|raw| serde_json::from_str::<Vec<Operation>>(&raw).ok());
+        if let Some(ops) = ops {
+            if log.merge(&ops).is_ok() {
+                let _ = fs::rename(&legacy, legacy.with_extension("json.bak"));
+            }
+        }
+    }
+    Ok(log)
 }
 
 fn default_discovery_port() -> u16 {
@@ -745,6 +1356,16 @@ fn default_auto_sync() -> bool {
     false
 }
 
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+/// Permanently delete notes that have been in the trash longer than the retention period.
+fn purge_expired_trash(store: &Store, retention_days: u32) -> Result<usize, StoreError> {
+    let cutoff = Utc::now() - chrono::Duration::days(i64::from(retention_days));
+    store.purge_trash(&cutoff.to_rfc3339())
+}
+
 fn psk_from_config(cfg: &AppConfig) -> Option<[u8; 32]> {
     cfg.transport_secret.as_ref().and_then(|s| {
         hex::decode(s).ok().and_then(|bytes| {
@@ -791,22 +1412,31 @@ fn main() {
     let sync_port = Arc::new(std::sync::atomic::AtomicU16::new(config.sync_port));
     let psk_arc: Arc<Mutex<Option<[u8; 32]>>> = Arc::new(Mutex::new(psk));
     start_advertise_loop(discovery_port.clone(), device_identity.clone());
+    let _ = purge_expired_trash(&store, config.trash_retention_days);
     let store = Arc::new(Mutex::new(store));
     let trust_store = Arc::new(Mutex::new(trust_store));
-    let op_log: Arc<Mutex<OpLogStore>> =
-        Arc::new(Mutex::new(OpLogStore::load(resolve_oplog_path())));
+    let op_log = match open_op_log() {
+        Ok(log) => Arc::new(Mutex::new(log)),
+        Err(e) => {
+            eprintln!("notes-desktop: {e}");
+            std::process::exit(1);
+        }
+    };
     let auto_sync_enabled =
         Arc::new(std::sync::atomic::AtomicBool::new(config.auto_sync_enabled));
     let sync_events: Arc<Mutex<Vec<SyncEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let pending_pairings: Arc<Mutex<Vec<PendingPairing>>> = Arc::new(Mutex::new(Vec::new()));
     start_sync_listener(
         store.clone(),
         op_log.clone(),
-        resolve_trust_path(),
+        device_identity.clone(),
         sync_port.clone(),
         psk_arc.clone(),
         sync_events.clone(),
+        pending_pairings.clone(),
     );
     start_auto_sync(
+        store.clone(),
         op_log.clone(),
         trust_store.clone(),
         device_identity.clone(),
@@ -815,6 +1445,13 @@ fn main() {
         discovery_port.clone(),
         sync_port.clone(),
         sync_events.clone(),
+        config.legacy_psk_transport,
+    );
+
+    let vault_watcher = start_vault_watcher(
+        store.clone(),
+        op_log.clone(),
+        device_identity.device_id.clone(),
     );
 
     tauri::Builder::default()
@@ -831,6 +1468,8 @@ fn main() {
             discovery_port,
             sync_port,
             psk: psk_arc,
+            pending_pairings,
+            vault_watcher: Mutex::new(vault_watcher),
         })
         .invoke_handler(tauri::generate_handler![
             health_check,
@@ -838,14 +1477,35 @@ fn main() {
             load_plugin_manifest,
             list_documents,
             search_documents,
+            full_text_search,
             get_document,
+            get_backlinks,
+            get_outgoing_links,
+            list_unresolved_links,
             update_document,
             delete_document,
+            list_trash,
+            restore_from_trash,
+            get_trash_retention_days,
+            set_trash_retention_days,
+            list_conflicts,
+            resolve_conflict,
+            get_document_history,
+            get_document_version,
+            diff_document_versions,
+            restore_document_version,
+            attach_file,
+            detach_file,
+            list_attachments,
+            get_attachment_path,
             get_vault_root,
             set_vault_root,
             get_device_identity,
             list_trusted_devices,
             add_trusted_device,
+            start_pairing,
+            list_pairing_requests,
+            confirm_pairing,
             remove_trusted_device,
             get_auto_sync_enabled,
             set_auto_sync_enabled,
@@ -854,6 +1514,9 @@ fn main() {
             set_network_config,
             list_sync_events,
             sync_now,
+            compact_op_log,
+            rebuild_vault,
+            reindex_vault,
             discover_peers
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,199 @@
+//! Content hashes. `before_hash`/`after_hash` decide conflicts, so a document's hash must not
+//! depend on how a YAML library happens to format it. A hash is tagged with the scheme that
+//! produced it (`v1:sha256:<hex>`); untagged hex digests are the legacy scheme (SHA-256 of the
+//! YAML rendering). Hashes are always checked with the scheme named by their tag, so a peer
+//! still sending legacy hashes and one sending v1 hashes both verify.
+//!
+//! v1 hashes an explicit encoding of the normalized document: every value is a type byte
+//! followed by a length-prefixed payload, fields come in a fixed order, the title is trimmed
+//! (empty counts as none), tags and links are sorted and deduplicated, extra properties are
+//! sorted by key (recursively), and line endings in the body are normalized to `\n`.
+
+use crate::{parse, Document, DocumentType, FrontmatterFormat};
+use serde_yaml::Value;
+use sha2::{Digest, Sha256};
+
+const V1_PREFIX: &str = "v1:sha256:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashScheme {
+    /// Untagged SHA-256 of the YAML rendering, from before hashes were versioned.
+    Legacy,
+    /// SHA-256 of the canonical v1 encoding.
+    V1,
+}
+
+impl HashScheme {
+    /// The scheme new hashes are made with.
+    pub const CURRENT: HashScheme = HashScheme::V1;
+
+    /// The scheme `hash` was made with, if it is one this version knows.
+    pub fn of(hash: &str) -> Option<Self> {
+        if hash.starts_with(V1_PREFIX) {
+            Some(HashScheme::V1)
+        } else if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Some(HashScheme::Legacy)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn hash(doc: &Document, scheme: HashScheme) -> Option<String> {
+    match scheme {
+        HashScheme::Legacy => {
+            let content = parse::render(doc, FrontmatterFormat::Yaml).ok()?;
+            Some(format!("{:x}", Sha256::digest(content.as_bytes())))
+        }
+        HashScheme::V1 => Some(v1(doc)),
+    }
+}
+
+/// The v1 hash; the encoding can't fail.
+pub(crate) fn v1(doc: &Document) -> String {
+    let mut encoder = Encoder(Sha256::new());
+    encoder.document(doc);
+    format!("{V1_PREFIX}{:x}", encoder.0.finalize())
+}
+
+struct Encoder(Sha256);
+
+impl Encoder {
+    fn bytes(&mut self, kind: u8, data: &[u8]) {
+        self.0.update([kind]);
+        self.0.update((data.len() as u64).to_be_bytes());
+        self.0.update(data);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(b's', s.as_bytes());
+    }
+
+    /// A count of the items that follow.
+    fn count(&mut self, kind: u8, n: usize) {
+        self.bytes(kind, &(n as u64).to_be_bytes());
+    }
+
+    fn strings(&mut self, items: &[String]) {
+        let mut items: Vec<&str> = items.iter().map(|s| s.trim()).collect();
+        items.sort_unstable();
+        items.dedup();
+        self.count(b'l', items.len());
+        for item in items {
+            self.str(item);
+        }
+    }
+
+    fn document(&mut self, doc: &Document) {
+        let fm = &doc.frontmatter;
+        self.str(&fm.id);
+        self.str(match fm.doc_type {
+            DocumentType::Note => "note",
+            DocumentType::Source => "source",
+            DocumentType::Highlight => "highlight",
+            DocumentType::Annotation => "annotation",
+            DocumentType::Reference => "reference",
+            DocumentType::System => "system",
+        });
+        match fm.title.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+            Some(title) => self.str(title),
+            None => self.bytes(b'n', &[]),
+        }
+        self.str(fm.created.trim());
+        self.str(fm.updated.trim());
+        self.strings(&fm.tags);
+        self.strings(&fm.links);
+        self.map(fm.extra.iter());
+        self.str(&doc.body.replace("\r\n", "\n").replace('\r', "\n"));
+    }
+
+    fn map<'a>(&mut self, entries: impl Iterator<Item = (&'a Value, &'a Value)>) {
+        let mut entries: Vec<(String, &Value)> =
+            entries.map(|(k, v)| (canonical_key(k), v)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        self.count(b'm', entries.len());
+        for (key, value) in entries {
+            self.str(&key);
+            self.value(value);
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.bytes(b'n', &[]),
+            Value::Bool(b) => self.bytes(b'b', &[*b as u8]),
+            Value::Number(n) => self.bytes(b'd', n.to_string().as_bytes()),
+            Value::String(s) => self.str(s),
+            Value::Sequence(items) => {
+                self.count(b'l', items.len());
+                for item in items {
+                    self.value(item);
+                }
+            }
+            Value::Mapping(map) => self.map(map.iter()),
+            Value::Tagged(tagged) => {
+                self.bytes(b't', tagged.tag.to_string().as_bytes());
+                self.value(&tagged.value);
+            }
+        }
+    }
+}
+
+/// Map keys are almost always strings; anything else is keyed by its YAML form.
+fn canonical_key(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(raw: &str) -> Document {
+        Document::from_markdown(raw).unwrap()
+    }
+
+    #[test]
+    fn v1_ignores_layout_and_normalizes_fields() {
+        let a = doc(
+            "---\nid: a\ntype: note\ntitle: Hi\ncreated: c\nupdated: u\n\
+                     tags: [x, y]\nstatus: draft\nrating: 3\n---\n\nline one\nline two\n",
+        );
+        let b = doc(
+            "+++\nrating = 3\nstatus = \"draft\"\ntags = [\"y\", \"x\", \"x\"]\n\
+                     title = \" Hi \"\nupdated = \"u\"\ncreated = \"c\"\nid = \"a\"\n+++\n\n\
+                     line one\r\nline two\r\n",
+        );
+        assert_eq!(a.hash_content(), b.hash_content());
+        assert!(a.hash_content().starts_with("v1:sha256:"));
+        assert_eq!(HashScheme::of(&a.hash_content()), Some(HashScheme::V1));
+
+        let mut edited = a.clone();
+        edited.body.push('!');
+        assert_ne!(edited.hash_content(), a.hash_content());
+        let mut edited = a.clone();
+        edited.frontmatter.set_property("status", "final").unwrap();
+        assert_ne!(edited.hash_content(), a.hash_content());
+        let mut edited = a.clone();
+        edited.frontmatter.title = None;
+        assert_ne!(edited.hash_content(), a.hash_content());
+    }
+
+    #[test]
+    fn hashes_are_checked_with_their_own_scheme() {
+        let d = doc("---\nid: a\ntype: note\ncreated: c\nupdated: u\n---\n\nbody");
+        let legacy = d.hash_with(HashScheme::Legacy).unwrap();
+        assert_eq!(HashScheme::of(&legacy), Some(HashScheme::Legacy));
+        assert!(d.matches_hash(&legacy));
+        assert!(d.matches_hash(&d.hash_content()));
+        assert!(!d.matches_hash("v2:blake3:00"));
+        assert!(!d.matches_hash(""));
+
+        let mut other = d.clone();
+        other.body = "changed".into();
+        assert!(!other.matches_hash(&legacy));
+        assert!(!other.matches_hash(&d.hash_content()));
+    }
+}
@@ -1,6 +1,16 @@
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
 use thiserror::Error;
 
+mod hash;
+mod parse;
+
+pub use hash::HashScheme;
+pub use parse::FrontmatterFormat;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum DocumentType {
@@ -26,49 +36,128 @@ pub struct Frontmatter {
     pub tags: Vec<String>,
     #[serde(default)]
     pub links: Vec<String>,
+    /// Any other keys (`author`, `aliases`, ...), kept in the order they were written.
+    #[serde(flatten)]
+    pub extra: Mapping,
+}
+
+/// Keys `Frontmatter` has fields for; they can't be set as extra properties.
+pub const RESERVED_KEYS: [&str; 7] = ["id", "type", "title", "created", "updated", "tags", "links"];
+
+impl Frontmatter {
+    pub fn property(&self, key: &str) -> Option<&Value> {
+        self.extra.get(key)
+    }
+
+    pub fn property_str(&self, key: &str) -> Option<&str> {
+        self.property(key)?.as_str()
+    }
+
+    pub fn property_bool(&self, key: &str) -> Option<bool> {
+        self.property(key)?.as_bool()
+    }
+
+    pub fn property_i64(&self, key: &str) -> Option<i64> {
+        self.property(key)?.as_i64()
+    }
+
+    pub fn property_f64(&self, key: &str) -> Option<f64> {
+        self.property(key)?.as_f64()
+    }
+
+    /// A list of strings, e.g. `aliases`; a single string counts as a one-item list.
+    pub fn property_strings(&self, key: &str) -> Option<Vec<&str>> {
+        match self.property(key)? {
+            Value::String(s) => Some(vec![s.as_str()]),
+            Value::Sequence(items) => items.iter().map(Value::as_str).collect(),
+            _ => None,
+        }
+    }
+
+    /// Set an extra property, keeping its position if it already exists (new keys go last).
+    /// Returns the previous value.
+    pub fn set_property(
+        &mut self,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, DocumentError> {
+        if RESERVED_KEYS.contains(&key) {
+            return Err(DocumentError::ReservedKey(key.to_string()));
+        }
+        Ok(self.extra.insert(key.into(), value.into()))
+    }
+
+    /// Remove an extra property, keeping the order of the others.
+    pub fn remove_property(&mut self, key: &str) -> Option<Value> {
+        self.extra.shift_remove(key)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Document {
     pub frontmatter: Frontmatter,
     pub body: String,
+    /// The format `to_markdown` writes the frontmatter in: the one the file was read in.
+    #[serde(skip)]
+    pub format: FrontmatterFormat,
 }
 
 #[derive(Debug, Error)]
 pub enum DocumentError {
-    #[error("failed to parse frontmatter")]
-    Frontmatter,
+    #[error("invalid {format} frontmatter at line {line}, column {column}: {message}")]
+    InvalidFrontmatter {
+        format: FrontmatterFormat,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("{format} frontmatter opened at line {line} is never closed")]
+    UnterminatedFrontmatter {
+        format: FrontmatterFormat,
+        line: usize,
+    },
     #[error("failed to serialize document")]
     Serialize,
     #[error("invalid document")]
     Invalid,
+    #[error("`{0}` is a built-in frontmatter key")]
+    ReservedKey(String),
 }
 
 impl Document {
+    /// Hash of the document's content with the current [`HashScheme`]. It doesn't depend on
+    /// the frontmatter format, key order or line endings (see `hash.rs`).
     pub fn hash_content(&self) -> String {
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        let content = self.to_markdown().unwrap_or_default();
-        hasher.update(content.as_bytes());
-        format!("{:x}", hasher.finalize())
+        hash::v1(self)
+    }
+
+    /// Hash with a given scheme; `None` if the document can't be encoded for it.
+    pub fn hash_with(&self, scheme: HashScheme) -> Option<String> {
+        hash::hash(self, scheme)
+    }
+
+    /// Whether `hash` is this document's hash under the scheme `hash` is tagged with. Use this
+    /// rather than comparing with `hash_content`, which only knows the current scheme.
+    pub fn matches_hash(&self, hash: &str) -> bool {
+        HashScheme::of(hash)
+            .and_then(|scheme| self.hash_with(scheme))
+            .is_some_and(|own| own == hash)
     }
 
     pub fn to_markdown(&self) -> Result<String, DocumentError> {
-        let fm = serde_yaml::to_string(&self.frontmatter).map_err(|_| DocumentError::Serialize)?;
-        Ok(format!("---\n{}---\n\n{}", fm, self.body))
+        parse::render(self, self.format)
     }
 
+    /// Parse a markdown file with YAML, TOML or JSON frontmatter, or none at all; a document
+    /// without an id gets a fresh one.
     pub fn from_markdown(raw: &str) -> Result<Self, DocumentError> {
-        let mut sections = raw.splitn(3, "---");
-        let _before = sections.next();
-        let fm_section = sections.next().ok_or(DocumentError::Frontmatter)?;
-        let body_section = sections.next().unwrap_or_default();
-        let frontmatter: Frontmatter =
-            serde_yaml::from_str(fm_section).map_err(|_| DocumentError::Frontmatter)?;
-        Ok(Self {
-            frontmatter,
-            body: body_section.trim_start_matches('\n').to_string(),
-        })
+        parse::parse(raw, None)
+    }
+
+    /// Like [`Document::from_markdown`], with `id` (usually from the file name) for a document
+    /// that doesn't have one.
+    pub fn from_markdown_with_id(raw: &str, id: &str) -> Result<Self, DocumentError> {
+        parse::parse(raw, Some(id))
     }
 }
 
@@ -76,6 +165,28 @@ pub fn generate_id() -> String {
     ulid::Ulid::new().to_string()
 }
 
+/// Replace `path` with `contents` so that a crash leaves either the old or the new file, never
+/// a truncated one: write to `<name>.tmp` beside it, fsync, rename over `path`, then fsync the
+/// directory so the rename itself is durable.
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    // directories can't be opened for syncing on every platform; the rename is still atomic
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,12 +202,63 @@ mod tests {
                 updated: "2025-01-01T00:00:00Z".into(),
                 tags: vec!["tag".into()],
                 links: vec![],
+                extra: Mapping::new(),
             },
             body: "Hello".into(),
+            format: FrontmatterFormat::Yaml,
         };
         let md = doc.to_markdown().unwrap();
         let parsed = Document::from_markdown(&md).unwrap();
         assert_eq!(parsed.frontmatter.id, doc.frontmatter.id);
         assert_eq!(parsed.body, doc.body);
     }
+
+    #[test]
+    fn extra_frontmatter_keys_roundtrip_in_order() {
+        let raw = "---\nid: abc\ntype: note\ncreated: 2025-01-01T00:00:00Z\nupdated: 2025-01-01T00:00:00Z\n\
+                   tags: []\nlinks: []\nstatus: draft\nauthor: Ada\naliases:\n- first\n- second\n\
+                   rating: 4\npublished: false\n---\n\nBody\n";
+        let doc = Document::from_markdown(raw).unwrap();
+        let fm = &doc.frontmatter;
+        let keys: Vec<_> = fm.extra.keys().filter_map(Value::as_str).collect();
+        assert_eq!(keys, ["status", "author", "aliases", "rating", "published"]);
+        assert_eq!(fm.property_str("author"), Some("Ada"));
+        assert_eq!(
+            fm.property_strings("aliases"),
+            Some(vec!["first", "second"])
+        );
+        assert_eq!(fm.property_i64("rating"), Some(4));
+        assert_eq!(fm.property_bool("published"), Some(false));
+        assert_eq!(doc.to_markdown().unwrap(), raw);
+
+        let mut doc = doc;
+        doc.frontmatter.set_property("status", "final").unwrap();
+        doc.frontmatter
+            .set_property("source_url", "https://example.com")
+            .unwrap();
+        doc.frontmatter.remove_property("author");
+        assert!(doc.frontmatter.set_property("title", "nope").is_err());
+        let reparsed = Document::from_markdown(&doc.to_markdown().unwrap()).unwrap();
+        let keys: Vec<_> = reparsed
+            .frontmatter
+            .extra
+            .keys()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            ["status", "aliases", "rating", "published", "source_url"]
+        );
+        assert_eq!(reparsed.frontmatter.property_str("status"), Some("final"));
+    }
+
+    #[test]
+    fn write_atomic_replaces_without_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.md");
+        write_atomic(&path, "first").unwrap();
+        write_atomic(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
 }
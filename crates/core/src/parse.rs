@@ -0,0 +1,455 @@
+//! Frontmatter parsing. The frontmatter block is found line by line, after an optional BOM and
+//! leading blank lines: `---` (YAML, closed by `---` or `...`), `+++` (TOML), `;;;` (JSON) or a
+//! bare JSON object starting with `{`. Only a delimiter on a line of its own closes a block, so
+//! `---` inside a value doesn't. A file without frontmatter is a note whose body is the whole
+//! file; so is one that opens with a `---` never closed (a horizontal rule) or with a `{` that
+//! doesn't start a JSON object. Missing `id`, `type`, `created` and `updated` keys are filled in
+//! (a fresh id unless the caller knows one), and everything after the closing delimiter and one
+//! blank separator line is the body, byte for byte.
+
+use crate::{generate_id, Document, DocumentError, Frontmatter};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::fmt;
+
+/// How a document's frontmatter is written; kept so a file is saved in the format it came in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrontmatterFormat {
+    /// `---` fenced YAML.
+    #[default]
+    Yaml,
+    /// `+++` fenced TOML.
+    Toml,
+    /// A bare JSON object.
+    Json,
+    /// `;;;` fenced JSON.
+    JsonFenced,
+}
+
+impl fmt::Display for FrontmatterFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrontmatterFormat::Yaml => "YAML",
+            FrontmatterFormat::Toml => "TOML",
+            FrontmatterFormat::Json | FrontmatterFormat::JsonFenced => "JSON",
+        })
+    }
+}
+
+/// A frontmatter block: its format, its text, the file line the text starts on, and the byte
+/// offset the body starts at.
+struct Block<'a> {
+    format: FrontmatterFormat,
+    text: &'a str,
+    first_line: usize,
+    body_start: usize,
+}
+
+pub(crate) fn parse(raw: &str, id: Option<&str>) -> Result<Document, DocumentError> {
+    let text = raw.strip_prefix('\u{feff}').unwrap_or(raw);
+    let Some(block) = find_block(text)? else {
+        return Ok(Document {
+            frontmatter: defaults(Mapping::new(), id).map_err(|_| DocumentError::Invalid)?,
+            body: text.to_string(),
+            format: FrontmatterFormat::Yaml,
+        });
+    };
+
+    let at = |line: usize, column: usize, message: String| DocumentError::InvalidFrontmatter {
+        format: block.format,
+        line: block.first_line + line.max(1) - 1,
+        column: column.max(1),
+        message,
+    };
+    let value = match block.format {
+        FrontmatterFormat::Yaml => serde_yaml::from_str::<Value>(block.text).map_err(|e| {
+            let (line, column) = e.location().map_or((1, 1), |l| (l.line(), l.column()));
+            at(line, column, strip_location(&e.to_string()))
+        })?,
+        FrontmatterFormat::Toml => {
+            let table = toml::from_str::<toml::Table>(block.text).map_err(|e| {
+                let offset = e.span().map_or(0, |s| s.start);
+                let (line, column) = line_column(block.text, offset);
+                at(line, column, e.message().to_string())
+            })?;
+            toml_to_yaml(toml::Value::Table(table))
+        }
+        FrontmatterFormat::Json | FrontmatterFormat::JsonFenced => {
+            serde_json::from_str::<Value>(block.text)
+                .map_err(|e| at(e.line(), e.column(), strip_location(&e.to_string())))?
+        }
+    };
+    let map = match value {
+        Value::Mapping(map) => map,
+        Value::Null => Mapping::new(),
+        _ => return Err(at(1, 1, "frontmatter must be a mapping".into())),
+    };
+    let frontmatter = defaults(map, id).map_err(|e| {
+        // a type error has no position here; deserializing the text directly finds it
+        let position = match block.format {
+            FrontmatterFormat::Yaml => serde_yaml::from_str::<Frontmatter>(block.text)
+                .err()
+                .and_then(|e| e.location())
+                .map(|l| (l.line(), l.column())),
+            FrontmatterFormat::Json | FrontmatterFormat::JsonFenced => {
+                serde_json::from_str::<Frontmatter>(block.text)
+                    .err()
+                    .map(|e| (e.line(), e.column()))
+            }
+            FrontmatterFormat::Toml => None,
+        };
+        let (line, column) = position.unwrap_or((1, 1));
+        at(line, column, e.to_string())
+    })?;
+    let body = &text[block.body_start..];
+    let body = body
+        .strip_prefix("\r\n")
+        .or_else(|| body.strip_prefix('\n'))
+        .unwrap_or(body);
+    Ok(Document {
+        frontmatter,
+        body: body.to_string(),
+        format: block.format,
+    })
+}
+
+pub(crate) fn render(doc: &Document, format: FrontmatterFormat) -> Result<String, DocumentError> {
+    let fm = &doc.frontmatter;
+    let body = &doc.body;
+    Ok(match format {
+        FrontmatterFormat::Yaml => {
+            let yaml = serde_yaml::to_string(fm).map_err(|_| DocumentError::Serialize)?;
+            format!("---\n{yaml}---\n\n{body}")
+        }
+        FrontmatterFormat::Toml => {
+            let value = serde_yaml::to_value(fm).map_err(|_| DocumentError::Serialize)?;
+            let toml = match yaml_to_toml(value) {
+                Some(table @ toml::Value::Table(_)) => {
+                    toml::to_string(&table).map_err(|_| DocumentError::Serialize)?
+                }
+                _ => return Err(DocumentError::Serialize),
+            };
+            format!("+++\n{toml}+++\n\n{body}")
+        }
+        FrontmatterFormat::Json => {
+            let json = serde_json::to_string_pretty(fm).map_err(|_| DocumentError::Serialize)?;
+            format!("{json}\n\n{body}")
+        }
+        FrontmatterFormat::JsonFenced => {
+            let json = serde_json::to_string_pretty(fm).map_err(|_| DocumentError::Serialize)?;
+            format!(";;;\n{json}\n;;;\n\n{body}")
+        }
+    })
+}
+
+fn find_block(text: &str) -> Result<Option<Block<'_>>, DocumentError> {
+    let mut lines = Lines { text, pos: 0 };
+    let mut line_no = 0;
+    let (start, opening) = loop {
+        let Some((start, line)) = lines.next() else {
+            return Ok(None);
+        };
+        line_no += 1;
+        if !line.trim().is_empty() {
+            break (start, line.trim_end());
+        }
+    };
+    let (format, closing): (_, &[&str]) = match opening {
+        "---" => (FrontmatterFormat::Yaml, &["---", "..."]),
+        "+++" => (FrontmatterFormat::Toml, &["+++"]),
+        ";;;" => (FrontmatterFormat::JsonFenced, &[";;;"]),
+        _ if opening.starts_with('{') => {
+            // prose that merely starts with a brace is body
+            let Some(end) = json_object_end(&text[start..])
+                .filter(|end| serde_json::from_str::<Value>(&text[start..start + end]).is_ok())
+            else {
+                return Ok(None);
+            };
+            // the object runs to the end of the line its closing brace is on
+            let mut rest = Lines {
+                text,
+                pos: start + end,
+            };
+            let end_of_line = rest.next().map_or(text.len(), |(s, l)| s + l.len());
+            return Ok(Some(Block {
+                format: FrontmatterFormat::Json,
+                text: &text[start..end_of_line],
+                first_line: line_no,
+                body_start: rest.pos,
+            }));
+        }
+        _ => return Ok(None),
+    };
+    let content_start = lines.pos;
+    while let Some((line_start, line)) = lines.next() {
+        if closing.contains(&line.trim_end()) {
+            return Ok(Some(Block {
+                format,
+                text: &text[content_start..line_start],
+                first_line: line_no + 1,
+                body_start: lines.pos,
+            }));
+        }
+    }
+    // an unclosed `---` is a horizontal rule at the top of the body
+    if format == FrontmatterFormat::Yaml {
+        return Ok(None);
+    }
+    Err(DocumentError::UnterminatedFrontmatter {
+        format,
+        line: line_no,
+    })
+}
+
+/// Lines with their start offsets, without the line break (a trailing `\r` is kept).
+struct Lines<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.text.len() {
+            return None;
+        }
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let (line, len) = match rest.find('\n') {
+            Some(i) => (&rest[..i], i + 1),
+            None => (rest, rest.len()),
+        };
+        self.pos += len;
+        Some((start, line))
+    }
+}
+
+/// Byte length of the JSON object `text` starts with, up to and including its closing brace.
+fn json_object_end(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// 1-based line and column (in characters) of byte `offset` in `text`.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// serde_yaml and serde_json append "at line X column Y" relative to the block; the error
+/// carries the file position instead.
+fn strip_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(i) => message[..i].to_string(),
+        None => message.to_string(),
+    }
+}
+
+/// Fill in the keys a document can't do without and deserialize.
+fn defaults(mut map: Mapping, id: Option<&str>) -> Result<Frontmatter, serde_yaml::Error> {
+    let mut fill = |key: &str, value: String| {
+        if !map.contains_key(key) {
+            map.insert(key.into(), value.into());
+        }
+    };
+    fill("id", id.map_or_else(generate_id, str::to_string));
+    fill("type", "note".into());
+    fill("created", String::new());
+    fill("updated", String::new());
+    serde_yaml::from_value(Value::Mapping(map))
+}
+
+fn toml_to_yaml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(dt) => Value::String(dt.to_string()),
+        toml::Value::Array(items) => Value::Sequence(items.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(table) => Value::Mapping(
+            table
+                .into_iter()
+                .map(|(k, v)| (Value::String(k), toml_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// TOML has no null; null values (and entries holding them) are left out.
+fn yaml_to_toml(value: Value) -> Option<toml::Value> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(b) => toml::Value::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => toml::Value::Float(n.as_f64()?),
+        },
+        Value::String(s) => toml::Value::String(s),
+        Value::Sequence(items) => {
+            toml::Value::Array(items.into_iter().filter_map(yaml_to_toml).collect())
+        }
+        Value::Mapping(map) => toml::Value::Table(
+            map.into_iter()
+                .filter_map(|(k, v)| Some((k.as_str()?.to_string(), yaml_to_toml(v)?)))
+                .collect(),
+        ),
+        Value::Tagged(tagged) => return yaml_to_toml(tagged.value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_with_bom_blank_lines_and_dashes_in_values() {
+        let raw = "\u{feff}\n\n---\nid: abc\ntype: note\ntitle: \"a --- b\"\n\
+                   created: 2025-01-01T00:00:00Z\nupdated: 2025-01-01T00:00:00Z\n---\n\n\n# Body\n---\nmore";
+        let doc = Document::from_markdown(raw).unwrap();
+        assert_eq!(doc.frontmatter.id, "abc");
+        assert_eq!(doc.frontmatter.title.as_deref(), Some("a --- b"));
+        assert_eq!(doc.body, "\n# Body\n---\nmore");
+
+        let written = doc.to_markdown().unwrap();
+        let reparsed = Document::from_markdown(&written).unwrap();
+        assert_eq!(reparsed.body, doc.body);
+        assert_eq!(reparsed.to_markdown().unwrap(), written);
+    }
+
+    #[test]
+    fn files_without_frontmatter_are_plain_notes() {
+        let raw = "# Just text\n\nNo frontmatter.\n";
+        let doc = Document::from_markdown_with_id(raw, "plain").unwrap();
+        assert_eq!(doc.frontmatter.id, "plain");
+        assert_eq!(doc.frontmatter.doc_type, crate::DocumentType::Note);
+        assert_eq!(doc.body, raw);
+        assert!(!Document::from_markdown(raw)
+            .unwrap()
+            .frontmatter
+            .id
+            .is_empty());
+        assert_eq!(Document::from_markdown("").unwrap().body, "");
+    }
+
+    #[test]
+    fn toml_and_json_frontmatter() {
+        let toml = "+++\nid = \"t\"\ntitle = \"Toml\"\ncreated = 2025-01-01T00:00:00Z\n\
+                    updated = \"2025-01-02T00:00:00Z\"\ntags = [\"a\"]\nstatus = \"draft\"\n+++\n\nBody";
+        let doc = Document::from_markdown(toml).unwrap();
+        assert_eq!(doc.format, FrontmatterFormat::Toml);
+        assert_eq!(doc.frontmatter.created, "2025-01-01T00:00:00Z");
+        assert_eq!(doc.frontmatter.tags, vec!["a"]);
+        assert_eq!(doc.frontmatter.property_str("status"), Some("draft"));
+        assert_eq!(doc.body, "Body");
+        let written = doc.to_markdown().unwrap();
+        assert!(written.starts_with("+++\n"));
+        let reparsed = Document::from_markdown(&written).unwrap();
+        assert_eq!(reparsed.frontmatter.property_str("status"), Some("draft"));
+        assert_eq!(reparsed.hash_content(), doc.hash_content());
+
+        let as_yaml = Document {
+            format: FrontmatterFormat::Yaml,
+            ..doc.clone()
+        };
+        assert_eq!(as_yaml.hash_content(), doc.hash_content());
+
+        let json = "{\n  \"id\": \"j\",\n  \"title\": \"} tricky {\"\n}\n\nBody";
+        let doc = Document::from_markdown(json).unwrap();
+        assert_eq!(doc.format, FrontmatterFormat::Json);
+        assert_eq!(doc.frontmatter.title.as_deref(), Some("} tricky {"));
+        assert_eq!(doc.body, "Body");
+        let reparsed = Document::from_markdown(&doc.to_markdown().unwrap()).unwrap();
+        assert_eq!(reparsed.frontmatter.title, doc.frontmatter.title);
+
+        let fenced = ";;;\n{ \"id\": \"f\" }\n;;;\nBody";
+        let doc = Document::from_markdown(fenced).unwrap();
+        assert_eq!(doc.format, FrontmatterFormat::JsonFenced);
+        assert_eq!(doc.frontmatter.id, "f");
+        assert_eq!(doc.body, "Body");
+        assert!(doc.to_markdown().unwrap().starts_with(";;;\n{"));
+    }
+
+    #[test]
+    fn errors_point_at_the_file_position() {
+        let position = |raw: &str| match Document::from_markdown(raw) {
+            Err(DocumentError::InvalidFrontmatter {
+                format,
+                line,
+                column,
+                ..
+            }) => (format, line, column),
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(
+            position("\n---\nid: a\ntags: [x\n---\n"),
+            (FrontmatterFormat::Yaml, 5, 1)
+        );
+        assert_eq!(
+            position("+++\nid = \"a\"\ntitle = \n+++\n").0,
+            FrontmatterFormat::Toml
+        );
+        assert_eq!(position("+++\nid = \"a\"\ntitle = \n+++\n").1, 3);
+        assert_eq!(
+            position("{\n  \"id\": \"a\",\n  \"tags\": 5\n}\n"),
+            (FrontmatterFormat::Json, 3, 11)
+        );
+        assert_eq!(
+            position("---\ntags: 5\n---\n"),
+            (FrontmatterFormat::Yaml, 2, 7)
+        );
+        assert!(matches!(
+            Document::from_markdown("\n+++\nid = \"a\"\n\nbody"),
+            Err(DocumentError::UnterminatedFrontmatter {
+                format: FrontmatterFormat::Toml,
+                line: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn unclosed_rules_and_stray_braces_are_body() {
+        let rule = "---\n\nA note that opens with a horizontal rule.\n";
+        let doc = Document::from_markdown_with_id(rule, "rule").unwrap();
+        assert_eq!(doc.body, rule);
+        assert_eq!(doc.frontmatter.id, "rule");
+
+        for raw in [
+            "{ \"id\": \"a\"\nbody",
+            "{{template}} placeholders\n",
+            "{not: json}\n\nbody\n",
+        ] {
+            let doc = Document::from_markdown_with_id(raw, "brace").unwrap();
+            assert_eq!(doc.body, raw);
+            assert_eq!(doc.frontmatter.id, "brace");
+        }
+    }
+}
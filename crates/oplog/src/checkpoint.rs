@@ -0,0 +1,55 @@
+use crate::{Operation, OperationType, VectorClock};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Checkpoints: compaction folds a document's old ops into the newest of them. Every create or
+// update carries the full document, so that op already is a snapshot of everything before it;
+// for a deleted document the delete stays behind as a tombstone. The checkpoint remembers,
+// per document, the clock of the op kept. Ops strictly before that clock are covered: a log
+// holding the checkpoint treats them as present, and peers that see it in a sync summary
+// send the snapshot instead of the individual ops.
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Ops older than this (RFC 3339) were eligible for compaction.
+    #[serde(default)]
+    pub horizon: String,
+    /// Per document, the clock of the op kept as its snapshot or tombstone.
+    #[serde(default)]
+    pub documents: BTreeMap<String, VectorClock>,
+}
+
+impl Checkpoint {
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Whether `op` was folded into a snapshot of this checkpoint.
+    pub fn covers(&self, op: &Operation) -> bool {
+        is_content_op(op)
+            && !op.clock.is_empty()
+            && self
+                .documents
+                .get(&op.document_id)
+                .is_some_and(|snapshot| op.clock.happens_before(snapshot))
+    }
+}
+
+/// What a compaction did.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Documents whose history was collapsed.
+    pub documents: usize,
+    /// Ops dropped from the log.
+    pub removed: usize,
+}
+
+/// Ops that change a document's content and advance its clock (attachments don't).
+pub(crate) fn is_content_op(op: &Operation) -> bool {
+    matches!(
+        op.op_type,
+        OperationType::CreateDocument
+            | OperationType::UpdateDocument
+            | OperationType::DeleteDocument
+    )
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+// Per-document vector clocks: each op carries the document's clock *after* the op, one
+// counter per device that has written to the document. Comparing two clocks tells whether
+// one edit had seen the other (fast-forward / stale) or whether they raced (concurrent).
+
+/// Causal relation of `self` to `other` as returned by [`VectorClock::compare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CausalOrder {
+    /// `self` happened before `other` (other has seen everything self has).
+    Before,
+    /// `self` happened after `other`.
+    After,
+    Equal,
+    Concurrent,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, device_id: &str) -> u64 {
+        self.0.get(device_id).copied().unwrap_or(0)
+    }
+
+    /// Bump this device's counter and return the new value.
+    pub fn increment(&mut self, device_id: &str) -> u64 {
+        let counter = self.0.entry(device_id.to_string()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Pointwise maximum of both clocks.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (device, &count) in &other.0 {
+            let entry = self.0.entry(device.clone()).or_insert(0);
+            *entry = (*entry).max(count);
+        }
+    }
+
+    pub fn merged(&self, other: &VectorClock) -> VectorClock {
+        let mut out = self.clone();
+        out.merge(other);
+        out
+    }
+
+    pub fn compare(&self, other: &VectorClock) -> CausalOrder {
+        let mut ordering = Ordering::Equal;
+        for device in self.0.keys().chain(other.0.keys()) {
+            match (self.get(device).cmp(&other.get(device)), ordering) {
+                (Ordering::Equal, _) => {}
+                (o, Ordering::Equal) => ordering = o,
+                (o, current) if o != current => return CausalOrder::Concurrent,
+                _ => {}
+            }
+        }
+        match ordering {
+            Ordering::Less => CausalOrder::Before,
+            Ordering::Greater => CausalOrder::After,
+            Ordering::Equal => CausalOrder::Equal,
+        }
+    }
+
+    pub fn happens_before(&self, other: &VectorClock) -> bool {
+        self.compare(other) == CausalOrder::Before
+    }
+
+    pub fn is_concurrent(&self, other: &VectorClock) -> bool {
+        self.compare(other) == CausalOrder::Concurrent
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(device, count)| (device.as_str(), *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_clocks() {
+        let mut a = VectorClock::new();
+        a.increment("laptop");
+        let mut b = a.clone();
+        assert_eq!(a.compare(&b), CausalOrder::Equal);
+
+        b.increment("desktop");
+        assert_eq!(a.compare(&b), CausalOrder::Before);
+        assert_eq!(b.compare(&a), CausalOrder::After);
+        assert!(a.happens_before(&b));
+
+        a.increment("laptop");
+        assert_eq!(a.compare(&b), CausalOrder::Concurrent);
+        assert!(b.is_concurrent(&a));
+
+        let merged = a.merged(&b);
+        assert_eq!(merged.get("laptop"), 2);
+        assert_eq!(merged.get("desktop"), 1);
+        assert_eq!(a.compare(&merged), CausalOrder::Before);
+        assert_eq!(b.compare(&merged), CausalOrder::Before);
+    }
+}
@@ -1,5 +1,13 @@
 use serde::{Deserialize, Serialize};
 
+mod checkpoint;
+mod clock;
+mod log;
+
+pub use checkpoint::{Checkpoint, CompactionReport};
+pub use clock::{CausalOrder, VectorClock};
+pub use log::{OpLog, OpLogError};
+
 // Operation log types shared across crates: defines operation kinds and hashing helpers
 // used for sync/signing/deduplication.
 
@@ -25,6 +33,9 @@ pub struct Operation {
     pub payload: serde_json::Value,
     pub before_hash: Option<String>,
     pub after_hash: Option<String>,
+    /// The document's vector clock after this op; empty on ops from older clients.
+    #[serde(default, skip_serializing_if = "VectorClock::is_empty")]
+    pub clock: VectorClock,
 }
 
 impl Operation {
@@ -43,12 +54,20 @@ impl Operation {
         if let Some(after) = &self.after_hash {
             hasher.update(after.as_bytes());
         }
+        if !self.clock.is_empty() {
+            hasher.update(serde_json::to_string(&self.clock).unwrap_or_default());
+        }
         format!("{:x}", hasher.finalize())
     }
 
     pub fn key(&self) -> String {
         format!("{}:{}", self.device_id, self.op_id)
     }
+
+    /// Causal relation of this op to `other`; only meaningful for ops on the same document.
+    pub fn causal_order(&self, other: &Operation) -> CausalOrder {
+        self.clock.compare(&other.clock)
+    }
 }
 
 impl Operation {
@@ -67,6 +86,9 @@ impl Operation {
         if let Some(after) = &self.after_hash {
             hasher.update(after.as_bytes());
         }
+        if !self.clock.is_empty() {
+            hasher.update(serde_json::to_string(&self.clock).unwrap_or_default());
+        }
         format!("{:x}", hasher.finalize())
     }
 }
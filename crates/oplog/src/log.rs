@@ -0,0 +1,542 @@
+use crate::checkpoint::{is_content_op, Checkpoint, CompactionReport};
+use crate::{CausalOrder, Operation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Append-only op-log storage. Each op is one line, `<checksum>\t<op json>\n`, where the
+// checksum is the first 16 hex digits of sha256 over the JSON bytes. Appending never rewrites
+// earlier records. On open every line is verified: a torn or corrupt tail left by a crash is
+// cut off, a corrupt record in the middle is skipped. In memory the log keeps the ops in
+// append order plus indexes by op key, document id and origin device.
+//
+// Compaction (see `checkpoint.rs`) is the only thing that rewrites the file: the kept ops go
+// to a temporary file headed by a `{"checkpoint": ...}` record, which then replaces the log.
+
+const CHECKSUM_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum OpLogError {
+    #[error("io error: {0}")]
+    Io(String),
+    #[error("serialize error: {0}")]
+    Serialize(String),
+}
+
+pub struct OpLog {
+    path: PathBuf,
+    file: File,
+    entries: Vec<Operation>,
+    by_key: HashMap<String, usize>,
+    by_document: HashMap<String, Vec<usize>>,
+    by_device: HashMap<String, Vec<usize>>,
+    checkpoint: Checkpoint,
+    skipped: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointRecord {
+    checkpoint: Checkpoint,
+}
+
+enum Record {
+    Op(Operation),
+    Checkpoint(Checkpoint),
+}
+
+fn checksum(record: &[u8]) -> String {
+    let digest = format!("{:x}", Sha256::digest(record));
+    digest[..CHECKSUM_LEN].to_string()
+}
+
+fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>, OpLogError> {
+    let json = serde_json::to_vec(record).map_err(|e| OpLogError::Serialize(e.to_string()))?;
+    let mut line = Vec::with_capacity(CHECKSUM_LEN + json.len() + 2);
+    line.extend_from_slice(checksum(&json).as_bytes());
+    line.push(b'\t');
+    line.extend_from_slice(&json);
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Parse one line (without its newline); `None` if it is torn or corrupt.
+fn decode(line: &[u8]) -> Option<Record> {
+    let (sum, json) = (line.get(..CHECKSUM_LEN)?, line.get(CHECKSUM_LEN + 1..)?);
+    if line[CHECKSUM_LEN] != b'\t' || checksum(json).as_bytes() != sum {
+        return None;
+    }
+    match serde_json::from_slice(json) {
+        Ok(op) => Some(Record::Op(op)),
+        Err(_) => serde_json::from_slice::<CheckpointRecord>(json)
+            .ok()
+            .map(|r| Record::Checkpoint(r.checkpoint)),
+    }
+}
+
+impl OpLog {
+    /// Open (or create) the log at `path`, recovering from a torn tail.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, OpLogError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| OpLogError::Io(e.to_string()))?;
+        }
+        let mut records = Vec::new();
+        let mut skipped = 0;
+        let mut valid_len = 0u64;
+        if path.exists() {
+            let file = File::open(&path).map_err(|e| OpLogError::Io(e.to_string()))?;
+            let mut reader = BufReader::new(file);
+            let mut offset = 0u64;
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let read = reader
+                    .read_until(b'\n', &mut line)
+                    .map_err(|e| OpLogError::Io(e.to_string()))?;
+                if read == 0 {
+                    break;
+                }
+                offset += read as u64;
+                if line.last() != Some(&b'\n') {
+                    break; // torn write
+                }
+                match decode(&line[..line.len() - 1]) {
+                    Some(record) => {
+                        records.push(record);
+                        valid_len = offset;
+                    }
+                    None => skipped += 1,
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| OpLogError::Io(e.to_string()))?;
+        // drop whatever follows the last good record (torn or corrupt tail)
+        let len = file
+            .metadata()
+            .map_err(|e| OpLogError::Io(e.to_string()))?
+            .len();
+        if len > valid_len {
+            file.set_len(valid_len)
+                .map_err(|e| OpLogError::Io(e.to_string()))?;
+        }
+
+        let mut log = Self {
+            path,
+            file,
+            entries: Vec::new(),
+            by_key: HashMap::new(),
+            by_document: HashMap::new(),
+            by_device: HashMap::new(),
+            checkpoint: Checkpoint::default(),
+            skipped,
+        };
+        for record in records {
+            match record {
+                Record::Op(op) if !log.contains(&op) => log.index(op),
+                Record::Op(_) => {}
+                Record::Checkpoint(checkpoint) => log.checkpoint = checkpoint,
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Corrupt records skipped in the middle of the file when it was opened.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn index(&mut self, op: Operation) {
+        let idx = self.entries.len();
+        self.by_key.insert(op.key(), idx);
+        self.by_document
+            .entry(op.document_id.clone())
+            .or_default()
+            .push(idx);
+        self.by_device
+            .entry(op.device_id.clone())
+            .or_default()
+            .push(idx);
+        self.entries.push(op);
+    }
+
+    fn write_records(&mut self, ops: &[Operation]) -> Result<(), OpLogError> {
+        let mut buf = Vec::new();
+        for op in ops {
+            buf.extend(encode(op)?);
+        }
+        self.file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| OpLogError::Io(e.to_string()))
+    }
+
+    /// Append a single op if not already present; returns whether it was added.
+    pub fn append(&mut self, op: Operation) -> Result<bool, OpLogError> {
+        if self.contains(&op) {
+            return Ok(false);
+        }
+        self.write_records(std::slice::from_ref(&op))?;
+        self.index(op);
+        Ok(true)
+    }
+
+    /// Append the ops not yet present (in one write) and return them.
+    pub fn merge(&mut self, incoming: &[Operation]) -> Result<Vec<Operation>, OpLogError> {
+        let mut accepted: Vec<Operation> = Vec::new();
+        for op in incoming {
+            if !self.contains(op) && !accepted.iter().any(|a| a.key() == op.key()) {
+                accepted.push(op.clone());
+            }
+        }
+        if !accepted.is_empty() {
+            self.write_records(&accepted)?;
+            for op in &accepted {
+                self.index(op.clone());
+            }
+        }
+        Ok(accepted)
+    }
+
+    /// Whether the log holds `op`, or held it before it was folded into a snapshot.
+    pub fn contains(&self, op: &Operation) -> bool {
+        self.by_key.contains_key(&op.key()) || self.checkpoint.covers(op)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Operation> {
+        self.by_key.get(key).map(|idx| &self.entries[*idx])
+    }
+
+    /// All ops in append order.
+    pub fn entries(&self) -> &[Operation] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Ops touching `document_id`, in append order.
+    pub fn document_ops(&self, document_id: &str) -> Vec<&Operation> {
+        self.indexed(self.by_document.get(document_id))
+    }
+
+    /// Ops authored by `device_id`, in the order this log received them.
+    pub fn device_ops(&self, device_id: &str) -> Vec<&Operation> {
+        self.indexed(self.by_device.get(device_id))
+    }
+
+    /// How many ops from `device_id` this log holds.
+    pub fn device_seq(&self, device_id: &str) -> usize {
+        self.by_device.get(device_id).map_or(0, Vec::len)
+    }
+
+    fn indexed(&self, idx: Option<&Vec<usize>>) -> Vec<&Operation> {
+        idx.map(|idx| idx.iter().map(|i| &self.entries[*i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// The latest compaction checkpoint (empty if the log was never compacted).
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    /// Collapse each document's ops older than `horizon` (an RFC 3339 timestamp, compared
+    /// like the ops' own) into the newest of them and drop the rest.
+    ///
+    /// A document is only compacted when its old ops form a single causal chain: with
+    /// concurrent edits (or ops from clients without clocks) there is no one op that holds
+    /// the merged state, so its history is kept as is.
+    pub fn compact(&mut self, horizon: &str) -> Result<CompactionReport, OpLogError> {
+        let mut checkpoint = self.checkpoint.clone();
+        let mut documents = 0;
+        for (document_id, idx) in &self.by_document {
+            let old: Vec<&Operation> = idx
+                .iter()
+                .map(|i| &self.entries[*i])
+                .filter(|op| is_content_op(op) && op.timestamp.as_str() < horizon)
+                .collect();
+            if old.len() < 2 || old.iter().any(|op| op.clock.is_empty()) {
+                continue;
+            }
+            let snapshot = old.iter().find(|candidate| {
+                old.iter().all(|op| {
+                    op.key() == candidate.key() || op.clock.happens_before(&candidate.clock)
+                })
+            });
+            let Some(snapshot) = snapshot else {
+                continue;
+            };
+            if let Some(previous) = checkpoint.documents.get(document_id) {
+                if !previous.happens_before(&snapshot.clock) {
+                    continue;
+                }
+            }
+            checkpoint
+                .documents
+                .insert(document_id.clone(), snapshot.clock.clone());
+            documents += 1;
+        }
+        if documents == 0 {
+            return Ok(CompactionReport::default());
+        }
+        if horizon > checkpoint.horizon.as_str() {
+            checkpoint.horizon = horizon.to_string();
+        }
+        let removed = self.rewrite(checkpoint)?;
+        Ok(CompactionReport { documents, removed })
+    }
+
+    /// Take over the snapshots of a peer's checkpoint for documents where this log already
+    /// holds the snapshot op (or a newer one), so both sides drop the same history. Returns
+    /// how many documents were adopted.
+    pub fn adopt_checkpoint(&mut self, remote: &Checkpoint) -> Result<usize, OpLogError> {
+        let mut checkpoint = self.checkpoint.clone();
+        let mut adopted = 0;
+        for (document_id, clock) in &remote.documents {
+            if let Some(ours) = checkpoint.documents.get(document_id) {
+                if !ours.happens_before(clock) {
+                    continue;
+                }
+            }
+            let has_snapshot = self.document_ops(document_id).iter().any(|op| {
+                is_content_op(op)
+                    && matches!(
+                        clock.compare(&op.clock),
+                        CausalOrder::Before | CausalOrder::Equal
+                    )
+            });
+            if has_snapshot {
+                checkpoint
+                    .documents
+                    .insert(document_id.clone(), clock.clone());
+                adopted += 1;
+            }
+        }
+        if adopted > 0 {
+            if remote.horizon > checkpoint.horizon {
+                checkpoint.horizon = remote.horizon.clone();
+            }
+            self.rewrite(checkpoint)?;
+        }
+        Ok(adopted)
+    }
+
+    /// Replace the file with `checkpoint` followed by the ops it doesn't cover; returns how
+    /// many ops were dropped.
+    fn rewrite(&mut self, checkpoint: Checkpoint) -> Result<usize, OpLogError> {
+        let kept: Vec<Operation> = self
+            .entries
+            .iter()
+            .filter(|op| !checkpoint.covers(op))
+            .cloned()
+            .collect();
+        let removed = self.entries.len() - kept.len();
+
+        let mut buf = encode(&CheckpointRecord {
+            checkpoint: checkpoint.clone(),
+        })?;
+        for op in &kept {
+            buf.extend(encode(op)?);
+        }
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp).map_err(|e| OpLogError::Io(e.to_string()))?;
+        file.write_all(&buf)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| OpLogError::Io(e.to_string()))?;
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| OpLogError::Io(e.to_string()))?;
+
+        self.entries.clear();
+        self.by_key.clear();
+        self.by_document.clear();
+        self.by_device.clear();
+        self.checkpoint = checkpoint;
+        for op in kept {
+            self.index(op);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OperationType, VectorClock};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn op(device: &str, id: &str, doc: &str) -> Operation {
+        Operation {
+            op_id: id.into(),
+            device_id: device.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: OperationType::UpdateDocument,
+            document_id: doc.into(),
+            payload: serde_json::json!({ "body": format!("{device} {id}") }),
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        }
+    }
+
+    fn clocked(
+        id: &str,
+        doc: &str,
+        timestamp: &str,
+        op_type: OperationType,
+        clock: &[(&str, u64)],
+    ) -> Operation {
+        let mut op = op("a", id, doc);
+        op.timestamp = timestamp.into();
+        op.op_type = op_type;
+        for (device, count) in clock {
+            for _ in 0..*count {
+                op.clock.increment(device);
+            }
+        }
+        op
+    }
+
+    #[test]
+    fn appends_dedups_and_indexes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("oplog.jsonl");
+        let mut log = OpLog::open(&path).unwrap();
+        assert!(log.append(op("a", "01", "x")).unwrap());
+        assert!(!log.append(op("a", "01", "x")).unwrap());
+        let merged = log
+            .merge(&[op("a", "01", "x"), op("b", "01", "y"), op("a", "02", "y")])
+            .unwrap();
+        assert_eq!(merged.len(), 2);
+
+        let log = OpLog::open(&path).unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.document_ops("y").len(), 2);
+        assert_eq!(log.device_seq("a"), 2);
+        assert_eq!(log.device_ops("b")[0].key(), "b:01");
+        assert!(log.get("a:02").is_some());
+    }
+
+    #[test]
+    fn recovers_from_torn_tail_and_skips_corrupt_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("oplog.jsonl");
+        let mut log = OpLog::open(&path).unwrap();
+        log.append(op("a", "01", "x")).unwrap();
+        log.append(op("a", "02", "x")).unwrap();
+        drop(log);
+
+        // flip a byte inside the first record, then simulate a crash mid-append
+        let mut raw = fs::read(&path).unwrap();
+        raw[CHECKSUM_LEN + 10] ^= 1;
+        raw.extend_from_slice(&encode(&op("a", "03", "x")).unwrap()[..20]);
+        fs::write(&path, &raw).unwrap();
+
+        let mut log = OpLog::open(&path).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.skipped(), 1);
+        assert_eq!(log.entries()[0].key(), "a:02");
+        log.append(op("a", "03", "x")).unwrap();
+        drop(log);
+
+        let log = OpLog::open(&path).unwrap();
+        let keys: Vec<String> = log.entries().iter().map(|o| o.key()).collect();
+        assert_eq!(keys, vec!["a:02", "a:03"]);
+
+        // a tail without its newline is cut off too
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"0123").unwrap();
+        drop(file);
+        assert_eq!(OpLog::open(&path).unwrap().len(), 2);
+        assert!(fs::read(&path).unwrap().ends_with(b"\n"));
+    }
+
+    #[test]
+    fn compaction_keeps_snapshots_and_tombstones() {
+        use OperationType::*;
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("oplog.jsonl");
+        let mut log = OpLog::open(&path).unwrap();
+        let ops = vec![
+            clocked("01", "x", "2025-01-01", CreateDocument, &[("a", 1)]),
+            clocked("02", "x", "2025-01-02", UpdateDocument, &[("a", 2)]),
+            clocked(
+                "03",
+                "x",
+                "2025-01-03",
+                UpdateDocument,
+                &[("a", 2), ("b", 1)],
+            ),
+            clocked(
+                "04",
+                "x",
+                "2025-03-01",
+                UpdateDocument,
+                &[("a", 3), ("b", 1)],
+            ),
+            clocked("05", "y", "2025-01-01", CreateDocument, &[("a", 1)]),
+            clocked("06", "y", "2025-01-02", DeleteDocument, &[("a", 2)]),
+            // concurrent edits: nothing to collapse into
+            clocked("07", "z", "2025-01-01", UpdateDocument, &[("a", 1)]),
+            clocked("08", "z", "2025-01-01", UpdateDocument, &[("b", 1)]),
+        ];
+        log.merge(&ops).unwrap();
+
+        let report = log.compact("2025-02-01").unwrap();
+        assert_eq!(
+            report,
+            CompactionReport {
+                documents: 2,
+                removed: 3
+            }
+        );
+        let keys: Vec<String> = log.entries().iter().map(|o| o.key()).collect();
+        assert_eq!(keys, vec!["a:03", "a:04", "a:06", "a:07", "a:08"]);
+        // dropped ops stay known, so a peer can't re-add them
+        assert!(log.contains(&ops[0]));
+        assert!(log.merge(&ops).unwrap().is_empty());
+        assert_eq!(log.compact("2025-02-01").unwrap().removed, 0);
+        log.append(clocked(
+            "09",
+            "x",
+            "2025-03-02",
+            UpdateDocument,
+            &[("a", 4), ("b", 1)],
+        ))
+        .unwrap();
+        drop(log);
+
+        let log = OpLog::open(&path).unwrap();
+        assert_eq!(log.len(), 6);
+        assert_eq!(log.checkpoint().horizon, "2025-02-01");
+        assert!(log.contains(&ops[1]));
+
+        // a peer that still has the full history adopts the checkpoint once it holds the
+        // snapshots
+        let mut peer = OpLog::open(dir.path().join("peer.jsonl")).unwrap();
+        peer.merge(&ops[..2]).unwrap();
+        assert_eq!(peer.adopt_checkpoint(log.checkpoint()).unwrap(), 0);
+        peer.merge(&ops).unwrap();
+        assert_eq!(peer.adopt_checkpoint(log.checkpoint()).unwrap(), 2);
+        assert_eq!(peer.len(), 5);
+    }
+}
@@ -0,0 +1,124 @@
+//! Applied-op registry: every op `apply` has dealt with is recorded in `applied_ops` (key, op
+//! hash, when and how), so re-deliveries are recognised across restarts and the sync layer can
+//! ask which of a set of op keys the vault already has without loading the op-log.
+
+use crate::{Store, StoreError};
+use chrono::Utc;
+use notes_oplog::Operation;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AppliedOutcome {
+    /// The op changed the vault.
+    Applied,
+    /// The local version already causally followed the op.
+    Superseded,
+    /// The op had conflicted and the conflict was resolved since.
+    Resolved,
+}
+
+impl AppliedOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            AppliedOutcome::Applied => "applied",
+            AppliedOutcome::Superseded => "superseded",
+            AppliedOutcome::Resolved => "resolved",
+        }
+    }
+
+    fn parse(raw: &str) -> Self {
+        match raw {
+            "superseded" => AppliedOutcome::Superseded,
+            "resolved" => AppliedOutcome::Resolved,
+            _ => AppliedOutcome::Applied,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AppliedOp {
+    pub op_key: String,
+    pub op_hash: String,
+    pub applied_at: String,
+    pub outcome: AppliedOutcome,
+}
+
+impl Store {
+    pub(crate) fn init_applied_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS applied_ops(
+                op_key TEXT PRIMARY KEY,
+                op_hash TEXT NOT NULL,
+                applied_at TEXT NOT NULL,
+                outcome TEXT NOT NULL
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    pub(crate) fn mark_applied(
+        &self,
+        op: &Operation,
+        outcome: AppliedOutcome,
+    ) -> Result<(), StoreError> {
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO applied_ops(op_key, op_hash, applied_at, outcome)
+                 VALUES(?1, ?2, ?3, ?4)",
+                params![
+                    op.key(),
+                    op.op_hash(),
+                    Utc::now().to_rfc3339(),
+                    outcome.as_str()
+                ],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn is_applied(&self, op_key: &str) -> Result<bool, StoreError> {
+        Ok(self.applied_op(op_key)?.is_some())
+    }
+
+    pub fn applied_op(&self, op_key: &str) -> Result<Option<AppliedOp>, StoreError> {
+        self.conn
+            .query_row(
+                "SELECT op_key, op_hash, applied_at, outcome FROM applied_ops WHERE op_key=?1",
+                params![op_key],
+                |row| {
+                    Ok(AppliedOp {
+                        op_key: row.get(0)?,
+                        op_hash: row.get(1)?,
+                        applied_at: row.get(2)?,
+                        outcome: AppliedOutcome::parse(&row.get::<_, String>(3)?),
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// The subset of `keys` this vault has already applied.
+    pub fn applied_op_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Result<BTreeSet<String>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT 1 FROM applied_ops WHERE op_key=?1")
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut applied = BTreeSet::new();
+        for key in keys {
+            let found = stmt
+                .exists(params![key])
+                .map_err(|e| StoreError::Db(e.to_string()))?;
+            if found {
+                applied.insert(key.to_string());
+            }
+        }
+        Ok(applied)
+    }
+}
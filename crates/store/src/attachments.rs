@@ -0,0 +1,268 @@
+//! Attachment store: file contents live content-addressed under `attachments/<sha256>` in the
+//! vault and the `attachments` table links blobs to documents. `AttachFile`/`DetachFile` ops
+//! only carry the blob hash (plus name and size), never the bytes, so a blob can arrive from a
+//! peer before or after the op that references it. Blobs are kept on detach; older ops and
+//! other documents may still point at them.
+
+use crate::{Store, StoreError};
+use chrono::Utc;
+use notes_oplog::{Operation, OperationType, VectorClock};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+
+const ATTACHMENTS_DIR: &str = "attachments";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub document_id: String,
+    /// sha256 of the contents, hex encoded; also the blob's file name.
+    pub hash: String,
+    /// Original file name, for display and export.
+    pub name: String,
+    pub size: u64,
+    pub added: String,
+    /// Whether the blob is present locally (it may still be in transit from a peer).
+    pub available: bool,
+}
+
+/// Payload of `AttachFile`/`DetachFile` ops.
+#[derive(Debug, Serialize, Deserialize)]
+struct AttachmentPayload {
+    hash: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    size: u64,
+}
+
+/// Hex sha256 of `data`, the address of a blob.
+pub fn blob_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn is_blob_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl Store {
+    pub(crate) fn init_attachments_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS attachments(
+                document_id TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                added TEXT NOT NULL,
+                PRIMARY KEY(document_id, hash)
+            );
+            CREATE INDEX IF NOT EXISTS attachments_hash ON attachments(hash);",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Directory holding the blobs, one file per sha256.
+    pub fn attachments_dir(&self) -> PathBuf {
+        self.root.join(ATTACHMENTS_DIR)
+    }
+
+    /// Where the blob with `hash` lives (whether or not it exists yet).
+    pub fn blob_path(&self, hash: &str) -> Result<PathBuf, StoreError> {
+        if !is_blob_hash(hash) {
+            return Err(StoreError::Document(format!("invalid blob hash {hash}")));
+        }
+        Ok(self.attachments_dir().join(hash))
+    }
+
+    pub fn has_blob(&self, hash: &str) -> bool {
+        self.blob_path(hash).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// Store `data` content-addressed and return its hash; storing the same bytes twice is a
+    /// no-op.
+    pub fn put_blob(&self, data: &[u8]) -> Result<String, StoreError> {
+        let hash = blob_hash(data);
+        let path = self.blob_path(&hash)?;
+        if !path.exists() {
+            fs::create_dir_all(self.attachments_dir())
+                .map_err(|e| StoreError::Io(e.to_string()))?;
+            // a crash never leaves a truncated blob behind
+            notes_core::write_atomic(&path, data).map_err(|e| StoreError::Io(e.to_string()))?;
+        }
+        Ok(hash)
+    }
+
+    pub fn read_blob(&self, hash: &str) -> Result<Vec<u8>, StoreError> {
+        let path = self.blob_path(hash)?;
+        if !path.exists() {
+            return Err(StoreError::NotFound);
+        }
+        fs::read(path).map_err(|e| StoreError::Io(e.to_string()))
+    }
+
+    /// Store `data` and attach it to `document_id` as a new `AttachFile` op authored by
+    /// `device_id`. Returns the attachment and the op to log/sync.
+    pub fn attach_file(
+        &mut self,
+        document_id: &str,
+        name: &str,
+        data: &[u8],
+        device_id: &str,
+    ) -> Result<(Attachment, Operation), StoreError> {
+        if self.load_document(document_id)?.is_none() {
+            return Err(StoreError::NotFound);
+        }
+        let hash = self.put_blob(data)?;
+        let op = attachment_op(
+            OperationType::AttachFile,
+            document_id,
+            device_id,
+            AttachmentPayload {
+                hash: hash.clone(),
+                name: name.to_string(),
+                size: data.len() as u64,
+            },
+        )?;
+        self.apply(op.clone())?;
+        let attachment = self
+            .list_attachments(document_id)?
+            .into_iter()
+            .find(|a| a.hash == hash)
+            .ok_or(StoreError::NotFound)?;
+        Ok((attachment, op))
+    }
+
+    /// Unlink the blob `hash` from `document_id` with a `DetachFile` op authored by
+    /// `device_id`. Returns the op to log/sync.
+    pub fn detach_file(
+        &mut self,
+        document_id: &str,
+        hash: &str,
+        device_id: &str,
+    ) -> Result<Operation, StoreError> {
+        let attachment = self
+            .list_attachments(document_id)?
+            .into_iter()
+            .find(|a| a.hash == hash)
+            .ok_or(StoreError::NotFound)?;
+        let op = attachment_op(
+            OperationType::DetachFile,
+            document_id,
+            device_id,
+            AttachmentPayload {
+                hash: attachment.hash,
+                name: attachment.name,
+                size: attachment.size,
+            },
+        )?;
+        self.apply(op.clone())?;
+        Ok(op)
+    }
+
+    /// Attachments of a document, oldest first.
+    pub fn list_attachments(&self, document_id: &str) -> Result<Vec<Attachment>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT hash, name, size, added FROM attachments
+                 WHERE document_id=?1 ORDER BY added, name",
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map(params![document_id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut attachments = Vec::new();
+        for r in rows {
+            let (hash, name, size, added) = r.map_err(|e| StoreError::Db(e.to_string()))?;
+            attachments.push(Attachment {
+                document_id: document_id.to_string(),
+                available: self.has_blob(&hash),
+                hash,
+                name,
+                size: size as u64,
+                added,
+            });
+        }
+        Ok(attachments)
+    }
+
+    /// Apply an `AttachFile`/`DetachFile` op to the attachments table.
+    pub(crate) fn apply_attachment_op(&self, op: &Operation) -> Result<(), StoreError> {
+        let payload: AttachmentPayload = serde_json::from_value(op.payload.clone())
+            .map_err(|e| StoreError::Document(e.to_string()))?;
+        if !is_blob_hash(&payload.hash) {
+            return Err(StoreError::Document(format!(
+                "invalid blob hash {}",
+                payload.hash
+            )));
+        }
+        match op.op_type {
+            OperationType::AttachFile => {
+                self.conn
+                    .execute(
+                        "INSERT INTO attachments(document_id, hash, name, size, added)
+                         VALUES(?1, ?2, ?3, ?4, ?5)
+                         ON CONFLICT(document_id, hash) DO UPDATE SET name=excluded.name",
+                        params![
+                            op.document_id,
+                            payload.hash,
+                            payload.name,
+                            payload.size as i64,
+                            op.timestamp
+                        ],
+                    )
+                    .map_err(|e| StoreError::Db(e.to_string()))?;
+            }
+            OperationType::DetachFile => {
+                self.conn
+                    .execute(
+                        "DELETE FROM attachments WHERE document_id=?1 AND hash=?2",
+                        params![op.document_id, payload.hash],
+                    )
+                    .map_err(|e| StoreError::Db(e.to_string()))?;
+            }
+            _ => return Err(StoreError::Unsupported),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_attachments(&self, document_id: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute(
+                "DELETE FROM attachments WHERE document_id=?1",
+                params![document_id],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+}
+
+fn attachment_op(
+    op_type: OperationType,
+    document_id: &str,
+    device_id: &str,
+    payload: AttachmentPayload,
+) -> Result<Operation, StoreError> {
+    Ok(Operation {
+        op_id: notes_core::generate_id(),
+        device_id: device_id.to_string(),
+        timestamp: Utc::now().to_rfc3339(),
+        op_type,
+        document_id: document_id.to_string(),
+        payload: serde_json::to_value(payload).map_err(|e| StoreError::Document(e.to_string()))?,
+        // those are document content hashes; the blob's hash is in the payload only
+        before_hash: None,
+        after_hash: None,
+        // attachments don't change the document's content, so they don't advance its clock
+        clock: VectorClock::new(),
+    })
+}
@@ -0,0 +1,148 @@
+//! Batch application for sync: a peer's ops are applied in causal order inside one SQLite
+//! transaction, with document writes staged (see `journal.rs`) so each touched file is written
+//! once, after the commit. Each op runs under a savepoint, so a per-op failure (bad payload,
+//! hash mismatch) is reported and undoes only that op; a conflict keeps its record. An I/O or
+//! database error before the commit rolls the whole index back and leaves the documents
+//! untouched (conflict copies it wrote are removed). A file that can't be written after the
+//! commit doesn't undo the batch: its write stays journaled for `Store::recover`, and the
+//! report lists it.
+
+use crate::history::sort_causally;
+use crate::journal::Staged;
+use crate::{AppliedOutcome, Store, StoreError};
+use notes_oplog::Operation;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    /// Valid, but the local version already supersedes it; recorded as seen, nothing written.
+    Superseded,
+    /// Applied before.
+    Duplicate,
+    /// Concurrent with the local version and not mergeable; recorded as a conflict.
+    Conflict,
+    /// Rejected because the content it describes doesn't match its `after_hash`.
+    HashMismatch,
+    Rejected {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OpOutcome {
+    pub op_key: String,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// Outcomes of a batch, in the (causal) order the ops were applied.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BatchReport {
+    pub outcomes: Vec<OpOutcome>,
+    /// Documents whose files couldn't be written after the commit; `Store::recover` finishes
+    /// them.
+    #[serde(default)]
+    pub unflushed: Vec<String>,
+}
+
+impl BatchReport {
+    pub fn count(&self, outcome: &Outcome) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|o| &o.outcome == outcome)
+            .count()
+    }
+}
+
+impl Store {
+    /// Apply `ops` in causal order in a single transaction.
+    pub fn apply_batch(&mut self, ops: &[Operation]) -> Result<BatchReport, StoreError> {
+        self.apply_batch_with_history(ops, &[])
+    }
+
+    /// Like [`Store::apply_batch`], with `history` (the op-log) used to merge conflicting
+    /// updates as in [`Store::apply_with_history`]. Ops of the batch count as history for
+    /// the ones after them.
+    pub fn apply_batch_with_history(
+        &mut self,
+        ops: &[Operation],
+        history: &[Operation],
+    ) -> Result<BatchReport, StoreError> {
+        let mut ordered: Vec<&Operation> = ops.iter().collect();
+        sort_causally(&mut ordered);
+
+        self.conn
+            .execute_batch("BEGIN")
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        self.staged = Some(Staged::new());
+        let result = self.apply_ordered(&ordered, history).and_then(|outcomes| {
+            self.conn
+                .execute_batch("COMMIT")
+                .map_err(|e| StoreError::Db(e.to_string()))?;
+            Ok(outcomes)
+        });
+        let staged = self.staged.take().unwrap_or_default();
+        match result {
+            Ok(outcomes) => Ok(BatchReport {
+                outcomes,
+                unflushed: self.flush_staged(&staged),
+            }),
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                let _ = self.remove_orphaned_conflict_copies();
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_ordered(
+        &mut self,
+        ordered: &[&Operation],
+        history: &[Operation],
+    ) -> Result<Vec<OpOutcome>, StoreError> {
+        let mut history = history.to_vec();
+        let mut outcomes = Vec::with_capacity(ordered.len());
+        for op in ordered {
+            let op_key = op.key();
+            let outcome = if self.is_applied(&op_key)? {
+                Outcome::Duplicate
+            } else {
+                self.savepoint("SAVEPOINT batch_op")?;
+                let staged = self.staged_entry(&op.document_id);
+                let outcome = match self.apply_with_history((*op).clone(), &history) {
+                    Ok(_) => {
+                        history.push((*op).clone());
+                        // an op that left no record matched a conflict already on file
+                        match self.applied_op(&op_key)?.map(|a| a.outcome) {
+                            Some(AppliedOutcome::Superseded) => Outcome::Superseded,
+                            Some(_) => Outcome::Applied,
+                            None => Outcome::Conflict,
+                        }
+                    }
+                    Err(StoreError::Conflict(_)) => Outcome::Conflict,
+                    Err(StoreError::HashMismatch(_)) => Outcome::HashMismatch,
+                    Err(e @ (StoreError::Io(_) | StoreError::Db(_))) => return Err(e),
+                    Err(e) => Outcome::Rejected {
+                        reason: e.to_string(),
+                    },
+                };
+                if matches!(outcome, Outcome::HashMismatch | Outcome::Rejected { .. }) {
+                    self.savepoint("ROLLBACK TO batch_op")?;
+                    self.restore_staged_entry(&op.document_id, staged);
+                }
+                self.savepoint("RELEASE batch_op")?;
+                outcome
+            };
+            outcomes.push(OpOutcome { op_key, outcome });
+        }
+        Ok(outcomes)
+    }
+
+    fn savepoint(&self, sql: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute_batch(sql)
+            .map_err(|e| StoreError::Db(e.to_string()))
+    }
+}
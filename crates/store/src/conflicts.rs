@@ -0,0 +1,288 @@
+//! Conflict registry: every conflict copy written by `apply` is recorded in the `conflicts`
+//! table together with the remote op that caused it, so conflicts can be listed and resolved
+//! with a regular op that causally follows both sides. Delete-vs-edit races are conflicts too
+//! (see `tombstones.rs`); when the remote side is the delete there is no copy to write.
+
+use crate::{AppliedOutcome, Store, StoreError};
+use chrono::Utc;
+use notes_core::Document;
+use notes_oplog::{Operation, OperationType, VectorClock};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Both sides edited the document.
+    Edit,
+    /// The document was deleted here and edited remotely; the copy holds the remote edit.
+    DeletedLocally,
+    /// The document was edited here and deleted remotely; there is no copy.
+    DeletedRemotely,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConflictRecord {
+    pub id: i64,
+    pub document_id: String,
+    pub kind: ConflictKind,
+    /// Hash of the local document when the conflict was detected.
+    pub local_hash: Option<String>,
+    pub remote_op_key: String,
+    /// Empty when there is no copy (`DeletedRemotely`).
+    pub copy_path: String,
+    pub detected_at: String,
+}
+
+/// How to settle a conflict. `Merged` carries user-edited content in the same shape as an op
+/// payload. For delete-vs-edit conflicts, keeping the deleting side deletes the document.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "choice", rename_all = "snake_case")]
+pub enum ConflictChoice {
+    KeepLocal,
+    KeepRemote,
+    Merged {
+        frontmatter: serde_yaml::Value,
+        body: String,
+    },
+}
+
+pub(crate) enum ConflictStatus {
+    Open,
+    Resolved,
+}
+
+impl Store {
+    pub(crate) fn init_conflicts_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conflicts(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                document_id TEXT NOT NULL,
+                local_hash TEXT,
+                remote_op_key TEXT NOT NULL,
+                remote_payload TEXT NOT NULL,
+                remote_clock TEXT NOT NULL,
+                copy_path TEXT NOT NULL,
+                detected_at TEXT NOT NULL,
+                resolved_at TEXT,
+                resolution TEXT
+            );
+            CREATE INDEX IF NOT EXISTS conflicts_op ON conflicts(remote_op_key);",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Whether `op_key` already produced a conflict (so re-deliveries don't pile up copies).
+    pub(crate) fn conflict_status(
+        &self,
+        op_key: &str,
+    ) -> Result<Option<ConflictStatus>, StoreError> {
+        let resolved: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT resolved_at FROM conflicts WHERE remote_op_key=?1 ORDER BY id DESC LIMIT 1",
+                params![op_key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(resolved.map(|r| match r {
+            Some(_) => ConflictStatus::Resolved,
+            None => ConflictStatus::Open,
+        }))
+    }
+
+    /// Write a conflict copy (if any) next to the document and register the conflict. `local`
+    /// is `None` when the document was deleted here.
+    pub(crate) fn record_conflict(
+        &self,
+        op: &Operation,
+        local: Option<&Document>,
+        copy: Option<&Document>,
+    ) -> Result<(), StoreError> {
+        let mut filename = String::new();
+        if let Some(copy) = copy {
+            // the id keeps copies made within the same second apart
+            let ts = Utc::now().format("%Y%m%d%H%M%S");
+            filename = format!(
+                "{}.conflict.{ts}.{}.md",
+                op.document_id,
+                notes_core::generate_id()
+            );
+            let content = copy
+                .to_markdown()
+                .map_err(|e| StoreError::Document(e.to_string()))?;
+            notes_core::write_atomic(self.root.join(&filename), content)
+                .map_err(|e| StoreError::Io(e.to_string()))?;
+        }
+        let remote_clock =
+            serde_json::to_string(&op.clock).map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute(
+                "INSERT INTO conflicts(document_id, local_hash, remote_op_key, remote_payload, remote_clock, copy_path, detected_at)
+                 VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    op.document_id,
+                    local.map(|d| d.hash_content()),
+                    op.key(),
+                    op.payload.to_string(),
+                    remote_clock,
+                    filename,
+                    Utc::now().to_rfc3339()
+                ],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    /// For an op that conflicts with the local copy: `Ok(true)` if that conflict was resolved
+    /// already, an error while it is still open, `Ok(false)` if it hasn't conflicted before.
+    pub(crate) fn known_conflict(&self, op: &Operation) -> Result<bool, StoreError> {
+        match self.conflict_status(&op.key())? {
+            Some(ConflictStatus::Resolved) => {
+                self.mark_applied(op, AppliedOutcome::Resolved)?;
+                Ok(true)
+            }
+            Some(ConflictStatus::Open) => Err(StoreError::Conflict(op.document_id.clone())),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove conflict copies no open conflict refers to, e.g. ones written by a batch that
+    /// was rolled back.
+    pub(crate) fn remove_orphaned_conflict_copies(&self) -> Result<(), StoreError> {
+        let open: Vec<String> = self
+            .list_conflicts()?
+            .into_iter()
+            .map(|c| c.copy_path)
+            .collect();
+        let entries = fs::read_dir(&self.root).map_err(|e| StoreError::Io(e.to_string()))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.contains(".conflict.") && name.ends_with(".md") && !open.contains(&name) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// Unresolved conflicts, oldest first.
+    pub fn list_conflicts(&self) -> Result<Vec<ConflictRecord>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, document_id, local_hash, remote_op_key, copy_path, detected_at,
+                 remote_payload FROM conflicts WHERE resolved_at IS NULL ORDER BY id",
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map([], |row| {
+                let local_hash: Option<String> = row.get(2)?;
+                let kind = if row.get::<_, String>(6)? == "null" {
+                    ConflictKind::DeletedRemotely
+                } else if local_hash.is_none() {
+                    ConflictKind::DeletedLocally
+                } else {
+                    ConflictKind::Edit
+                };
+                Ok(ConflictRecord {
+                    id: row.get(0)?,
+                    document_id: row.get(1)?,
+                    kind,
+                    local_hash,
+                    remote_op_key: row.get(3)?,
+                    copy_path: row.get(4)?,
+                    detected_at: row.get(5)?,
+                })
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut conflicts = Vec::new();
+        for r in rows {
+            conflicts.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
+        }
+        Ok(conflicts)
+    }
+
+    /// Settle a conflict by writing the chosen content as a new op authored by `device_id`: an
+    /// update, a create when the document had been deleted here, or a delete when the chosen
+    /// side is a deletion. The op's clock covers both sides, so peers holding either one
+    /// fast-forward to it. Returns the written document (`None` if deleted) and the op to
+    /// log/sync.
+    pub fn resolve_conflict(
+        &mut self,
+        conflict_id: i64,
+        choice: ConflictChoice,
+        device_id: &str,
+    ) -> Result<(Option<Document>, Operation), StoreError> {
+        let row: (String, String, String, String) = self
+            .conn
+            .query_row(
+                "SELECT document_id, remote_payload, remote_clock, copy_path FROM conflicts
+                 WHERE id=?1 AND resolved_at IS NULL",
+                params![conflict_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .map_err(|e| StoreError::Db(e.to_string()))?
+            .ok_or(StoreError::NotFound)?;
+        let (document_id, remote_payload, remote_clock, copy_path) = row;
+
+        let current = self.load_document(&document_id)?;
+        let resolution = match &choice {
+            ConflictChoice::KeepLocal => "keep_local",
+            ConflictChoice::KeepRemote => "keep_remote",
+            ConflictChoice::Merged { .. } => "merged",
+        };
+        // `None`: the chosen side is a deletion
+        let payload = match choice {
+            ConflictChoice::KeepLocal => current.as_ref().map(
+                |local| serde_json::json!({ "frontmatter": local.frontmatter, "body": local.body }),
+            ),
+            ConflictChoice::KeepRemote => {
+                let remote: serde_json::Value = serde_json::from_str(&remote_payload)
+                    .map_err(|e| StoreError::Document(e.to_string()))?;
+                Some(remote).filter(|p| !p.is_null())
+            }
+            ConflictChoice::Merged { frontmatter, body } => {
+                Some(serde_json::json!({ "frontmatter": frontmatter, "body": body }))
+            }
+        };
+        let op_type = match (&payload, &current) {
+            (None, _) => OperationType::DeleteDocument,
+            (Some(_), None) => OperationType::CreateDocument,
+            (Some(_), Some(_)) => OperationType::UpdateDocument,
+        };
+
+        let remote_clock: VectorClock = serde_json::from_str(&remote_clock).unwrap_or_default();
+        let mut clock = self.document_clock(&document_id)?.merged(&remote_clock);
+        clock.increment(device_id);
+        let mut op = Operation {
+            op_id: notes_core::generate_id(),
+            device_id: device_id.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type,
+            document_id: document_id.clone(),
+            payload: payload.unwrap_or(serde_json::Value::Null),
+            before_hash: current.as_ref().map(|d| d.hash_content()),
+            after_hash: None,
+            clock,
+        };
+        let doc = self.apply(op.clone())?;
+        if !matches!(op.op_type, OperationType::DeleteDocument) {
+            let doc = doc.as_ref().ok_or(StoreError::NotFound)?;
+            op.after_hash = Some(doc.hash_content());
+        }
+
+        self.conn
+            .execute(
+                "UPDATE conflicts SET resolved_at=?1, resolution=?2 WHERE id=?3",
+                params![Utc::now().to_rfc3339(), resolution, conflict_id],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        if !copy_path.is_empty() {
+            let _ = fs::remove_file(self.root.join(copy_path));
+        }
+        Ok((doc, op))
+    }
+}
@@ -0,0 +1,260 @@
+//! Per-document version history reconstructed from the op-log: every create/update op is a
+//! version (materialized exactly as `apply` would write it), deletes are recorded as
+//! tombstone entries. Versions are ordered causally (clock sum, then timestamp, then key).
+
+use crate::merge::lcs_matches;
+use crate::{Store, StoreError};
+use chrono::Utc;
+use notes_core::Document;
+use notes_oplog::{Operation, OperationType};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DocumentVersion {
+    pub op_key: String,
+    pub device_id: String,
+    pub timestamp: String,
+    /// Content hash of the materialized version; `None` for deletes.
+    pub hash: Option<String>,
+    pub deleted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    Equal,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VersionDiff {
+    pub frontmatter: Vec<FieldChange>,
+    pub body: Vec<DiffLine>,
+}
+
+/// Ops touching `id`, in causal order.
+fn document_ops<'a>(id: &str, ops: &'a [Operation]) -> Vec<&'a Operation> {
+    let mut out: Vec<&Operation> = ops
+        .iter()
+        .filter(|op| op.document_id == id)
+        .filter(|op| {
+            matches!(
+                op.op_type,
+                OperationType::CreateDocument
+                    | OperationType::UpdateDocument
+                    | OperationType::DeleteDocument
+            )
+        })
+        .collect();
+    sort_causally(&mut out);
+    out
+}
+
+/// Sort ops so that causally earlier ones come first: an op's clock sum is strictly larger
+/// than that of every op it has seen. Ties (concurrent or clock-less ops) fall back to the
+/// timestamp and then the op key, so the order is deterministic.
+pub(crate) fn sort_causally(ops: &mut [&Operation]) {
+    ops.sort_by(|a, b| {
+        let sum = |op: &Operation| op.clock.iter().map(|(_, n)| n).sum::<u64>();
+        (sum(a), &a.timestamp, a.key()).cmp(&(sum(b), &b.timestamp, b.key()))
+    });
+}
+
+impl Store {
+    /// Ordered version list of a document, oldest first.
+    pub fn document_history(&self, id: &str, ops: &[Operation]) -> Vec<DocumentVersion> {
+        document_ops(id, ops)
+            .into_iter()
+            .map(|op| {
+                let deleted = matches!(op.op_type, OperationType::DeleteDocument);
+                let hash = if deleted {
+                    None
+                } else {
+                    self.materialize(op).ok().map(|d| d.hash_content())
+                };
+                DocumentVersion {
+                    op_key: op.key(),
+                    device_id: op.device_id.clone(),
+                    timestamp: op.timestamp.clone(),
+                    hash,
+                    deleted,
+                }
+            })
+            .collect()
+    }
+
+    /// The document as written by the op `op_key`.
+    pub fn document_version(
+        &self,
+        id: &str,
+        ops: &[Operation],
+        op_key: &str,
+    ) -> Result<Document, StoreError> {
+        let op = document_ops(id, ops)
+            .into_iter()
+            .find(|op| op.key() == op_key)
+            .ok_or(StoreError::NotFound)?;
+        if matches!(op.op_type, OperationType::DeleteDocument) {
+            return Err(StoreError::NotFound);
+        }
+        self.materialize(op)
+    }
+
+    /// Line diff of the body plus changed frontmatter fields between two versions.
+    pub fn diff_versions(
+        &self,
+        id: &str,
+        ops: &[Operation],
+        from_key: &str,
+        to_key: &str,
+    ) -> Result<VersionDiff, StoreError> {
+        let from = self.document_version(id, ops, from_key)?;
+        let to = self.document_version(id, ops, to_key)?;
+        Ok(diff_documents(&from, &to))
+    }
+
+    /// Bring back an old version as a new op authored by `device_id` (an update if the
+    /// document exists, a create if it was deleted). Returns the document and the op to log.
+    pub fn restore_version(
+        &mut self,
+        id: &str,
+        ops: &[Operation],
+        op_key: &str,
+        device_id: &str,
+    ) -> Result<(Document, Operation), StoreError> {
+        let version = self.document_version(id, ops, op_key)?;
+        let current = self.load_document(id)?;
+        let mut op = Operation {
+            op_id: notes_core::generate_id(),
+            device_id: device_id.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: if current.is_some() {
+                OperationType::UpdateDocument
+            } else {
+                OperationType::CreateDocument
+            },
+            document_id: id.to_string(),
+            payload: serde_json::json!({
+                "frontmatter": version.frontmatter,
+                "body": version.body,
+            }),
+            before_hash: current.map(|d| d.hash_content()),
+            after_hash: None,
+            clock: self.next_clock(id, device_id)?,
+        };
+        let doc = self.apply(op.clone())?.ok_or(StoreError::NotFound)?;
+        op.after_hash = Some(doc.hash_content());
+        Ok((doc, op))
+    }
+}
+
+pub fn diff_documents(from: &Document, to: &Document) -> VersionDiff {
+    let (a, b) = (&from.frontmatter, &to.frontmatter);
+    let mut frontmatter = Vec::new();
+    let mut field = |name: &str, before: String, after: String| {
+        if before != after {
+            frontmatter.push(FieldChange {
+                field: name.to_string(),
+                before,
+                after,
+            });
+        }
+    };
+    field(
+        "type",
+        format!("{:?}", a.doc_type).to_lowercase(),
+        format!("{:?}", b.doc_type).to_lowercase(),
+    );
+    field(
+        "title",
+        a.title.clone().unwrap_or_default(),
+        b.title.clone().unwrap_or_default(),
+    );
+    field("created", a.created.clone(), b.created.clone());
+    field("tags", a.tags.join(", "), b.tags.join(", "));
+    field("links", a.links.join(", "), b.links.join(", "));
+    let extra_keys = a
+        .extra
+        .keys()
+        .chain(b.extra.keys().filter(|k| !a.extra.contains_key(*k)));
+    for key in extra_keys {
+        let text = |v: Option<&serde_yaml::Value>| match v {
+            None => String::new(),
+            Some(serde_yaml::Value::String(s)) => s.clone(),
+            Some(v) => serde_yaml::to_string(v)
+                .unwrap_or_default()
+                .trim_end()
+                .to_string(),
+        };
+        let name = key
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| text(Some(key)));
+        field(&name, text(a.extra.get(key)), text(b.extra.get(key)));
+    }
+
+    VersionDiff {
+        frontmatter,
+        body: diff_lines(&from.body, &to.body),
+    }
+}
+
+fn diff_lines(from: &str, to: &str) -> Vec<DiffLine> {
+    let a: Vec<&str> = from.lines().collect();
+    let b: Vec<&str> = to.lines().collect();
+    let matches = lcs_matches(&a, &b);
+    let line = |kind, text: &str| DiffLine {
+        kind,
+        text: text.to_string(),
+    };
+    let mut out = Vec::new();
+    let mut j = 0;
+    for (i, m) in matches.iter().enumerate() {
+        match m {
+            Some(target) => {
+                out.extend(b[j..*target].iter().map(|t| line(DiffKind::Added, t)));
+                out.push(line(DiffKind::Equal, a[i]));
+                j = target + 1;
+            }
+            None => out.push(line(DiffKind::Removed, a[i])),
+        }
+    }
+    out.extend(b[j..].iter().map(|t| line(DiffKind::Added, t)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_diff_marks_added_and_removed() {
+        let diff = diff_lines("a\nb\nc", "a\nc\nd");
+        let kinds: Vec<DiffKind> = diff.iter().map(|l| l.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DiffKind::Equal,
+                DiffKind::Removed,
+                DiffKind::Equal,
+                DiffKind::Added
+            ]
+        );
+        assert_eq!(diff[1].text, "b");
+        assert_eq!(diff[3].text, "d");
+    }
+}
@@ -0,0 +1,269 @@
+//! Crash-safe document writes. A write touches two things that can't share a transaction: the
+//! `<id>.md` file and the index rows (documents, full-text, links, clock). Before either is
+//! changed, the state the document is headed for (its markdown, or nothing for a delete, plus
+//! its clock) is committed to `pending_writes`. The file is then replaced atomically
+//! (temp file, fsync, rename) and the index updated in a transaction that also removes the
+//! journal row, so the index only commits after the rename. Whatever a crash interrupts, the
+//! journal row survives until the write is complete, and `recover` (run by `with_root`) rolls
+//! it forward. A journaled write whose markdown no longer parses can't be rolled forward; its
+//! content is moved to `<vault>/.recovery/<id>.md` and reported instead.
+//!
+//! Inside `apply_batch` writes are staged instead: journal row and index rows go into the
+//! batch transaction, the new content is kept in memory for later ops of the batch to read, and
+//! the files are written once the transaction has committed.
+
+use crate::{Store, StoreError};
+use notes_core::Document;
+use notes_oplog::VectorClock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Writes staged by an open batch: per document, the markdown and document it will be written
+/// as, or `None` for a delete.
+pub(crate) type Staged = BTreeMap<String, Option<(String, Document)>>;
+
+/// A journaled write [`Store::recover`] couldn't complete because its markdown doesn't parse.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UnparseableWrite {
+    pub document_id: String,
+    /// Where the journaled markdown was moved.
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// What [`Store::recover`] repaired.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Interrupted document writes that were completed.
+    pub completed: usize,
+    /// Leftover temp files from interrupted writes that were removed.
+    pub removed_temp_files: usize,
+    /// Journaled writes that were moved aside instead; the document is left as it was.
+    pub set_aside: Vec<UnparseableWrite>,
+}
+
+impl Store {
+    pub(crate) fn init_journal_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pending_writes(
+                document_id TEXT PRIMARY KEY,
+                content TEXT,
+                clock TEXT NOT NULL
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Write `doc` (or delete the document when `None`) with its index rows and clock: right
+    /// away, or staged when a batch is open.
+    pub(crate) fn write_document(
+        &mut self,
+        id: &str,
+        doc: Option<&Document>,
+        clock: &VectorClock,
+    ) -> Result<(), StoreError> {
+        if self.staged.is_none() {
+            return self.write_durably(id, doc, clock);
+        }
+        let content = doc
+            .map(|d| d.to_markdown())
+            .transpose()
+            .map_err(|e| StoreError::Document(e.to_string()))?;
+        self.journal_write(id, content.as_deref(), clock)?;
+        match doc {
+            Some(doc) => self.upsert_index(doc)?,
+            None => self.delete_index(id)?,
+        }
+        self.set_document_clock(id, clock)?;
+        if let Some(staged) = self.staged.as_mut() {
+            staged.insert(id.to_string(), content.zip(doc.cloned()));
+        }
+        Ok(())
+    }
+
+    /// The staged version of `id` in the open batch: `Some(None)` if it is being deleted.
+    pub(crate) fn staged_document(&self, id: &str) -> Option<Option<Document>> {
+        let staged = self.staged.as_ref()?.get(id)?;
+        Some(staged.as_ref().map(|(_, doc)| doc.clone()))
+    }
+
+    /// The staged entry for `id`, to put back with `restore_staged_entry` if an op is undone.
+    pub(crate) fn staged_entry(&self, id: &str) -> Option<Option<(String, Document)>> {
+        self.staged.as_ref()?.get(id).cloned()
+    }
+
+    pub(crate) fn restore_staged_entry(
+        &mut self,
+        id: &str,
+        entry: Option<Option<(String, Document)>>,
+    ) {
+        if let Some(staged) = self.staged.as_mut() {
+            match entry {
+                Some(entry) => staged.insert(id.to_string(), entry),
+                None => staged.remove(id),
+            };
+        }
+    }
+
+    /// Write the files of a committed batch and clear their journal rows. Returns the ids
+    /// whose file couldn't be written; their journal rows stay for `recover`, as do all of
+    /// them if clearing the rows fails (repeating a write is harmless).
+    pub(crate) fn flush_staged(&self, staged: &Staged) -> Vec<String> {
+        let mut failed = Vec::new();
+        let mut written = Vec::new();
+        for (id, content) in staged {
+            match self.write_file(id, content.as_ref().map(|(content, _)| content.as_str())) {
+                Ok(()) => written.push(id),
+                Err(_) => failed.push(id.clone()),
+            }
+        }
+        let clear = || -> rusqlite::Result<()> {
+            let tx = self.conn.unchecked_transaction()?;
+            for id in written {
+                self.conn.execute(
+                    "DELETE FROM pending_writes WHERE document_id=?1",
+                    params![id],
+                )?;
+            }
+            tx.commit()
+        };
+        let _ = clear();
+        failed
+    }
+
+    /// Durably write `doc` (or delete the document when `None`) and its index rows, and set its
+    /// clock (an empty clock leaves it alone).
+    pub(crate) fn write_durably(
+        &self,
+        id: &str,
+        doc: Option<&Document>,
+        clock: &VectorClock,
+    ) -> Result<(), StoreError> {
+        let content = doc
+            .map(|d| d.to_markdown())
+            .transpose()
+            .map_err(|e| StoreError::Document(e.to_string()))?;
+        self.journal_write(id, content.as_deref(), clock)?;
+        self.finish_write(id, content.as_deref().zip(doc), clock)
+    }
+
+    pub(crate) fn journal_write(
+        &self,
+        id: &str,
+        content: Option<&str>,
+        clock: &VectorClock,
+    ) -> Result<(), StoreError> {
+        let clock = serde_json::to_string(clock).map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute(
+                "INSERT INTO pending_writes(document_id, content, clock) VALUES(?1, ?2, ?3)
+                 ON CONFLICT(document_id) DO UPDATE SET content=excluded.content, clock=excluded.clock",
+                params![id, content, clock],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Apply a journaled write (the markdown and the document it encodes); safe to repeat.
+    fn finish_write(
+        &self,
+        id: &str,
+        written: Option<(&str, &Document)>,
+        clock: &VectorClock,
+    ) -> Result<(), StoreError> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        self.write_file(id, written.map(|(content, _)| content))?;
+        match written {
+            Some((_, doc)) => self.upsert_index(doc)?,
+            None => self.delete_index(id)?,
+        }
+        self.set_document_clock(id, clock)?;
+        self.conn
+            .execute(
+                "DELETE FROM pending_writes WHERE document_id=?1",
+                params![id],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        tx.commit().map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Replace `<id>.md` with `content`, or move it to the trash.
+    fn write_file(&self, id: &str, content: Option<&str>) -> Result<(), StoreError> {
+        let result = match content {
+            Some(content) => notes_core::write_atomic(self.doc_path(id), content),
+            None => self.move_to_trash(id),
+        };
+        result.map_err(|e| StoreError::Io(e.to_string()))
+    }
+
+    /// Complete document writes a crash interrupted and remove their temp files. A write
+    /// whose markdown doesn't parse is moved to `.recovery/` and its journal row dropped.
+    pub fn recover(&self) -> Result<RecoveryReport, StoreError> {
+        let mut report = RecoveryReport::default();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT document_id, content, clock FROM pending_writes")
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let pending = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        drop(stmt);
+        for (id, content, clock) in pending {
+            let clock: VectorClock = serde_json::from_str(&clock).unwrap_or_default();
+            let doc = match content.as_deref().map(Document::from_markdown).transpose() {
+                Ok(doc) => doc,
+                Err(e) => {
+                    let path = self.set_aside(&id, content.as_deref().unwrap_or_default())?;
+                    report.set_aside.push(UnparseableWrite {
+                        document_id: id,
+                        path,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            self.finish_write(&id, content.as_deref().zip(doc.as_ref()), &clock)?;
+            report.completed += 1;
+        }
+
+        let entries = fs::read_dir(&self.root).map_err(|e| StoreError::Io(e.to_string()))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            if name.to_string_lossy().ends_with(".md.tmp") {
+                fs::remove_file(entry.path()).map_err(|e| StoreError::Io(e.to_string()))?;
+                report.removed_temp_files += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Move a journaled write that can't be completed to `.recovery/<id>.md`.
+    fn set_aside(&self, id: &str, content: &str) -> Result<PathBuf, StoreError> {
+        let dir = self.root.join(".recovery");
+        let path = dir.join(format!("{id}.md"));
+        fs::create_dir_all(&dir)
+            .and_then(|_| notes_core::write_atomic(&path, content))
+            .map_err(|e| StoreError::Io(e.to_string()))?;
+        self.conn
+            .execute(
+                "DELETE FROM pending_writes WHERE document_id=?1",
+                params![id],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(path)
+    }
+}
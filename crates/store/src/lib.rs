@@ -1,12 +1,40 @@
-use chrono::Utc;
-use notes_core::{Document, DocumentError, DocumentType, Frontmatter};
-use notes_oplog::{Operation, OperationType};
-use rusqlite::{params, Connection};
+use notes_core::{Document, DocumentType, Frontmatter, FrontmatterFormat};
+use notes_oplog::{CausalOrder, Operation, OperationType, VectorClock};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+mod applied;
+mod attachments;
+mod batch;
+mod conflicts;
+mod history;
+mod journal;
+mod links;
+mod merge;
+mod rebuild;
+mod reindex;
+mod tombstones;
+mod trash;
+mod watch;
+
+pub use applied::{AppliedOp, AppliedOutcome};
+pub use attachments::{blob_hash, Attachment};
+pub use batch::{BatchReport, OpOutcome, Outcome};
+pub use conflicts::{ConflictChoice, ConflictKind, ConflictRecord};
+pub use history::{diff_documents, DiffKind, DiffLine, DocumentVersion, FieldChange, VersionDiff};
+pub use journal::{RecoveryReport, UnparseableWrite};
+pub use links::{extract_links, Link, LinkKind};
+pub use merge::{merge_documents, merge_text, DocumentMerge, TextMerge};
+pub use rebuild::{DocumentDrift, DriftKind, RebuildReport, ReplayFailure};
+pub use reindex::{ReindexReport, UnparseableFile, INDEX_SCHEMA_VERSION};
+pub use tombstones::Tombstone;
+use tombstones::{delete_decision, tombstone_decision};
+pub use trash::TrashEntry;
+pub use watch::{document_id_from_path, VaultWatcher};
+
 #[derive(Debug, Error)]
 pub enum StoreError {
     #[error("conflict detected for document {0}")]
@@ -34,7 +62,8 @@ struct DocPayload {
 pub struct Store {
     root: PathBuf,
     conn: Connection,
-    seen_ops: std::collections::HashSet<String>,
+    /// Writes of the batch being applied, if any (see `batch.rs`).
+    staged: Option<journal::Staged>,
 }
 
 impl Default for Store {
@@ -42,7 +71,7 @@ impl Default for Store {
         Self::with_root("vault").unwrap_or_else(|_| Self {
             root: PathBuf::from("vault"),
             conn: Connection::open_in_memory().unwrap(),
-            seen_ops: std::collections::HashSet::new(),
+            staged: None,
         })
     }
 }
@@ -57,11 +86,14 @@ impl Store {
         let conn =
             Connection::open(root.join("index.db")).map_err(|e| StoreError::Db(e.to_string()))?;
         Self::init_db(&conn)?;
-        Ok(Self {
+        let store = Self {
             root,
             conn,
-            seen_ops: std::collections::HashSet::new(),
-        })
+            staged: None,
+        };
+        store.recover()?;
+        store.migrate_index()?;
+        Ok(store)
     }
 
     pub fn root_path(&self) -> PathBuf {
@@ -69,88 +101,217 @@ impl Store {
     }
 
     /// Apply an op to the vault (create/update/delete), enforcing before/after hashes and
-    /// writing conflicts when needed. Returns the written document for create/update.
+    /// writing conflicts when needed. Returns the written document for create/update, or
+    /// `None` when the op was already applied (even before a restart) or is causally superseded
+    /// by the local version.
     pub fn apply(&mut self, op: Operation) -> Result<Option<Document>, StoreError> {
+        self.apply_with_history(op, &[])
+    }
+
+    /// Like [`Store::apply`], but when an update conflicts with the local copy the common
+    /// ancestor is looked up in `history` (the op-log) and the two versions are three-way
+    /// merged. Only overlapping body hunks still produce a conflict copy (with diff3 markers).
+    pub fn apply_with_history(
+        &mut self,
+        op: Operation,
+        history: &[Operation],
+    ) -> Result<Option<Document>, StoreError> {
         let op_key = op.key();
-        if self.seen_ops.contains(&op_key) {
+        if self.is_applied(&op_key)? {
             return Ok(None);
         }
         match op.op_type {
             OperationType::CreateDocument | OperationType::UpdateDocument => {
-                // parse payload → document and ensure updated timestamps
-                let payload: DocPayload = serde_json::from_value(op.payload.clone())
-                    .map_err(|e| StoreError::Document(e.to_string()))?;
-
-                let mut doc = self.payload_to_document(&payload)?;
-                doc.frontmatter.updated = Utc::now().to_rfc3339();
-                let computed_hash = doc.hash_content();
-
-                // conflict detection
-                if let Some(current) = self.load_document(&op.document_id)? {
-                    if let Some(before_hash) = op.before_hash.as_ref() {
-                        if &current.hash_content() != before_hash {
-                            self.write_conflict(&op.document_id, &doc)?;
-                            return Err(StoreError::Conflict(op.document_id));
-                        }
-                    }
-                }
+                // parse payload → document; `updated` comes from the op so every replica
+                // materializes (and hashes) the same content
+                let doc = self.materialize(&op)?;
 
-                // ensure caller-supplied after_hash matches what we will write
+                // ensure caller-supplied after_hash matches what the op describes
                 if let Some(expected_after) = op.after_hash.as_ref() {
-                    if expected_after != &computed_hash {
+                    if !doc.matches_hash(expected_after) {
                         return Err(StoreError::HashMismatch(op.document_id));
                     }
                 }
 
-                self.write_document(&op.document_id, &doc)?;
-                self.upsert_index(&doc)?;
-                self.seen_ops.insert(op_key);
+                // conflict detection, against the local copy or, if it was deleted, its
+                // tombstone
+                let local_clock = self.document_clock(&op.document_id)?;
+                let mut doc = doc;
+                let current = self.load_document(&op.document_id)?;
+                if let Some(current) = &current {
+                    // keep writing the file in the frontmatter format it uses
+                    doc.format = current.format;
+                }
+                let decision = match &current {
+                    Some(current) => causal_decision(&op, current, &local_clock),
+                    None => match self.tombstone(&op.document_id)? {
+                        Some(tombstone) => tombstone_decision(&op, &tombstone),
+                        None => ApplyDecision::FastForward,
+                    },
+                };
+                match decision {
+                    ApplyDecision::FastForward => {}
+                    ApplyDecision::Stale => {
+                        self.mark_applied(&op, AppliedOutcome::Superseded)?;
+                        return Ok(None);
+                    }
+                    ApplyDecision::Conflict => {
+                        if self.known_conflict(&op)? {
+                            return Ok(None);
+                        }
+                        let merged = current.as_ref().and_then(|current| {
+                            self.find_ancestor(&op, history)
+                                .map(|base| merge_documents(&base, current, &doc))
+                        });
+                        match merged {
+                            Some(merged) if merged.conflicts == 0 => doc = merged.document,
+                            other => {
+                                let copy = other.map(|m| m.document).unwrap_or(doc);
+                                self.record_conflict(&op, current.as_ref(), Some(&copy))?;
+                                return Err(StoreError::Conflict(op.document_id));
+                            }
+                        }
+                    }
+                }
+
+                self.write_document(&op.document_id, Some(&doc), &local_clock.merged(&op.clock))?;
+                self.clear_tombstone(&op.document_id)?;
+                self.mark_applied(&op, AppliedOutcome::Applied)?;
                 Ok(Some(doc))
             }
             OperationType::DeleteDocument => {
-                let path = self.doc_path(&op.document_id);
-                let _ = fs::remove_file(path);
-                self.delete_index(&op.document_id)?;
-                self.seen_ops.insert(op_key);
+                let local_clock = self.document_clock(&op.document_id)?;
+                let current = self.load_document(&op.document_id)?;
+                if let Some(current) = &current {
+                    match delete_decision(&op, current, &local_clock) {
+                        ApplyDecision::FastForward => {}
+                        ApplyDecision::Stale => {
+                            self.mark_applied(&op, AppliedOutcome::Superseded)?;
+                            return Ok(None);
+                        }
+                        ApplyDecision::Conflict => {
+                            if self.known_conflict(&op)? {
+                                return Ok(None);
+                            }
+                            // the local edit stays until the conflict is resolved
+                            self.record_conflict(&op, Some(current), None)?;
+                            return Err(StoreError::Conflict(op.document_id));
+                        }
+                    }
+                }
+                // keep the clock so late updates the delete had already seen stay stale
+                let clock = local_clock.merged(&op.clock);
+                self.write_document(&op.document_id, None, &clock)?;
+                if let Some(current) = &current {
+                    self.record_trash(current, Some(&op.key()))?;
+                }
+                self.record_tombstone(
+                    &op.document_id,
+                    &op.key(),
+                    &clock,
+                    current.map(|d| d.hash_content()),
+                )?;
+                self.mark_applied(&op, AppliedOutcome::Applied)?;
+                Ok(None)
+            }
+            OperationType::AttachFile | OperationType::DetachFile => {
+                self.apply_attachment_op(&op)?;
+                self.mark_applied(&op, AppliedOutcome::Applied)?;
                 Ok(None)
             }
-            _ => Err(StoreError::Unsupported),
         }
     }
 
+    /// The document a create/update op describes, exactly as `apply` would write it.
+    pub(crate) fn materialize(&self, op: &Operation) -> Result<Document, StoreError> {
+        let payload: DocPayload = serde_json::from_value(op.payload.clone())
+            .map_err(|e| StoreError::Document(e.to_string()))?;
+        let mut doc = self.payload_to_document(&payload, &op.document_id, &op.timestamp)?;
+        doc.frontmatter.updated = op.timestamp.clone();
+        Ok(doc)
+    }
+
+    /// The version `op` was based on: the document produced by the op in `history` whose
+    /// content hash equals `op.before_hash`.
+    fn find_ancestor(&self, op: &Operation, history: &[Operation]) -> Option<Document> {
+        let before_hash = op.before_hash.as_ref()?;
+        history
+            .iter()
+            .rev()
+            .filter(|h| h.document_id == op.document_id)
+            .filter(|h| {
+                matches!(
+                    h.op_type,
+                    OperationType::CreateDocument | OperationType::UpdateDocument
+                )
+            })
+            .filter_map(|h| self.materialize(h).ok())
+            .find(|d| d.matches_hash(before_hash))
+    }
+
+    /// Current vector clock of a document (empty if it has never been written with one).
+    pub fn document_clock(&self, id: &str) -> Result<VectorClock, StoreError> {
+        let raw: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT clock FROM doc_clocks WHERE id=?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(raw
+            .and_then(|r| serde_json::from_str(&r).ok())
+            .unwrap_or_default())
+    }
+
+    /// Clock to attach to a new local op on `id` authored by `device_id`.
+    pub fn next_clock(&self, id: &str, device_id: &str) -> Result<VectorClock, StoreError> {
+        let mut clock = self.document_clock(id)?;
+        clock.increment(device_id);
+        Ok(clock)
+    }
+
+    fn set_document_clock(&self, id: &str, clock: &VectorClock) -> Result<(), StoreError> {
+        if clock.is_empty() {
+            return Ok(());
+        }
+        let raw = serde_json::to_string(clock).map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute(
+                "INSERT INTO doc_clocks(id, clock) VALUES(?1, ?2)
+                 ON CONFLICT(id) DO UPDATE SET clock=excluded.clock",
+                params![id, raw],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
     pub fn load_document(&self, id: &str) -> Result<Option<Document>, StoreError> {
+        if let Some(staged) = self.staged_document(id) {
+            return Ok(staged);
+        }
         let path = self.doc_path(id);
         if !path.exists() {
             return Ok(None);
         }
         let raw = fs::read_to_string(&path).map_err(|e| StoreError::Io(e.to_string()))?;
-        let doc = Document::from_markdown(&raw).map_err(|e| StoreError::Document(e.to_string()))?;
-        Ok(Some(doc))
-    }
-
-    fn write_document(&self, id: &str, doc: &Document) -> Result<(), StoreError> {
-        let path = self.doc_path(id);
-        let content = doc
-            .to_markdown()
+        let doc = Document::from_markdown_with_id(&raw, id)
             .map_err(|e| StoreError::Document(e.to_string()))?;
-        fs::write(path, content).map_err(|e| StoreError::Io(e.to_string()))
-    }
-
-    fn write_conflict(&self, id: &str, doc: &Document) -> Result<(), StoreError> {
-        let ts = Utc::now().format("%Y%m%d%H%M%S");
-        let filename = format!("{id}.conflict.{ts}.md");
-        let path = self.root.join(filename);
-        let content = doc
-            .to_markdown()
-            .map_err(|e| StoreError::Document(e.to_string()))?;
-        fs::write(path, content).map_err(|e| StoreError::Io(e.to_string()))
+        Ok(Some(doc))
     }
 
     fn doc_path(&self, id: &str) -> PathBuf {
         self.root.join(format!("{id}.md"))
     }
 
-    fn payload_to_document(&self, payload: &DocPayload) -> Result<Document, StoreError> {
+    /// A payload without an id gets `document_id`, so every replica materializes it alike.
+    fn payload_to_document(
+        &self,
+        payload: &DocPayload,
+        document_id: &str,
+        timestamp: &str,
+    ) -> Result<Document, StoreError> {
         #[derive(Deserialize)]
         struct PartialFrontmatter {
             id: Option<String>,
@@ -163,12 +324,13 @@ impl Store {
             tags: Vec<String>,
             #[serde(default)]
             links: Vec<String>,
+            #[serde(flatten)]
+            extra: serde_yaml::Mapping,
         }
 
         let partial: PartialFrontmatter = serde_yaml::from_value(payload.frontmatter.clone())
-            .map_err(|_| StoreError::Document(DocumentError::Frontmatter.to_string()))?;
+            .map_err(|e| StoreError::Document(e.to_string()))?;
 
-        let now = Utc::now().to_rfc3339();
         let doc_type = match partial.doc_type.as_deref() {
             Some("note") | None => DocumentType::Note,
             Some("source") => DocumentType::Source,
@@ -180,13 +342,20 @@ impl Store {
         };
 
         let mut frontmatter = Frontmatter {
-            id: partial.id.unwrap_or_else(notes_core::generate_id),
+            id: partial.id.unwrap_or_else(|| document_id.to_string()),
             doc_type,
             title: partial.title,
-            created: partial.created.unwrap_or_else(|| now.clone()),
-            updated: partial.updated.unwrap_or_else(|| now.clone()),
+            created: partial
+                .created
+                .filter(|c| !c.is_empty())
+                .unwrap_or_else(|| timestamp.to_string()),
+            updated: partial
+                .updated
+                .filter(|u| !u.is_empty())
+                .unwrap_or_else(|| timestamp.to_string()),
             tags: partial.tags,
             links: partial.links,
+            extra: partial.extra,
         };
 
         if frontmatter
@@ -204,6 +373,7 @@ impl Store {
         Ok(Document {
             frontmatter,
             body: payload.body.clone(),
+            format: FrontmatterFormat::default(),
         })
     }
 
@@ -225,7 +395,7 @@ impl Store {
             .prepare("PRAGMA table_info(documents);")
             .map_err(|e| StoreError::Db(e.to_string()))?;
         let rows = stmt
-            .query_map([], |row| Ok(row.get::<_, String>(1)?))
+            .query_map([], |row| row.get::<_, String>(1))
             .map_err(|e| StoreError::Db(e.to_string()))?;
         for r in rows {
             cols.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
@@ -238,33 +408,86 @@ impl Store {
             conn.execute("ALTER TABLE documents ADD COLUMN tags TEXT;", [])
                 .ok();
         }
-        Ok(())
+        // content hash of the last version the store wrote (see `watch.rs`)
+        if !cols.contains(&"hash".to_string()) {
+            conn.execute("ALTER TABLE documents ADD COLUMN hash TEXT;", [])
+                .ok();
+        }
+
+        // full-text index over title/tags/body; id is stored but not tokenized
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS documents_fts USING fts5(
+                id UNINDEXED,
+                title,
+                tags,
+                body,
+                tokenize = 'unicode61 remove_diacritics 2',
+                prefix = '2 3'
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS doc_clocks(
+                id TEXT PRIMARY KEY,
+                clock TEXT NOT NULL
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))?;
+        Self::init_links_table(conn)?;
+        Self::init_conflicts_table(conn)?;
+        Self::init_journal_table(conn)?;
+        Self::init_applied_table(conn)?;
+        Self::init_tombstones_table(conn)?;
+        Self::init_trash_table(conn)?;
+        Self::init_attachments_table(conn)
     }
 
     fn upsert_index(&self, doc: &Document) -> Result<(), StoreError> {
         self.conn
             .execute(
-                "INSERT INTO documents(id, doc_type, updated, title, tags) VALUES(?1, ?2, ?3, ?4, ?5)
-                 ON CONFLICT(id) DO UPDATE SET doc_type=excluded.doc_type, updated=excluded.updated, title=excluded.title, tags=excluded.tags;",
+                "INSERT INTO documents(id, doc_type, updated, title, tags, hash) VALUES(?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(id) DO UPDATE SET doc_type=excluded.doc_type, updated=excluded.updated, title=excluded.title, tags=excluded.tags, hash=excluded.hash;",
                 params![
                     doc.frontmatter.id,
                     format!("{:?}", doc.frontmatter.doc_type).to_lowercase(),
                     doc.frontmatter.updated,
                     doc.frontmatter.title.clone().unwrap_or_default(),
-                    serde_json::to_string(&doc.frontmatter.tags).unwrap_or_else(|_| "[]".into())
+                    serde_json::to_string(&doc.frontmatter.tags).unwrap_or_else(|_| "[]".into()),
+                    doc.hash_content()
                 ],
             )
             .map_err(|e| StoreError::Db(e.to_string()))?;
-        Ok(())
+        self.upsert_fts(doc)?;
+        self.upsert_links(doc)
     }
 
-    fn delete_index(&self, id: &str) -> Result<(), StoreError> {
+    fn upsert_fts(&self, doc: &Document) -> Result<(), StoreError> {
         self.conn
-            .execute("DELETE FROM documents WHERE id=?1", params![id])
+            .execute(
+                "DELETE FROM documents_fts WHERE id=?1",
+                params![doc.frontmatter.id],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute(
+                "INSERT INTO documents_fts(id, title, tags, body) VALUES(?1, ?2, ?3, ?4)",
+                params![
+                    doc.frontmatter.id,
+                    doc.frontmatter.title.clone().unwrap_or_default(),
+                    doc.frontmatter.tags.join(" "),
+                    doc.body
+                ],
+            )
             .map_err(|e| StoreError::Db(e.to_string()))?;
         Ok(())
     }
 
+    fn delete_index(&self, id: &str) -> Result<(), StoreError> {
+        self.delete_document_rows(id)?;
+        self.delete_attachments(id)
+    }
+
     pub fn list_documents(&self) -> Result<Vec<DocumentSummary>, StoreError> {
         let mut stmt = self
             .conn
@@ -293,7 +516,15 @@ impl Store {
         Ok(docs)
     }
 
+    /// Document summaries matching `query`, without scores or snippets: first the full-text
+    /// hits on title, tags and body in [`Store::search`] order, then any other documents whose
+    /// title, tags or id contain `query` verbatim (newest first), so a partial id still works.
     pub fn search_documents(&self, query: &str) -> Result<Vec<DocumentSummary>, StoreError> {
+        let mut docs: Vec<DocumentSummary> = self
+            .search(query)?
+            .into_iter()
+            .map(|hit| hit.summary)
+            .collect();
         let like = format!("%{}%", query);
         let mut stmt = self
             .conn
@@ -312,56 +543,122 @@ impl Store {
                 })
             })
             .map_err(|e| StoreError::Db(e.to_string()))?;
-        let mut docs = Vec::new();
         for r in rows {
-            docs.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
+            let summary = r.map_err(|e| StoreError::Db(e.to_string()))?;
+            if !docs.iter().any(|d| d.id == summary.id) {
+                docs.push(summary);
+            }
         }
         Ok(docs)
     }
 
+    /// Ranked full-text search over title, tags and body (bm25, title weighted highest).
+    /// Supports `"quoted phrases"` and `prefix*` terms; every term must match.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>, StoreError> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT d.id, d.doc_type, d.updated, d.title, d.tags,
+                        bm25(documents_fts, 0.0, 10.0, 5.0, 1.0) AS score,
+                        snippet(documents_fts, 3, ?2, ?3, '…', 12)
+                 FROM documents_fts
+                 JOIN documents d ON d.id = documents_fts.id
+                 WHERE documents_fts MATCH ?1
+                 ORDER BY score
+                 LIMIT 200",
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map(
+                params![fts_query, SNIPPET_OPEN, SNIPPET_CLOSE],
+                |row| {
+                    let tags_json: String = row.get(4)?;
+                    let tags: Vec<String> = serde_json::from_str(&tags_json).unwrap_or_default();
+                    let score: f64 = row.get(5)?;
+                    Ok(SearchResult {
+                        summary: DocumentSummary {
+                            id: row.get(0)?,
+                            doc_type: row.get(1)?,
+                            updated: row.get(2)?,
+                            title: row.get(3).ok(),
+                            tags,
+                        },
+                        // bm25 is "lower is better"; flip it so callers can sort descending
+                        score: -score,
+                        snippet: row.get(6)?,
+                    })
+                },
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut hits = Vec::new();
+        for r in rows {
+            hits.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
+        }
+        Ok(hits)
+    }
+
+    /// Move a document to the trash without an op (deletes that should sync go through
+    /// `apply`). The tombstone it leaves has no clock, so a remote edit arriving afterwards
+    /// is a conflict instead of silently re-creating the document.
     pub fn delete_document(&self, id: &str) -> Result<(), StoreError> {
-        let path = self.doc_path(id);
-        let _ = fs::remove_file(path);
-        self.delete_index(id)
+        let current = self.load_document(id)?;
+        self.write_durably(id, None, &VectorClock::default())?;
+        match current {
+            Some(doc) => {
+                self.record_tombstone(id, "", &VectorClock::default(), Some(doc.hash_content()))?;
+                self.record_trash(&doc, None)
+            }
+            None => Ok(()),
+        }
     }
 
     /// Update a document with conflict/hash checks.
     pub fn update_document(&mut self, op: Operation) -> Result<Document, StoreError> {
         match op.op_type {
             OperationType::UpdateDocument => {
-                let payload: DocPayload = serde_json::from_value(op.payload.clone())
-                    .map_err(|e| StoreError::Document(e.to_string()))?;
-                let mut doc = self.payload_to_document(&payload)?;
-                doc.frontmatter.updated = Utc::now().to_rfc3339();
-                let computed_hash = doc.hash_content();
-
-                if let Some(current) = self.load_document(&op.document_id)? {
-                    if let Some(before_hash) = op.before_hash.as_ref() {
-                        if &current.hash_content() != before_hash {
-                            self.write_conflict(&op.document_id, &doc)?;
-                            return Err(StoreError::Conflict(op.document_id));
-                        }
-                    }
-                } else {
+                let id = op.document_id.clone();
+                if !self.doc_path(&id).exists() {
                     return Err(StoreError::NotFound);
                 }
-
-                if let Some(expected_after) = op.after_hash.as_ref() {
-                    if expected_after != &computed_hash {
-                        return Err(StoreError::HashMismatch(op.document_id));
-                    }
+                match self.apply(op)? {
+                    Some(doc) => Ok(doc),
+                    // already applied or superseded: report what is on disk
+                    None => self.load_document(&id)?.ok_or(StoreError::NotFound),
                 }
-
-                self.write_document(&op.document_id, &doc)?;
-                self.upsert_index(&doc)?;
-                self.seen_ops.insert(op.key());
-                Ok(doc)
             }
             _ => Err(StoreError::Unsupported),
         }
     }
 }
 
+enum ApplyDecision {
+    FastForward,
+    Stale,
+    Conflict,
+}
+
+/// Decide how an incoming op relates to the local copy. Clocks decide when both
+/// sides have one; concurrent edits (or legacy ops without clocks) fall back to `before_hash`,
+/// so a concurrent op based on exactly the local content still applies cleanly. A concurrent
+/// op with no `before_hash` can't show that, so it conflicts.
+fn causal_decision(op: &Operation, current: &Document, local: &VectorClock) -> ApplyDecision {
+    if !op.clock.is_empty() && !local.is_empty() {
+        match op.clock.compare(local) {
+            CausalOrder::Before | CausalOrder::Equal => return ApplyDecision::Stale,
+            CausalOrder::After => return ApplyDecision::FastForward,
+            CausalOrder::Concurrent if op.before_hash.is_none() => return ApplyDecision::Conflict,
+            CausalOrder::Concurrent => {}
+        }
+    }
+    match op.before_hash.as_ref() {
+        Some(before_hash) if !current.matches_hash(before_hash) => ApplyDecision::Conflict,
+        _ => ApplyDecision::FastForward,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DocumentSummary {
     pub id: String,
@@ -382,6 +679,73 @@ impl DocumentSummary {
     }
 }
 
+/// Markers wrapped around matched terms in [`SearchResult::snippet`].
+pub const SNIPPET_OPEN: &str = "<mark>";
+pub const SNIPPET_CLOSE: &str = "</mark>";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub summary: DocumentSummary,
+    /// Relevance score; higher is better.
+    pub score: f64,
+    /// Excerpt of the body with matches wrapped in [`SNIPPET_OPEN`]/[`SNIPPET_CLOSE`].
+    pub snippet: String,
+}
+
+/// Translate user input into a safe FTS5 expression: quoted phrases stay phrases, a trailing
+/// `*` makes a prefix query, everything else is quoted so FTS syntax characters can't leak in.
+fn fts_query(input: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut text = String::new();
+        if c == '"' {
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                text.push(c);
+            }
+        } else {
+            text.push(c);
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+        }
+        let mut prefix = false;
+        if text.ends_with('*') {
+            text = text.trim_end_matches('*').to_string();
+            prefix = true;
+        } else if chars.peek() == Some(&'*') {
+            chars.next();
+            prefix = true;
+        }
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_string())
+            .collect();
+        if words.is_empty() {
+            continue;
+        }
+        let phrase = format!("\"{}\"", words.join(" "));
+        terms.push(if prefix { format!("{phrase}*") } else { phrase });
+    }
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
 fn derive_title(body: &str) -> Option<String> {
     for line in body.lines() {
         let trimmed = line.trim_start_matches('#').trim();
@@ -395,6 +759,7 @@ fn derive_title(body: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::Utc;
     use tempfile::tempdir;
 
     fn make_payload(id: Option<String>, body: &str) -> serde_json::Value {
@@ -419,6 +784,38 @@ mod tests {
         })
     }
 
+    /// An op on `doc` whose clock has `n` ticks from each `(device, n)`; the first device
+    /// authors it.
+    fn op_with_clock(
+        op_id: &str,
+        op_type: OperationType,
+        doc: &str,
+        body: &str,
+        clock: &[(&str, u64)],
+    ) -> Operation {
+        let mut vc = VectorClock::new();
+        for (device, n) in clock {
+            for _ in 0..*n {
+                vc.increment(device);
+            }
+        }
+        let payload = match op_type {
+            OperationType::DeleteDocument => serde_json::Value::Null,
+            _ => make_payload(Some(doc.into()), body),
+        };
+        Operation {
+            op_id: op_id.into(),
+            device_id: clock[0].0.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type,
+            document_id: doc.into(),
+            payload,
+            before_hash: None,
+            after_hash: None,
+            clock: vc,
+        }
+    }
+
     #[test]
     fn create_and_list() {
         let dir = tempdir().unwrap();
@@ -432,6 +829,7 @@ mod tests {
             payload: make_payload(Some("doc1".into()), "hello"),
             before_hash: None,
             after_hash: None,
+            clock: VectorClock::new(),
         };
         let doc = store.apply(op).unwrap().unwrap();
         assert_eq!(doc.frontmatter.id, "doc1");
@@ -454,6 +852,7 @@ mod tests {
             payload: make_payload(Some("doc1".into()), "first"),
             before_hash: None,
             after_hash: None,
+            clock: VectorClock::new(),
         };
         store.apply(create_op).unwrap();
 
@@ -466,8 +865,1121 @@ mod tests {
             payload: make_payload(Some("doc1".into()), "second"),
             before_hash: Some("mismatch".into()),
             after_hash: None,
+            clock: VectorClock::new(),
         };
         let err = store.update_document(update_op).unwrap_err();
         assert!(matches!(err, StoreError::Conflict(_)));
     }
+
+    #[test]
+    fn full_text_search_over_body() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        for (id, body) in [
+            ("doc1", "Quarterly planning meeting with the design team"),
+            ("doc2", "Grocery list: apples, pears, planning snacks"),
+        ] {
+            store
+                .apply(Operation {
+                    op_id: format!("op-{id}"),
+                    device_id: "dev".into(),
+                    timestamp: Utc::now().to_rfc3339(),
+                    op_type: OperationType::CreateDocument,
+                    document_id: id.into(),
+                    payload: make_payload(Some(id.into()), body),
+                    before_hash: None,
+                    after_hash: None,
+                    clock: VectorClock::new(),
+                })
+                .unwrap();
+        }
+
+        let hits = store.search("\"design team\"").unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].summary.id, "doc1");
+        assert!(hits[0].snippet.contains("<mark>design"));
+
+        let hits = store.search("plan*").unwrap();
+        assert_eq!(hits.len(), 2);
+
+        let summaries = store.search_documents("apples").unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, "doc2");
+
+        store.delete_document("doc2").unwrap();
+        assert!(store.search("apples").unwrap().is_empty());
+    }
+
+    #[test]
+    fn fts_query_escapes_syntax() {
+        assert_eq!(fts_query("foo bar").as_deref(), Some("\"foo\" \"bar\""));
+        assert_eq!(fts_query("\"a b\"* c*").as_deref(), Some("\"a b\"* \"c\"*"));
+        assert_eq!(fts_query("NOT OR(").as_deref(), Some("\"NOT\" \"OR\""));
+        assert_eq!(fts_query("  -- "), None);
+    }
+
+    #[test]
+    fn backlinks_and_unresolved_links() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let create = |id: &str, body: &str| Operation {
+            op_id: format!("op-{id}"),
+            device_id: "dev".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::CreateDocument,
+            document_id: id.into(),
+            payload: make_payload(Some(id.into()), body),
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        store
+            .apply(create("a", "links to [[b]] and [[Missing Note]]"))
+            .unwrap();
+        store.apply(create("c", "also see [b](b.md)")).unwrap();
+
+        let unresolved = store.unresolved_links().unwrap();
+        assert_eq!(unresolved.len(), 3);
+        assert!(store.backlinks("b").unwrap().is_empty());
+
+        store.apply(create("b", "target")).unwrap();
+        let backlinks: Vec<String> = store
+            .backlinks("b")
+            .unwrap()
+            .into_iter()
+            .map(|d| d.id)
+            .collect();
+        assert_eq!(backlinks.len(), 2);
+        assert!(backlinks.contains(&"a".to_string()));
+        assert!(backlinks.contains(&"c".to_string()));
+
+        let outgoing = store.outgoing_links("a").unwrap();
+        assert_eq!(outgoing[0].resolved_id.as_deref(), Some("b"));
+        assert_eq!(outgoing[1].resolved_id, None);
+        assert_eq!(store.unresolved_links().unwrap().len(), 1);
+
+        store.delete_document("a").unwrap();
+        assert_eq!(store.backlinks("b").unwrap().len(), 1);
+        assert!(store.unresolved_links().unwrap().is_empty());
+    }
+
+    #[test]
+    fn vector_clocks_decide_fast_forward_stale_and_conflict() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let op = |op_id: &str, body: &str, clock: &[(&str, u64)]| Operation {
+            // deliberately stale: clocks take precedence over hashes
+            before_hash: Some("stale".into()),
+            ..op_with_clock(op_id, OperationType::UpdateDocument, "doc1", body, clock)
+        };
+
+        store.apply(op("op1", "v1", &[("laptop", 1)])).unwrap();
+        assert_eq!(store.next_clock("doc1", "desktop").unwrap().get("desktop"), 1);
+
+        // desktop saw v1 and edited: fast-forward despite the before_hash
+        let doc = store
+            .apply(op("op2", "v2", &[("desktop", 1), ("laptop", 1)]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.body, "v2");
+
+        // a re-delivered older edit is stale, not a conflict
+        let res = store.apply(op("op1b", "v1", &[("laptop", 1)])).unwrap();
+        assert!(res.is_none());
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().body, "v2");
+
+        // laptop edited v1 without seeing v2: concurrent
+        let err = store.apply(op("op3", "v3", &[("laptop", 2)])).unwrap_err();
+        assert!(matches!(err, StoreError::Conflict(_)));
+
+        // a concurrent op without a before_hash can't show what it was based on
+        let err = store
+            .apply(Operation {
+                before_hash: None,
+                ..op("op4", "v4", &[("phone", 1)])
+            })
+            .unwrap_err();
+        assert!(matches!(err, StoreError::Conflict(_)));
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().body, "v2");
+
+        // both conflicts got their own copy, though they happened within a second
+        let copies: std::collections::HashSet<String> = store
+            .list_conflicts()
+            .unwrap()
+            .into_iter()
+            .map(|c| c.copy_path)
+            .collect();
+        assert_eq!(copies.len(), 2);
+        assert!(copies.iter().all(|c| dir.path().join(c).exists()));
+    }
+
+    #[test]
+    fn concurrent_updates_three_way_merge() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let op = |op_id: &str, device: &str, body: &str, before: Option<String>| Operation {
+            op_id: op_id.into(),
+            device_id: device.into(),
+            timestamp: format!("2025-01-01T00:00:0{}Z", &op_id[2..]),
+            op_type: OperationType::UpdateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), body),
+            before_hash: before,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        let create = op("op1", "laptop", "title\nmiddle\nend\n", None);
+        let base = store.apply(create.clone()).unwrap().unwrap();
+        let history = vec![create];
+
+        let local = op(
+            "op2",
+            "laptop",
+            "title!\nmiddle\nend\n",
+            Some(base.hash_content()),
+        );
+        store.apply(local).unwrap();
+
+        let remote = op(
+            "op3",
+            "desktop",
+            "title\nmiddle\nend\nmore\n",
+            Some(base.hash_content()),
+        );
+        let merged = store.apply_with_history(remote, &history).unwrap().unwrap();
+        assert_eq!(merged.body, "title!\nmiddle\nend\nmore\n");
+
+        let overlapping = op(
+            "op4",
+            "desktop",
+            "title?\nmiddle\nend\n",
+            Some(base.hash_content()),
+        );
+        let err = store.apply_with_history(overlapping, &history).unwrap_err();
+        assert!(matches!(err, StoreError::Conflict(_)));
+        let conflict_copy = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().contains(".conflict."))
+            .unwrap();
+        let raw = fs::read_to_string(conflict_copy.path()).unwrap();
+        assert!(raw.contains("<<<<<<< local\ntitle!\n"));
+        assert!(raw.contains(">>>>>>> remote\n"));
+    }
+
+    #[test]
+    fn conflicts_are_registered_and_resolved() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let op = |op_id: &str, device: &str, body: &str, before: Option<String>| Operation {
+            op_id: op_id.into(),
+            device_id: device.into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::UpdateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), body),
+            before_hash: before,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        store.apply(op("op1", "laptop", "local", None)).unwrap();
+        let remote = op("op2", "desktop", "remote", Some("other".into()));
+        assert!(store.apply(remote.clone()).is_err());
+        // re-delivery doesn't register a second conflict
+        assert!(store.apply(remote.clone()).is_err());
+
+        let conflicts = store.list_conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].document_id, "doc1");
+        assert_eq!(conflicts[0].remote_op_key, "desktop:op2");
+        assert!(dir.path().join(&conflicts[0].copy_path).exists());
+
+        let (doc, resolution) = store
+            .resolve_conflict(conflicts[0].id, ConflictChoice::KeepRemote, "laptop")
+            .unwrap();
+        let doc = doc.unwrap();
+        assert_eq!(doc.body, "remote");
+        assert!(matches!(resolution.op_type, OperationType::UpdateDocument));
+        assert_eq!(resolution.after_hash, Some(doc.hash_content()));
+        assert!(store.list_conflicts().unwrap().is_empty());
+        assert!(!dir.path().join(&conflicts[0].copy_path).exists());
+
+        // once resolved, the same remote op is treated as already handled
+        assert!(store.apply(remote).unwrap().is_none());
+    }
+
+    #[test]
+    fn history_materializes_diffs_and_restores_versions() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut log = Vec::new();
+        for (i, body) in ["one\n", "one\ntwo\n"].into_iter().enumerate() {
+            let mut op = Operation {
+                op_id: format!("op{i}"),
+                device_id: "dev".into(),
+                timestamp: format!("2025-01-01T00:00:0{i}Z"),
+                op_type: if i == 0 {
+                    OperationType::CreateDocument
+                } else {
+                    OperationType::UpdateDocument
+                },
+                document_id: "doc1".into(),
+                payload: make_payload(Some("doc1".into()), body),
+                before_hash: None,
+                after_hash: None,
+                clock: store.next_clock("doc1", "dev").unwrap(),
+            };
+            let doc = store.apply(op.clone()).unwrap().unwrap();
+            op.after_hash = Some(doc.hash_content());
+            log.push(op);
+        }
+
+        let history = store.document_history("doc1", &log);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].op_key, "dev:op0");
+        assert_eq!(history[0].hash, log[0].after_hash);
+
+        let diff = store
+            .diff_versions("doc1", &log, "dev:op0", "dev:op1")
+            .unwrap();
+        assert!(!diff.frontmatter.iter().any(|c| c.field == "title"));
+        assert_eq!(diff.body.last().unwrap().kind, DiffKind::Added);
+        assert_eq!(diff.body.last().unwrap().text, "two");
+
+        let (restored, op) = store
+            .restore_version("doc1", &log, "dev:op0", "dev")
+            .unwrap();
+        assert_eq!(restored.body, "one\n");
+        assert!(matches!(op.op_type, OperationType::UpdateDocument));
+        assert_eq!(op.clock.get("dev"), 3);
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().body, "one\n");
+    }
+
+    #[test]
+    fn attachments_are_content_addressed_and_linked() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        store
+            .apply(Operation {
+                op_id: "op1".into(),
+                device_id: "dev".into(),
+                timestamp: Utc::now().to_rfc3339(),
+                op_type: OperationType::CreateDocument,
+                document_id: "doc1".into(),
+                payload: make_payload(Some("doc1".into()), "see attached"),
+                before_hash: None,
+                after_hash: None,
+                clock: VectorClock::new(),
+            })
+            .unwrap();
+
+        let (attachment, op) = store
+            .attach_file("doc1", "shot.png", b"png bytes", "dev")
+            .unwrap();
+        assert_eq!(attachment.hash, blob_hash(b"png bytes"));
+        assert!(attachment.available);
+        assert!(dir
+            .path()
+            .join("attachments")
+            .join(&attachment.hash)
+            .exists());
+        assert_eq!(op.payload["hash"], attachment.hash.as_str());
+        assert_eq!((op.before_hash.as_deref(), op.after_hash.as_deref()), (None, None));
+        assert_eq!(store.read_blob(&attachment.hash).unwrap(), b"png bytes");
+
+        // a replica that receives the op before the blob lists it as unavailable
+        let other_dir = tempdir().unwrap();
+        let mut other = Store::with_root(other_dir.path()).unwrap();
+        other.apply(op).unwrap();
+        let listed = other.list_attachments("doc1").unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(!listed[0].available);
+
+        let detach = store.detach_file("doc1", &attachment.hash, "dev").unwrap();
+        assert!(matches!(detach.op_type, OperationType::DetachFile));
+        assert_eq!(detach.payload["hash"], attachment.hash.as_str());
+        assert!(detach.before_hash.is_none() && detach.after_hash.is_none());
+        assert!(store.list_attachments("doc1").unwrap().is_empty());
+        assert!(store.has_blob(&attachment.hash));
+        assert!(store.attach_file("missing", "x", b"x", "dev").is_err());
+    }
+
+    #[test]
+    fn compacted_log_rebuilds_the_same_vault() {
+        use notes_oplog::OpLog;
+        let dir = tempdir().unwrap();
+        let op = |op_id: &str, doc: &str, day: &str, op_type, clock: &[(&str, u64)]| Operation {
+            timestamp: format!("2025-{day}T00:00:00Z"),
+            ..op_with_clock(op_id, op_type, doc, &format!("{doc} as of {op_id}"), clock)
+        };
+        use OperationType::*;
+        let ops = vec![
+            op("op1", "doc1", "01-01", CreateDocument, &[("laptop", 1)]),
+            op("op2", "doc2", "01-02", CreateDocument, &[("laptop", 1)]),
+            op("op3", "doc1", "01-03", UpdateDocument, &[("laptop", 2)]),
+            op(
+                "op4",
+                "doc1",
+                "01-04",
+                UpdateDocument,
+                &[("laptop", 2), ("desktop", 1)],
+            ),
+            op("op5", "doc2", "01-05", DeleteDocument, &[("laptop", 2)]),
+            op("op6", "doc3", "01-06", CreateDocument, &[("laptop", 1)]),
+            op(
+                "op7",
+                "doc1",
+                "06-01",
+                UpdateDocument,
+                &[("laptop", 3), ("desktop", 1)],
+            ),
+            op("op8", "doc3", "06-02", UpdateDocument, &[("laptop", 2)]),
+        ];
+
+        let mut log = OpLog::open(dir.path().join("oplog.jsonl")).unwrap();
+        log.merge(&ops).unwrap();
+        let report = log.compact("2025-03-01").unwrap();
+        assert_eq!(report.removed, 3);
+        // doc2's delete survives as a tombstone
+        assert!(log
+            .document_ops("doc2")
+            .iter()
+            .any(|op| matches!(op.op_type, DeleteDocument)));
+
+        let (full, failed) = Store::replay(dir.path().join("full"), &ops).unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(full.list_documents().unwrap().len(), 2);
+        let report = full
+            .rebuild_from_ops(log.entries(), dir.path().join("compacted"))
+            .unwrap();
+        assert!(report.is_clean(), "{report:?}");
+    }
+
+    #[test]
+    fn rebuild_reports_drift_per_document() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path().join("vault")).unwrap();
+        let mut ops = Vec::new();
+        for (n, id) in ["doc1", "doc2", "doc3"].iter().enumerate() {
+            let mut clock = VectorClock::new();
+            clock.increment("dev");
+            let op = Operation {
+                op_id: format!("op{n}"),
+                device_id: "dev".into(),
+                timestamp: format!("2025-01-0{}T00:00:00Z", n + 1),
+                op_type: OperationType::CreateDocument,
+                document_id: id.to_string(),
+                payload: make_payload(Some(id.to_string()), "body"),
+                before_hash: None,
+                after_hash: None,
+                clock,
+            };
+            store.apply(op.clone()).unwrap();
+            ops.push(op);
+        }
+        // replaying out of order still rebuilds the same vault
+        ops.reverse();
+        let report = store
+            .rebuild_from_ops(&ops, dir.path().join("clean"))
+            .unwrap();
+        assert!(report.is_clean(), "{report:?}");
+        assert_eq!(report.replayed, 3);
+
+        fs::remove_file(dir.path().join("vault/doc1.md")).unwrap();
+        let edited = fs::read_to_string(dir.path().join("vault/doc2.md"))
+            .unwrap()
+            .replace("body", "tampered");
+        fs::write(dir.path().join("vault/doc2.md"), edited).unwrap();
+        fs::write(
+            dir.path().join("vault/stray.md"),
+            "---\nid: stray\ntype: note\n---\nstray",
+        )
+        .unwrap();
+
+        let report = store
+            .rebuild_from_ops(&ops, dir.path().join("drifted"))
+            .unwrap();
+        let drift: Vec<(&str, DriftKind)> = report
+            .drift
+            .iter()
+            .map(|d| (d.document_id.as_str(), d.kind))
+            .collect();
+        assert_eq!(
+            drift,
+            vec![
+                ("doc1", DriftKind::Missing),
+                ("doc2", DriftKind::HashDifferent),
+                ("stray", DriftKind::Extra),
+            ]
+        );
+        assert!(store
+            .rebuild_from_ops(&ops, dir.path().join("vault"))
+            .is_err());
+    }
+
+    #[test]
+    fn reindex_reconciles_index_with_files() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        for id in ["doc1", "doc2"] {
+            store
+                .apply(Operation {
+                    op_id: format!("op-{id}"),
+                    device_id: "dev".into(),
+                    timestamp: Utc::now().to_rfc3339(),
+                    op_type: OperationType::CreateDocument,
+                    document_id: id.into(),
+                    payload: make_payload(Some(id.into()), "hello"),
+                    before_hash: None,
+                    after_hash: None,
+                    clock: VectorClock::new(),
+                })
+                .unwrap();
+        }
+
+        // another tool removes doc1, drops in two notes and a broken file
+        fs::remove_file(dir.path().join("doc1.md")).unwrap();
+        fs::write(
+            dir.path().join("ext.md"),
+            "---\nid: ext\ntype: note\ntitle: From git\ncreated: 2025-01-01T00:00:00Z\n\
+             updated: 2025-01-01T00:00:00Z\n---\n\nsynced in",
+        )
+        .unwrap();
+        fs::write(dir.path().join("plain.md"), "no frontmatter here").unwrap();
+        fs::write(dir.path().join("bad.md"), "---\ntags: [unclosed\n---\n").unwrap();
+
+        let report = store.reindex().unwrap();
+        assert_eq!(report.indexed, 3);
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.unparseable.len(), 1);
+        assert!(report.unparseable[0].path.ends_with("bad.md"));
+        let mut ids: Vec<String> = store
+            .list_documents()
+            .unwrap()
+            .into_iter()
+            .map(|d| d.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["doc2", "ext", "plain"]);
+        assert_eq!(store.search("synced").unwrap().len(), 1);
+
+        // an index from another schema version is rebuilt on open
+        store
+            .conn
+            .execute_batch("DELETE FROM documents; PRAGMA user_version = 0;")
+            .unwrap();
+        drop(store);
+        let store = Store::with_root(dir.path()).unwrap();
+        assert_eq!(store.list_documents().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn external_edits_become_ops() {
+        let dir = tempdir().unwrap();
+        let vault = dir.path().join("vault");
+        let mut store = Store::with_root(&vault).unwrap();
+        let mut clock = VectorClock::new();
+        clock.increment("dev");
+        let create = Operation {
+            op_id: "op1".into(),
+            device_id: "dev".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::CreateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), "original"),
+            before_hash: None,
+            after_hash: None,
+            clock,
+        };
+        let original = store.apply(create.clone()).unwrap().unwrap();
+        // the store's own write is not an external change
+        assert!(store
+            .ingest_external_change("doc1", "dev")
+            .unwrap()
+            .is_none());
+
+        let path = vault.join("doc1.md");
+        let edited = fs::read_to_string(&path)
+            .unwrap()
+            .replace("original", "edited in vim");
+        fs::write(&path, edited).unwrap();
+        let update = store
+            .ingest_external_change("doc1", "dev")
+            .unwrap()
+            .unwrap();
+        assert!(matches!(update.op_type, OperationType::UpdateDocument));
+        assert_eq!(update.before_hash, Some(original.hash_content()));
+        let doc = store.load_document("doc1").unwrap().unwrap();
+        assert_eq!(doc.body, "edited in vim");
+        assert_eq!(update.after_hash, Some(doc.hash_content()));
+        assert!(store
+            .ingest_external_change("doc1", "dev")
+            .unwrap()
+            .is_none());
+
+        fs::write(
+            vault.join("new.md"),
+            "---\nid: new\ntype: note\ncreated: 2025-01-01T00:00:00Z\n\
+             updated: 2025-01-01T00:00:00Z\n---\n\nfrom vscode",
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        let ops = store.scan_external_changes("dev").unwrap();
+        let kinds: Vec<(&str, bool)> = ops
+            .iter()
+            .map(|op| {
+                let deleted = matches!(op.op_type, OperationType::DeleteDocument);
+                (op.document_id.as_str(), deleted)
+            })
+            .collect();
+        assert_eq!(kinds, vec![("doc1", true), ("new", false)]);
+        assert!(store.scan_external_changes("dev").unwrap().is_empty());
+
+        // peers replaying the ops end up with the same vault
+        let mut all = vec![create, update];
+        all.extend(ops);
+        let report = store
+            .rebuild_from_ops(&all, dir.path().join("peer"))
+            .unwrap();
+        assert!(report.is_clean(), "{report:?}");
+    }
+
+    #[test]
+    fn vault_watcher_reports_changed_documents() {
+        let dir = tempdir().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _watcher = VaultWatcher::start(
+            dir.path(),
+            std::time::Duration::from_millis(100),
+            move |ids| {
+                let _ = tx.send(ids);
+            },
+        )
+        .unwrap();
+        fs::write(dir.path().join("w1.md"), "x").unwrap();
+        fs::write(dir.path().join("w1.md"), "xy").unwrap();
+        fs::write(dir.path().join("notes.txt"), "x").unwrap();
+        fs::write(dir.path().join("w1.conflict.1.md"), "x").unwrap();
+        let ids = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(ids.into_iter().collect::<Vec<_>>(), vec!["w1"]);
+    }
+
+    #[test]
+    fn reopening_completes_interrupted_writes() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        for id in ["doc1", "doc2"] {
+            let mut clock = VectorClock::new();
+            clock.increment("dev");
+            store
+                .apply(Operation {
+                    op_id: format!("create-{id}"),
+                    device_id: "dev".into(),
+                    timestamp: Utc::now().to_rfc3339(),
+                    op_type: OperationType::CreateDocument,
+                    document_id: id.into(),
+                    payload: make_payload(Some(id.into()), "v1"),
+                    before_hash: None,
+                    after_hash: None,
+                    clock,
+                })
+                .unwrap();
+        }
+
+        // crash after journaling an update of doc1 (mid temp-file write) and a delete of doc2
+        let clock = store.next_clock("doc1", "dev").unwrap();
+        let update = Operation {
+            op_id: "update".into(),
+            device_id: "dev".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::UpdateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), "v2"),
+            before_hash: None,
+            after_hash: None,
+            clock: clock.clone(),
+        };
+        let updated = store.materialize(&update).unwrap();
+        let markdown = updated.to_markdown().unwrap();
+        store
+            .journal_write("doc1", Some(&markdown), &clock)
+            .unwrap();
+        fs::write(dir.path().join("doc1.md.tmp"), &markdown[..10]).unwrap();
+        store
+            .journal_write("doc2", None, &store.next_clock("doc2", "dev").unwrap())
+            .unwrap();
+        drop(store);
+
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let doc = store.load_document("doc1").unwrap().unwrap();
+        assert_eq!(doc.body, "v2");
+        assert_eq!(store.document_clock("doc1").unwrap(), clock);
+        assert!(!dir.path().join("doc1.md.tmp").exists());
+        assert!(store.load_document("doc2").unwrap().is_none());
+        let ids: Vec<String> = store
+            .list_documents()
+            .unwrap()
+            .into_iter()
+            .map(|d| d.id)
+            .collect();
+        assert_eq!(ids, vec!["doc1"]);
+        // the index matches the files, so nothing looks externally edited
+        assert!(store.scan_external_changes("dev").unwrap().is_empty());
+        assert_eq!(store.recover().unwrap(), RecoveryReport::default());
+    }
+
+    #[test]
+    fn unparseable_journal_entries_are_set_aside() {
+        let dir = tempdir().unwrap();
+        let store = Store::with_root(dir.path()).unwrap();
+        let clock = store.next_clock("doc1", "dev").unwrap();
+        store
+            .journal_write("doc1", Some("---\nid: [doc1\n---\n"), &clock)
+            .unwrap();
+        store
+            .journal_write(
+                "doc2",
+                Some("---\nid: doc2\ntype: note\ncreated: c\nupdated: u\n---\n\ntwo"),
+                &clock,
+            )
+            .unwrap();
+        drop(store);
+
+        // opening the vault still works and completes the readable entry
+        let store = Store::with_root(dir.path()).unwrap();
+        assert!(store.load_document("doc1").unwrap().is_none());
+        assert!(store.load_document("doc2").unwrap().is_some());
+        let aside = dir.path().join(".recovery/doc1.md");
+        assert_eq!(fs::read_to_string(&aside).unwrap(), "---\nid: [doc1\n---\n");
+        assert_eq!(store.recover().unwrap(), RecoveryReport::default());
+
+        store
+            .journal_write("doc3", Some("---\nid: [doc3\n---\n"), &clock)
+            .unwrap();
+        let report = store.recover().unwrap();
+        assert_eq!(report.completed, 0);
+        assert_eq!(report.set_aside.len(), 1);
+        assert_eq!(report.set_aside[0].document_id, "doc3");
+        assert_eq!(
+            report.set_aside[0].path,
+            dir.path().join(".recovery/doc3.md")
+        );
+        assert!(!report.set_aside[0].error.is_empty());
+    }
+
+    #[test]
+    fn apply_batch_reports_each_op_and_rolls_back_on_failure() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        use OperationType::{CreateDocument, UpdateDocument};
+        let create = op_with_clock("c1", CreateDocument, "doc1", "v1", &[("peer", 1)]);
+        let update = op_with_clock("u1", UpdateDocument, "doc1", "v2", &[("peer", 2)]);
+        let mut bad_hash = op_with_clock("b1", CreateDocument, "doc2", "x", &[("peer", 1)]);
+        bad_hash.after_hash = Some("nope".into());
+        let bad_key = bad_hash.key();
+        // given out of order; applied causally
+        let report = store
+            .apply_batch(&[update.clone(), create.clone(), create.clone(), bad_hash])
+            .unwrap();
+        let outcomes_of = |key: String| -> Vec<Outcome> {
+            report
+                .outcomes
+                .iter()
+                .filter(|o| o.op_key == key)
+                .map(|o| o.outcome.clone())
+                .collect()
+        };
+        assert_eq!(
+            outcomes_of(create.key()),
+            vec![Outcome::Applied, Outcome::Duplicate]
+        );
+        assert_eq!(outcomes_of(update.key()), vec![Outcome::Applied]);
+        assert_eq!(report.outcomes.last().unwrap().op_key, update.key());
+        assert_eq!(outcomes_of(bad_key), vec![Outcome::HashMismatch]);
+        let doc = store.load_document("doc1").unwrap().unwrap();
+        assert_eq!(doc.body, "v2");
+
+        // an edit v2 already covers is reported as superseded, not applied
+        let stale = op_with_clock("s1", UpdateDocument, "doc1", "old", &[("peer", 1)]);
+        let report = store.apply_batch(&[stale]).unwrap();
+        assert_eq!(report.count(&Outcome::Superseded), 1);
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().body, "v2");
+        assert!(store
+            .ingest_external_change("doc1", "dev")
+            .unwrap()
+            .is_none());
+
+        // a concurrent edit of doc1 and an unreadable doc3 file: the batch fails as a whole
+        let mut concurrent = op_with_clock(
+            "x1",
+            UpdateDocument,
+            "doc1",
+            "other",
+            &[("laptop", 1), ("peer", 1)],
+        );
+        concurrent.before_hash = Some("v1".into());
+        let fine = op_with_clock("c2", CreateDocument, "doc4", "new", &[("peer", 1)]);
+        fs::create_dir(dir.path().join("doc3.md")).unwrap();
+        let broken = op_with_clock("u3", UpdateDocument, "doc3", "x", &[("peer", 3)]);
+        assert!(store
+            .apply_batch(&[fine.clone(), concurrent.clone(), broken])
+            .is_err());
+        assert!(store.load_document("doc4").unwrap().is_none());
+        assert_eq!(store.list_documents().unwrap().len(), 1);
+        assert!(store.list_conflicts().unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        fs::remove_dir(dir.path().join("doc3.md")).unwrap();
+        let report = store.apply_batch(&[fine, concurrent]).unwrap();
+        assert_eq!(report.count(&Outcome::Applied), 1);
+        assert_eq!(report.count(&Outcome::Conflict), 1);
+        assert_eq!(store.list_documents().unwrap().len(), 2);
+
+        // a file that can't be written after the commit is reported, not failed, and
+        // recover finishes it
+        fs::create_dir(dir.path().join("doc5.md.tmp")).unwrap();
+        let create5 = op_with_clock("c5", CreateDocument, "doc5", "five", &[("peer", 1)]);
+        let report = store.apply_batch(&[create5]).unwrap();
+        assert_eq!(report.count(&Outcome::Applied), 1);
+        assert_eq!(report.unflushed, vec!["doc5".to_string()]);
+        fs::remove_dir(dir.path().join("doc5.md.tmp")).unwrap();
+        assert_eq!(store.recover().unwrap().completed, 1);
+        assert_eq!(store.load_document("doc5").unwrap().unwrap().body, "five");
+    }
+
+    #[test]
+    fn apply_batch_handles_thousands_of_ops() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut ops = Vec::new();
+        for doc in 0..100 {
+            let doc = format!("doc{doc}");
+            for n in 1..=50u64 {
+                let op_type = match n {
+                    1 => OperationType::CreateDocument,
+                    _ => OperationType::UpdateDocument,
+                };
+                let id = format!("{doc}-{n:02}");
+                ops.push(op_with_clock(
+                    &id,
+                    op_type,
+                    &doc,
+                    &format!("v{n}"),
+                    &[("peer", n)],
+                ));
+            }
+        }
+        ops.reverse();
+        let report = store.apply_batch(&ops).unwrap();
+        assert_eq!(report.count(&Outcome::Applied), 5000);
+        assert!(report.unflushed.is_empty());
+        assert_eq!(store.list_documents().unwrap().len(), 100);
+        assert_eq!(store.load_document("doc42").unwrap().unwrap().body, "v50");
+        assert_eq!(store.recover().unwrap(), RecoveryReport::default());
+    }
+
+    #[test]
+    fn applied_ops_survive_a_restart() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        // clock-less ops, as older clients send them, only have hashes to go by
+        let op = |op_id: &str, body: &str, before_hash: Option<String>| Operation {
+            op_id: op_id.into(),
+            device_id: "old-client".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: if before_hash.is_none() {
+                OperationType::CreateDocument
+            } else {
+                OperationType::UpdateDocument
+            },
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), body),
+            before_hash,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        let create = op("op1", "v1", None);
+        let v1 = store.apply(create.clone()).unwrap().unwrap();
+        let update = op("op2", "v2", Some(v1.hash_content()));
+        store.apply(update.clone()).unwrap().unwrap();
+        drop(store);
+
+        let mut store = Store::with_root(dir.path()).unwrap();
+        // without the registry the update's before_hash no longer matches: a conflict
+        assert!(store.apply(update.clone()).unwrap().is_none());
+        assert!(store.apply(create.clone()).unwrap().is_none());
+        assert!(store.list_conflicts().unwrap().is_empty());
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().body, "v2");
+
+        let applied = store.applied_op(&update.key()).unwrap().unwrap();
+        assert_eq!(applied.op_hash, update.op_hash());
+        assert_eq!(applied.outcome, AppliedOutcome::Applied);
+        let keys = [create.key(), op("op3", "v3", None).key()];
+        assert_eq!(
+            store
+                .applied_op_keys(keys.iter().map(String::as_str))
+                .unwrap(),
+            [create.key()].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn deletes_leave_tombstones_and_race_edits_conflict() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let op = |op_id: &str, op_type, body: &str, clock: &[(&str, u64)]| {
+            op_with_clock(op_id, op_type, "doc1", body, clock)
+        };
+        use OperationType::{CreateDocument, DeleteDocument, UpdateDocument};
+        store
+            .apply(op("c", CreateDocument, "v1", &[("laptop", 1)]))
+            .unwrap();
+        store
+            .apply(op("u", UpdateDocument, "v2", &[("laptop", 2)]))
+            .unwrap();
+        let delete = op("d", DeleteDocument, "", &[("laptop", 3)]);
+        store.apply(delete.clone()).unwrap();
+        let tombstone = store.tombstone("doc1").unwrap().unwrap();
+        assert_eq!(tombstone.op_key, delete.key());
+        assert!(tombstone.last_hash.is_some());
+
+        // an edit the delete had seen stays dead; one made concurrently is a conflict
+        let stale = op("late", UpdateDocument, "stale", &[("laptop", 2)]);
+        assert!(store.apply(stale).unwrap().is_none());
+        let raced = op(
+            "x",
+            UpdateDocument,
+            "desktop edit",
+            &[("desktop", 1), ("laptop", 2)],
+        );
+        assert!(matches!(
+            store.apply(raced.clone()),
+            Err(StoreError::Conflict(_))
+        ));
+        assert!(store.load_document("doc1").unwrap().is_none());
+        let conflicts = store.list_conflicts().unwrap();
+        assert_eq!(conflicts[0].kind, ConflictKind::DeletedLocally);
+        assert!(dir.path().join(&conflicts[0].copy_path).exists());
+
+        let (doc, recreate) = store
+            .resolve_conflict(conflicts[0].id, ConflictChoice::KeepRemote, "laptop")
+            .unwrap();
+        assert_eq!(doc.unwrap().body, "desktop edit");
+        assert!(matches!(recreate.op_type, CreateDocument));
+        assert!(store.tombstone("doc1").unwrap().is_none());
+
+        // a delete racing a local edit keeps the edit until resolved
+        let remote_delete = op("rd", DeleteDocument, "", &[("phone", 1), ("laptop", 3)]);
+        assert!(store.apply(remote_delete).is_err());
+        assert_eq!(
+            store.load_document("doc1").unwrap().unwrap().body,
+            "desktop edit"
+        );
+        let conflicts = store.list_conflicts().unwrap();
+        assert_eq!(conflicts[0].kind, ConflictKind::DeletedRemotely);
+        assert!(conflicts[0].copy_path.is_empty());
+        let (doc, delete) = store
+            .resolve_conflict(conflicts[0].id, ConflictChoice::KeepRemote, "laptop")
+            .unwrap();
+        assert!(doc.is_none());
+        assert!(matches!(delete.op_type, DeleteDocument));
+        assert!(store.load_document("doc1").unwrap().is_none());
+        assert_eq!(
+            store.tombstone("doc1").unwrap().unwrap().op_key,
+            delete.key()
+        );
+    }
+
+    #[test]
+    fn local_deletes_leave_tombstones_too() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        use OperationType::{CreateDocument, UpdateDocument};
+        let create = op_with_clock("c", CreateDocument, "doc1", "v1", &[("laptop", 1)]);
+        let created = store.apply(create).unwrap().unwrap();
+        store.delete_document("doc1").unwrap();
+        let tombstone = store.tombstone("doc1").unwrap().unwrap();
+        assert_eq!(tombstone.last_hash, Some(created.hash_content()));
+
+        // an edit made elsewhere on the deleted version must not bring it back
+        let stale = Operation {
+            before_hash: Some(created.hash_content()),
+            ..op_with_clock("u", UpdateDocument, "doc1", "v2", &[("laptop", 2)])
+        };
+        assert!(matches!(store.apply(stale), Err(StoreError::Conflict(_))));
+        assert!(store.load_document("doc1").unwrap().is_none());
+        assert_eq!(
+            store.list_conflicts().unwrap()[0].kind,
+            ConflictKind::DeletedLocally
+        );
+    }
+
+    #[test]
+    fn deleted_documents_go_to_trash_and_can_be_restored() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut clock = VectorClock::new();
+        clock.increment("laptop");
+        let create = Operation {
+            op_id: "c".into(),
+            device_id: "laptop".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::CreateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), "keep me"),
+            before_hash: None,
+            after_hash: None,
+            clock: clock.clone(),
+        };
+        store.apply(create).unwrap();
+        clock.increment("laptop");
+        let delete = Operation {
+            op_id: "d".into(),
+            device_id: "laptop".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::DeleteDocument,
+            document_id: "doc1".into(),
+            payload: serde_json::Value::Null,
+            before_hash: None,
+            after_hash: None,
+            clock,
+        };
+        store.apply(delete.clone()).unwrap();
+        assert!(!dir.path().join("doc1.md").exists());
+        assert!(dir.path().join(".trash/doc1.md").exists());
+        let trash = store.list_trash().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].title.as_deref(), Some("Test Title"));
+        assert_eq!(trash[0].op_key, Some(delete.key()));
+
+        let (doc, op) = store.restore("doc1", "desktop").unwrap();
+        assert_eq!(doc.body, "keep me");
+        assert!(matches!(op.op_type, OperationType::CreateDocument));
+        assert!(op.clock.compare(&delete.clock) == notes_oplog::CausalOrder::After);
+        assert_eq!(op.after_hash, Some(doc.hash_content()));
+        assert!(store.list_trash().unwrap().is_empty());
+        assert!(store.tombstone("doc1").unwrap().is_none());
+
+        // a local delete can be restored too
+        store.delete_document("doc1").unwrap();
+        assert_eq!(store.list_trash().unwrap()[0].op_key, None);
+        let (doc, _) = store.restore("doc1", "desktop").unwrap();
+        assert_eq!(doc.body, "keep me");
+        assert!(dir.path().join("doc1.md").exists());
+        assert!(!dir.path().join(".trash/doc1.md").exists());
+        assert!(store.tombstone("doc1").unwrap().is_none());
+        assert!(store.list_conflicts().unwrap().is_empty());
+
+        store.delete_document("doc1").unwrap();
+        assert_eq!(store.list_trash().unwrap()[0].op_key, None);
+        assert_eq!(store.purge_trash("2000-01-01T00:00:00+00:00").unwrap(), 0);
+        let cutoff = (Utc::now() + chrono::Duration::seconds(1)).to_rfc3339();
+        assert_eq!(store.purge_trash(&cutoff).unwrap(), 1);
+        assert!(store.list_trash().unwrap().is_empty());
+        assert!(!dir.path().join(".trash/doc1.md").exists());
+        assert!(matches!(
+            store.restore("doc1", "desktop"),
+            Err(StoreError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn payloads_without_an_id_take_the_ops_document_id() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut op = op_with_clock("c1", OperationType::CreateDocument, "doc1", "v1", &[("a", 1)]);
+        op.payload = make_payload(None, "v1");
+        let first = store.materialize(&op).unwrap();
+        assert_eq!(first.frontmatter.id, "doc1");
+        assert_eq!(store.materialize(&op).unwrap().hash_content(), first.hash_content());
+        let applied = store.apply(op).unwrap().unwrap();
+        assert_eq!(applied.hash_content(), first.hash_content());
+        assert_eq!(store.load_document("doc1").unwrap().unwrap().frontmatter.id, "doc1");
+    }
+
+    #[test]
+    fn extra_frontmatter_keys_survive_ops() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut payload = make_payload(Some("doc1".into()), "body");
+        let fm = payload["frontmatter"].as_object_mut().unwrap();
+        fm.insert("status".into(), "draft".into());
+        fm.insert("aliases".into(), serde_json::json!(["a", "b"]));
+        fm.insert("author".into(), "Ada".into());
+        let op = Operation {
+            op_id: "c".into(),
+            device_id: "dev".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::CreateDocument,
+            document_id: "doc1".into(),
+            payload,
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        // as it would arrive from the op-log or a peer
+        let op: Operation = serde_json::from_str(&serde_json::to_string(&op).unwrap()).unwrap();
+        store.apply(op).unwrap();
+
+        let doc = store.load_document("doc1").unwrap().unwrap();
+        let keys: Vec<_> = doc
+            .frontmatter
+            .extra
+            .keys()
+            .filter_map(|k| k.as_str())
+            .collect();
+        assert_eq!(keys, ["status", "aliases", "author"]);
+        assert_eq!(
+            doc.frontmatter.property_strings("aliases"),
+            Some(vec!["a", "b"])
+        );
+        let raw = fs::read_to_string(dir.path().join("doc1.md")).unwrap();
+        assert!(raw.contains("status: draft\naliases:\n- a\n- b\nauthor: Ada\n"));
+    }
+
+    #[test]
+    fn legacy_hashes_from_older_peers_still_verify() {
+        let dir = tempdir().unwrap();
+        let mut store = Store::with_root(dir.path()).unwrap();
+        let mut create = Operation {
+            op_id: "c".into(),
+            device_id: "old".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: OperationType::CreateDocument,
+            document_id: "doc1".into(),
+            payload: make_payload(Some("doc1".into()), "v1"),
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        };
+        let legacy = |store: &Store, op: &Operation| {
+            store
+                .materialize(op)
+                .unwrap()
+                .hash_with(notes_core::HashScheme::Legacy)
+        };
+        create.after_hash = legacy(&store, &create);
+        let doc = store.apply(create.clone()).unwrap().unwrap();
+        assert!(doc.hash_content().starts_with("v1:sha256:"));
+
+        let mut update = Operation {
+            op_id: "u".into(),
+            payload: make_payload(Some("doc1".into()), "v2"),
+            op_type: OperationType::UpdateDocument,
+            before_hash: doc.hash_with(notes_core::HashScheme::Legacy),
+            ..create.clone()
+        };
+        update.after_hash = legacy(&store, &update);
+        assert_eq!(store.apply(update.clone()).unwrap().unwrap().body, "v2");
+
+        let mut tampered = Operation {
+            op_id: "t".into(),
+            ..update
+        };
+        tampered.after_hash = legacy(&store, &create);
+        assert!(matches!(
+            store.apply(tampered),
+            Err(StoreError::HashMismatch(_))
+        ));
+    }
 }
@@ -0,0 +1,411 @@
+//! Link index: extracts `[[wikilinks]]`, relative markdown links and frontmatter `links`
+//! from documents, persists them in the `links` table and answers backlink/outgoing queries.
+//! Only links to notes count: image embeds, links to attachments (a known file extension such
+//! as `.png` or `.pdf`) and anything inside inline or fenced code are skipped.
+//! Targets are stored as written and resolved at query time (by id, then by title), so a link
+//! to a note that doesn't exist yet starts resolving as soon as that note is created.
+
+use crate::{DocumentSummary, Store, StoreError};
+use notes_core::Document;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    Wiki,
+    Markdown,
+    Frontmatter,
+}
+
+impl LinkKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LinkKind::Wiki => "wiki",
+            LinkKind::Markdown => "markdown",
+            LinkKind::Frontmatter => "frontmatter",
+        }
+    }
+
+    fn parse(raw: &str) -> Self {
+        match raw {
+            "wiki" => LinkKind::Wiki,
+            "markdown" => LinkKind::Markdown,
+            _ => LinkKind::Frontmatter,
+        }
+    }
+}
+
+/// A link as written in a document, with the id it currently resolves to (if any).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub source_id: String,
+    pub target: String,
+    pub kind: LinkKind,
+    pub resolved_id: Option<String>,
+}
+
+/// Extract normalized link targets from a document, deduplicated in first-seen order.
+pub fn extract_links(doc: &Document) -> Vec<(String, LinkKind)> {
+    let mut out: Vec<(String, LinkKind)> = Vec::new();
+    let mut push = |target: Option<String>, kind: LinkKind| {
+        if let Some(t) = target {
+            if !out.iter().any(|(existing, _)| existing == &t) {
+                out.push((t, kind));
+            }
+        }
+    };
+
+    let body = strip_code(&doc.body);
+    let mut rest = body.as_str();
+    while let Some(start) = rest.find("[[") {
+        let after = &rest[start + 2..];
+        match after.find("]]") {
+            Some(end) => {
+                let inner = &after[..end];
+                if !inner.contains('\n') {
+                    let target = inner.split('|').next().unwrap_or_default();
+                    if !is_attachment(target) {
+                        push(normalize_target(target), LinkKind::Wiki);
+                    }
+                }
+                rest = &after[end + 2..];
+            }
+            None => break,
+        }
+    }
+
+    let mut from = 0;
+    while let Some(i) = body[from..].find("](") {
+        let start = from + i;
+        let after = &body[start + 2..];
+        let Some(end) = after.find(')') else {
+            break;
+        };
+        let raw = after[..end].trim();
+        // drop an optional link title: [text](target "title")
+        let raw = raw.split_whitespace().next().unwrap_or_default();
+        let raw = raw.trim_start_matches('<').trim_end_matches('>');
+        let image = link_text_start(&body[..start]).is_some_and(|open| body[..open].ends_with('!'));
+        if !image && !is_external(raw) && !is_attachment(raw) {
+            push(normalize_target(raw), LinkKind::Markdown);
+        }
+        from = start + 2 + end + 1;
+    }
+
+    for link in &doc.frontmatter.links {
+        push(normalize_target(link), LinkKind::Frontmatter);
+    }
+    out
+}
+
+fn is_external(target: &str) -> bool {
+    target.contains("://") || target.starts_with("mailto:") || target.starts_with('#')
+}
+
+/// File extensions that mark a link target as an attachment rather than a note. Only known
+/// ones count, since note titles often contain dots (`Release 1.0`, `v2.1`).
+const ATTACHMENT_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "svg", "bmp", "tif", "tiff", "heic", "pdf", "epub",
+    "doc", "docx", "xls", "xlsx", "ppt", "pptx", "odt", "ods", "odp", "csv", "txt", "json",
+    "mp3", "m4a", "wav", "ogg", "flac", "mp4", "mov", "webm", "mkv", "zip", "tar", "gz",
+];
+
+/// Whether `target` names a file other than a note, e.g. `diagram.png` or `../specs/a.pdf`.
+fn is_attachment(target: &str) -> bool {
+    let path = target.split(['#', '?']).next().unwrap_or_default().trim();
+    let name = path.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ATTACHMENT_EXTENSIONS
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known)),
+        _ => false,
+    }
+}
+
+/// Offset of the `[` opening the link text that ends at the end of `before`.
+fn link_text_start(before: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, c) in before.char_indices().rev() {
+        match c {
+            ']' => depth += 1,
+            '[' if depth == 0 => return Some(i),
+            '[' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `body` without fenced code blocks and inline code spans (each replaced by whitespace).
+fn strip_code(body: &str) -> String {
+    let mut unfenced = String::with_capacity(body.len());
+    let mut fence: Option<&str> = None;
+    for line in body.split_inclusive('\n') {
+        let marker = ["```", "~~~"]
+            .into_iter()
+            .find(|m| line.trim_start().starts_with(m));
+        if fence.is_none() && marker.is_none() {
+            unfenced.push_str(line);
+            continue;
+        }
+        unfenced.push('\n');
+        match (fence, marker) {
+            (None, Some(open)) => fence = Some(open),
+            (Some(open), Some(close)) if open == close => fence = None,
+            _ => {}
+        }
+    }
+
+    // a span opened by a run of n backticks is closed by the next run of exactly n
+    let mut prose = String::with_capacity(unfenced.len());
+    let mut rest = unfenced.as_str();
+    while let Some(start) = rest.find('`') {
+        prose.push_str(&rest[..start]);
+        let run = backtick_run(&rest[start..]);
+        let after = &rest[start + run..];
+        let mut offset = 0;
+        let close = loop {
+            let Some(i) = after[offset..].find('`') else {
+                break None;
+            };
+            let len = backtick_run(&after[offset + i..]);
+            if len == run {
+                break Some(offset + i);
+            }
+            offset += i + len;
+        };
+        match close {
+            Some(end) => {
+                prose.push(' ');
+                rest = &after[end + run..];
+            }
+            None => {
+                prose.push_str(&rest[start..start + run]);
+                rest = after;
+            }
+        }
+    }
+    prose.push_str(rest);
+    prose
+}
+
+fn backtick_run(text: &str) -> usize {
+    text.len() - text.trim_start_matches('`').len()
+}
+
+fn normalize_target(raw: &str) -> Option<String> {
+    let target = raw.split('#').next().unwrap_or_default().trim();
+    let target = target.trim_start_matches("./");
+    let target = target.strip_suffix(".md").unwrap_or(target);
+    let target = target.replace("%20", " ");
+    if target.is_empty() {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+// A link target resolves to a document when it equals the id or (case-insensitively) the title.
+const RESOLVE_JOIN: &str =
+    "LEFT JOIN documents d ON d.id = l.target OR (d.title <> '' AND lower(d.title) = lower(l.target))";
+
+impl Store {
+    pub(crate) fn init_links_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS links(
+                source_id TEXT NOT NULL,
+                target TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                PRIMARY KEY(source_id, target)
+            );
+            CREATE INDEX IF NOT EXISTS links_target ON links(target);",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    pub(crate) fn upsert_links(&self, doc: &Document) -> Result<(), StoreError> {
+        self.delete_links(&doc.frontmatter.id)?;
+        for (target, kind) in extract_links(doc) {
+            self.conn
+                .execute(
+                    "INSERT OR IGNORE INTO links(source_id, target, kind) VALUES(?1, ?2, ?3)",
+                    params![doc.frontmatter.id, target, kind.as_str()],
+                )
+                .map_err(|e| StoreError::Db(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_links(&self, id: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute("DELETE FROM links WHERE source_id=?1", params![id])
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Documents that link to `id` (by id or by its title).
+    pub fn backlinks(&self, id: &str) -> Result<Vec<DocumentSummary>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT DISTINCT s.id, s.doc_type, s.updated, s.title, s.tags
+                 FROM links l
+                 JOIN documents t ON t.id = ?1
+                 JOIN documents s ON s.id = l.source_id
+                 WHERE l.source_id <> ?1
+                   AND (l.target = t.id OR (t.title <> '' AND lower(l.target) = lower(t.title)))
+                 ORDER BY s.updated DESC",
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map(params![id], |row| {
+                let tags_json: String = row.get(4)?;
+                Ok(DocumentSummary {
+                    id: row.get(0)?,
+                    doc_type: row.get(1)?,
+                    updated: row.get(2)?,
+                    title: row.get(3).ok(),
+                    tags: serde_json::from_str(&tags_json).unwrap_or_default(),
+                })
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut docs = Vec::new();
+        for r in rows {
+            docs.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
+        }
+        Ok(docs)
+    }
+
+    /// Links written in document `id`, resolved against the current index.
+    pub fn outgoing_links(&self, id: &str) -> Result<Vec<Link>, StoreError> {
+        self.query_links(
+            &format!(
+                "SELECT l.source_id, l.target, l.kind, min(d.id) FROM links l {RESOLVE_JOIN}
+                 WHERE l.source_id = ?1 GROUP BY l.source_id, l.target ORDER BY l.rowid"
+            ),
+            params![id],
+        )
+    }
+
+    /// Links across the vault whose target matches no document.
+    pub fn unresolved_links(&self) -> Result<Vec<Link>, StoreError> {
+        self.query_links(
+            &format!(
+                "SELECT l.source_id, l.target, l.kind, min(d.id) FROM links l {RESOLVE_JOIN}
+                 GROUP BY l.source_id, l.target HAVING min(d.id) IS NULL
+                 ORDER BY l.source_id, l.rowid"
+            ),
+            [],
+        )
+    }
+
+    fn query_links(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Link>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare(sql)
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map(params, |row| {
+                let kind: String = row.get(2)?;
+                Ok(Link {
+                    source_id: row.get(0)?,
+                    target: row.get(1)?,
+                    kind: LinkKind::parse(&kind),
+                    resolved_id: row.get(3)?,
+                })
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut links = Vec::new();
+        for r in rows {
+            links.push(r.map_err(|e| StoreError::Db(e.to_string()))?);
+        }
+        Ok(links)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notes_core::{DocumentType, Frontmatter};
+
+    fn doc(body: &str, links: Vec<String>) -> Document {
+        Document {
+            frontmatter: Frontmatter {
+                id: "src".into(),
+                doc_type: DocumentType::Note,
+                title: None,
+                created: "2025-01-01T00:00:00Z".into(),
+                updated: "2025-01-01T00:00:00Z".into(),
+                tags: vec![],
+                links,
+                extra: Default::default(),
+            },
+            body: body.into(),
+            format: Default::default(),
+        }
+    }
+
+    #[test]
+    fn extracts_wiki_markdown_and_frontmatter_links() {
+        let d = doc(
+            "See [[Meeting Notes|the notes]] and [[abc#Heading]].\n\
+             Also [spec](./spec.md \"Spec\"), [site](https://example.com) and [top](#top).\n\
+             Again [[Meeting Notes]].",
+            vec!["fm-target".into()],
+        );
+        let links = extract_links(&d);
+        assert_eq!(
+            links,
+            vec![
+                ("Meeting Notes".to_string(), LinkKind::Wiki),
+                ("abc".to_string(), LinkKind::Wiki),
+                ("spec".to_string(), LinkKind::Markdown),
+                ("fm-target".to_string(), LinkKind::Frontmatter),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_images_and_attachments() {
+        let d = doc(
+            "![diagram](./diagram.png) and [![badge](badge.svg)](./status.md)\n\
+             [the spec](spec.pdf), [[photo.JPG]], [[v2 plan]] and [notes](notes.md#top).\n\
+             Dotted titles are notes: [[Release 1.0]], [[v2.1|see]] and [n](Notes%202025.01).",
+            vec![],
+        );
+        assert_eq!(
+            extract_links(&d),
+            vec![
+                ("v2 plan".to_string(), LinkKind::Wiki),
+                ("Release 1.0".to_string(), LinkKind::Wiki),
+                ("v2.1".to_string(), LinkKind::Wiki),
+                ("status".to_string(), LinkKind::Markdown),
+                ("notes".to_string(), LinkKind::Markdown),
+                ("Notes 2025.01".to_string(), LinkKind::Markdown),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_links_in_code() {
+        let d = doc(
+            "Use `[[not a link]]` or ``[x](`y`.md)``, but [[real]].\n\
+             ```md\n[[fenced]] and [f](fenced.md)\n~~~\n```\n\
+             ~~~\n[t](tilde.md)\n~~~\n\
+             Unclosed ` tick, [after](after.md).",
+            vec![],
+        );
+        assert_eq!(
+            extract_links(&d),
+            vec![
+                ("real".to_string(), LinkKind::Wiki),
+                ("after".to_string(), LinkKind::Markdown),
+            ]
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! Three-way merge of concurrent document versions. Bodies are merged line by line (diff3
+//! against the common ancestor); frontmatter fields are merged individually, with tag/link
+//! lists merged as sets and extra properties merged key by key. The result is deterministic
+//! and symmetric in `local`/`remote` for non-overlapping edits, so two devices merging the
+//! same pair of edits converge.
+
+use notes_core::Document;
+use serde_yaml::Mapping;
+
+const MARKER_LOCAL: &str = "<<<<<<< local";
+const MARKER_BASE: &str = "||||||| base";
+const MARKER_SEP: &str = "=======";
+const MARKER_REMOTE: &str = ">>>>>>> remote";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMerge {
+    pub text: String,
+    /// Number of overlapping hunks rendered with diff3-style markers.
+    pub conflicts: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DocumentMerge {
+    pub document: Document,
+    pub conflicts: usize,
+}
+
+/// Merge two documents derived from `base`. Body hunks that overlap are kept with diff3
+/// markers and counted in `conflicts`; scalar frontmatter fields changed on both sides take
+/// the value from the side with the newer `updated` timestamp.
+pub fn merge_documents(base: &Document, local: &Document, remote: &Document) -> DocumentMerge {
+    let body = merge_text(&base.body, &local.body, &remote.body);
+    let newer_is_remote = (
+        &remote.frontmatter.updated,
+        &remote.body,
+        &remote.frontmatter.title,
+    ) > (
+        &local.frontmatter.updated,
+        &local.body,
+        &local.frontmatter.title,
+    );
+    let (b, l, r) = (&base.frontmatter, &local.frontmatter, &remote.frontmatter);
+
+    let mut frontmatter = l.clone();
+    frontmatter.doc_type = merge_scalar(&b.doc_type, &l.doc_type, &r.doc_type, newer_is_remote);
+    frontmatter.title = merge_scalar(&b.title, &l.title, &r.title, newer_is_remote);
+    frontmatter.created = merge_scalar(&b.created, &l.created, &r.created, newer_is_remote);
+    frontmatter.updated = l.updated.clone().max(r.updated.clone());
+    frontmatter.tags = merge_set(&b.tags, &l.tags, &r.tags);
+    frontmatter.links = merge_set(&b.links, &l.links, &r.links);
+    frontmatter.extra = merge_properties(&b.extra, &l.extra, &r.extra, newer_is_remote);
+
+    DocumentMerge {
+        document: Document {
+            frontmatter,
+            body: body.text,
+            format: local.format,
+        },
+        conflicts: body.conflicts,
+    }
+}
+
+fn merge_scalar<T: Clone + PartialEq>(base: &T, local: &T, remote: &T, prefer_remote: bool) -> T {
+    if local == remote || remote == base {
+        local.clone()
+    } else if local == base || prefer_remote {
+        remote.clone()
+    } else {
+        local.clone()
+    }
+}
+
+/// Three-way set merge: keep what both sides kept, add what either side added. If only one
+/// side changed the list its order is kept verbatim; otherwise additions are appended sorted.
+fn merge_set(base: &[String], local: &[String], remote: &[String]) -> Vec<String> {
+    if local == remote || remote == base {
+        return local.to_vec();
+    }
+    if local == base {
+        return remote.to_vec();
+    }
+    let mut out: Vec<String> = base
+        .iter()
+        .filter(|t| local.contains(t) && remote.contains(t))
+        .cloned()
+        .collect();
+    let mut added: Vec<String> = local
+        .iter()
+        .chain(remote.iter())
+        .filter(|t| !base.contains(t))
+        .cloned()
+        .collect();
+    added.sort();
+    added.dedup();
+    out.extend(added);
+    out
+}
+
+/// Three-way merge of extra frontmatter properties, each key merged like a scalar (absent
+/// counts as a value, so removals merge too). If only one side changed them its order is kept
+/// verbatim; otherwise keys keep their base order and additions are appended sorted.
+fn merge_properties(
+    base: &Mapping,
+    local: &Mapping,
+    remote: &Mapping,
+    prefer_remote: bool,
+) -> Mapping {
+    if local == remote || remote == base {
+        return local.clone();
+    }
+    if local == base {
+        return remote.clone();
+    }
+    let mut keys: Vec<_> = base.keys().collect();
+    let mut added: Vec<_> = local
+        .keys()
+        .chain(remote.keys())
+        .filter(|k| !base.contains_key(*k))
+        .collect();
+    added.sort_by_key(|k| serde_yaml::to_string(k).unwrap_or_default());
+    added.dedup();
+    keys.extend(added);
+    let mut out = Mapping::new();
+    for key in keys {
+        let merged = merge_scalar(
+            &base.get(key),
+            &local.get(key),
+            &remote.get(key),
+            prefer_remote,
+        );
+        if let Some(value) = merged {
+            out.insert(key.clone(), value.clone());
+        }
+    }
+    out
+}
+
+/// diff3-style line merge. Line endings are preserved exactly.
+pub fn merge_text(base: &str, local: &str, remote: &str) -> TextMerge {
+    let o: Vec<&str> = base.split_inclusive('\n').collect();
+    let a: Vec<&str> = local.split_inclusive('\n').collect();
+    let b: Vec<&str> = remote.split_inclusive('\n').collect();
+    let match_a = lcs_matches(&o, &a);
+    let match_b = lcs_matches(&o, &b);
+
+    let mut text = String::new();
+    let mut conflicts = 0;
+    let (mut io, mut ia, mut ib) = (0, 0, 0);
+    loop {
+        // emit stable lines present in all three at the current position
+        while io < o.len() && match_a[io] == Some(ia) && match_b[io] == Some(ib) {
+            text.push_str(o[io]);
+            io += 1;
+            ia += 1;
+            ib += 1;
+        }
+        // next base line matched on both sides ends the unstable chunk
+        let next = (io..o.len()).find(|&i| match_a[i].is_some() && match_b[i].is_some());
+        let (eo, ea, eb) = match next {
+            Some(i) => (
+                i,
+                match_a[i].unwrap_or(a.len()),
+                match_b[i].unwrap_or(b.len()),
+            ),
+            None => (o.len(), a.len(), b.len()),
+        };
+        let (co, ca, cb) = (&o[io..eo], &a[ia..ea], &b[ib..eb]);
+        if ca == co {
+            text.extend(cb.iter().copied());
+        } else if cb == co || ca == cb {
+            text.extend(ca.iter().copied());
+        } else {
+            conflicts += 1;
+            push_hunk(&mut text, MARKER_LOCAL, ca);
+            push_hunk(&mut text, MARKER_BASE, co);
+            push_hunk(&mut text, MARKER_SEP, cb);
+            text.push_str(MARKER_REMOTE);
+            text.push('\n');
+        }
+        if next.is_none() {
+            break;
+        }
+        io = eo;
+        ia = ea;
+        ib = eb;
+    }
+    TextMerge { text, conflicts }
+}
+
+fn push_hunk(out: &mut String, marker: &str, lines: &[&str]) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(marker);
+    out.push('\n');
+    for line in lines {
+        out.push_str(line);
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// For each line of `base`, the index of the line it is matched with in `other` along a
+/// longest common subsequence. Common prefix/suffix are matched directly so the quadratic
+/// table only covers the edited middle.
+pub(crate) fn lcs_matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    let prefix = base
+        .iter()
+        .zip(other.iter())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    for (i, m) in matches.iter_mut().enumerate().take(prefix) {
+        *m = Some(i);
+    }
+    for k in 0..suffix {
+        matches[base.len() - 1 - k] = Some(other.len() - 1 - k);
+    }
+
+    let x = &base[prefix..base.len() - suffix];
+    let y = &other[prefix..other.len() - suffix];
+    let (n, m) = (x.len(), y.len());
+    let mut table = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i * (m + 1) + j] = if x[i] == y[j] {
+                table[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                table[(i + 1) * (m + 1) + j].max(table[i * (m + 1) + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if x[i] == y[j] {
+            matches[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * (m + 1) + j] >= table[i * (m + 1) + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_non_overlapping_edits() {
+        let base = "# Meeting\n- agenda\n- notes\n- actions\n";
+        let local = "# Meeting (Mon)\n- agenda\n- notes\n- actions\n";
+        let remote = "# Meeting\n- agenda\n- notes\n- actions\n- follow up\n";
+        let merged = merge_text(base, local, remote);
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(
+            merged.text,
+            "# Meeting (Mon)\n- agenda\n- notes\n- actions\n- follow up\n"
+        );
+        assert_eq!(merge_text(base, remote, local), merged);
+    }
+
+    #[test]
+    fn overlapping_edits_get_markers() {
+        let merged = merge_text("a\nb\nc\n", "a\nlocal\nc\n", "a\nremote\nc\n");
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.text,
+            "a\n<<<<<<< local\nlocal\n||||||| base\nb\n=======\nremote\n>>>>>>> remote\nc\n"
+        );
+    }
+
+    #[test]
+    fn tag_sets_merge_additions_and_removals() {
+        let base = vec!["a".to_string(), "b".to_string()];
+        let local = vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        let remote = vec!["b".to_string(), "c".to_string()];
+        assert_eq!(merge_set(&base, &local, &remote), vec!["b", "c", "x"]);
+        assert_eq!(merge_set(&base, &remote, &local), vec!["b", "c", "x"]);
+    }
+
+    #[test]
+    fn extra_properties_merge_per_key() {
+        let map = |pairs: &[(&str, &str)]| -> Mapping {
+            pairs
+                .iter()
+                .map(|(k, v)| ((*k).into(), (*v).into()))
+                .collect()
+        };
+        let base = map(&[("status", "draft"), ("author", "Ada")]);
+        let local = map(&[("status", "final"), ("author", "Ada"), ("zeta", "l")]);
+        let remote = map(&[("status", "draft"), ("alpha", "r")]);
+        let expected = map(&[("status", "final"), ("alpha", "r"), ("zeta", "l")]);
+        let merged = merge_properties(&base, &local, &remote, false);
+        assert_eq!(merged, expected);
+        assert_eq!(
+            merged.keys().collect::<Vec<_>>(),
+            expected.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(merge_properties(&base, &remote, &local, true), expected);
+    }
+}
@@ -0,0 +1,139 @@
+//! Vault rebuild: replay the op-log into a fresh directory and compare the result with the
+//! live vault. Ops are replayed in causal order (see `history::sort_causally`) with the
+//! replayed prefix as merge history, so a rebuild from the same ops is deterministic. The
+//! comparison looks at both the index and the `<id>.md` files, so either being damaged shows
+//! up as drift.
+
+use crate::history::sort_causally;
+use crate::{Store, StoreError};
+use notes_oplog::Operation;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftKind {
+    /// The ops produce the document but the live vault doesn't have it.
+    Missing,
+    /// The live vault has a document the ops don't produce.
+    Extra,
+    /// Both have the document with different content (or the live file doesn't parse).
+    HashDifferent,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DocumentDrift {
+    pub document_id: String,
+    pub kind: DriftKind,
+    /// Content hash in the live vault, if its file exists and parses.
+    pub live_hash: Option<String>,
+    /// Content hash in the rebuilt vault.
+    pub rebuilt_hash: Option<String>,
+}
+
+/// An op the replay could not apply.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReplayFailure {
+    pub op_key: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RebuildReport {
+    /// Directory holding the rebuilt vault.
+    pub root: PathBuf,
+    /// Number of ops replayed, including failed ones.
+    pub replayed: usize,
+    pub failed: Vec<ReplayFailure>,
+    /// Per-document differences from the live vault, by document id.
+    pub drift: Vec<DocumentDrift>,
+}
+
+impl RebuildReport {
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty() && self.drift.is_empty()
+    }
+}
+
+impl Store {
+    /// Build a vault at `root` (which must be empty or not exist) from `ops` alone. Returns
+    /// the new store and the ops that failed to apply (e.g. unresolved conflicts).
+    pub fn replay(
+        root: impl AsRef<Path>,
+        ops: &[Operation],
+    ) -> Result<(Store, Vec<ReplayFailure>), StoreError> {
+        let root = root.as_ref();
+        let occupied = fs::read_dir(root)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        if occupied {
+            return Err(StoreError::Io(format!("{} is not empty", root.display())));
+        }
+        let mut store = Store::with_root(root)?;
+        let mut ordered: Vec<&Operation> = ops.iter().collect();
+        sort_causally(&mut ordered);
+
+        let mut history: Vec<Operation> = Vec::with_capacity(ordered.len());
+        let mut failed = Vec::new();
+        for op in ordered {
+            match store.apply_with_history(op.clone(), &history) {
+                Ok(_) => history.push(op.clone()),
+                Err(e) => failed.push(ReplayFailure {
+                    op_key: op.key(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+        Ok((store, failed))
+    }
+
+    /// Replay `ops` into the fresh directory `root` and report how this vault differs from
+    /// the result.
+    pub fn rebuild_from_ops(
+        &self,
+        ops: &[Operation],
+        root: impl AsRef<Path>,
+    ) -> Result<RebuildReport, StoreError> {
+        let (rebuilt, failed) = Store::replay(&root, ops)?;
+        let rebuilt_ids = rebuilt.document_ids_on_disk()?;
+        let mut ids = self.document_ids_on_disk()?;
+        // a damaged index may not list anything; the files are still compared
+        ids.extend(
+            self.list_documents()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| d.id),
+        );
+        ids.extend(rebuilt_ids.iter().cloned());
+
+        let mut drift = Vec::new();
+        for id in ids {
+            let live_exists = self.doc_path(&id).exists();
+            let live_hash = self
+                .load_document(&id)
+                .ok()
+                .flatten()
+                .map(|d| d.hash_content());
+            let rebuilt_hash = rebuilt.load_document(&id)?.map(|d| d.hash_content());
+            let kind = match (rebuilt_ids.contains(&id), live_exists) {
+                (true, false) => DriftKind::Missing,
+                (false, _) => DriftKind::Extra,
+                (true, true) if live_hash != rebuilt_hash => DriftKind::HashDifferent,
+                (true, true) => continue,
+            };
+            drift.push(DocumentDrift {
+                document_id: id,
+                kind,
+                live_hash,
+                rebuilt_hash,
+            });
+        }
+        Ok(RebuildReport {
+            root: root.as_ref().to_path_buf(),
+            replayed: ops.len(),
+            failed,
+            drift,
+        })
+    }
+}
@@ -0,0 +1,125 @@
+//! Reconciling `index.db` with the files on disk. Documents normally reach the index through
+//! `apply`, but files edited, added or removed by other tools (git, editors, file sync) only
+//! show up after a reindex: every `<id>.md` in the vault root is parsed and upserted, and rows
+//! without a readable file are dropped. Attachment links and document clocks come from ops,
+//! not files, so they are left alone. `with_root` reindexes whenever the stored index schema
+//! version differs from [`INDEX_SCHEMA_VERSION`].
+
+use crate::{document_id_from_path, Store, StoreError};
+use notes_core::Document;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// Bump when the index tables change shape or meaning; stored as SQLite's `user_version`.
+pub const INDEX_SCHEMA_VERSION: i64 = 4;
+
+/// A `*.md` file the reindex could not index.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UnparseableFile {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ReindexReport {
+    /// Documents (re)written to the index.
+    pub indexed: usize,
+    /// Index rows dropped because their file is gone or unreadable.
+    pub removed: usize,
+    pub unparseable: Vec<UnparseableFile>,
+}
+
+impl Store {
+    /// Rebuild the document index from the `*.md` files in the vault root.
+    pub fn reindex(&self) -> Result<ReindexReport, StoreError> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut report = ReindexReport::default();
+        let mut indexed = BTreeSet::new();
+        for id in self.document_ids_on_disk()? {
+            let path = self.doc_path(&id);
+            let parsed = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|raw| {
+                    Document::from_markdown_with_id(&raw, &id).map_err(|e| e.to_string())
+                })
+                .and_then(|doc| {
+                    if doc.frontmatter.id == id {
+                        Ok(doc)
+                    } else {
+                        Err(format!(
+                            "frontmatter id {} doesn't match the file name",
+                            doc.frontmatter.id
+                        ))
+                    }
+                });
+            match parsed {
+                Ok(doc) => {
+                    self.upsert_index(&doc)?;
+                    indexed.insert(id);
+                    report.indexed += 1;
+                }
+                Err(error) => report.unparseable.push(UnparseableFile { path, error }),
+            }
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM documents")
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let stale = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| StoreError::Db(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| StoreError::Db(e.to_string()))?
+            .into_iter()
+            .filter(|id| !indexed.contains(id));
+        for id in stale {
+            self.delete_document_rows(&id)?;
+            report.removed += 1;
+        }
+        drop(stmt);
+        tx.commit().map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(report)
+    }
+
+    /// Reindex if the index was built by a different schema version, then record the current
+    /// one.
+    pub(crate) fn migrate_index(&self) -> Result<(), StoreError> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        if version == INDEX_SCHEMA_VERSION {
+            return Ok(());
+        }
+        self.reindex()?;
+        self.conn
+            .execute_batch(&format!("PRAGMA user_version = {INDEX_SCHEMA_VERSION}"))
+            .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Ids of the `<id>.md` files in the vault root (conflict copies excluded).
+    pub(crate) fn document_ids_on_disk(&self) -> Result<BTreeSet<String>, StoreError> {
+        let entries = fs::read_dir(&self.root).map_err(|e| StoreError::Io(e.to_string()))?;
+        Ok(entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| document_id_from_path(&self.root, &e.path()))
+            .collect())
+    }
+
+    pub(crate) fn delete_document_rows(&self, id: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute("DELETE FROM documents WHERE id=?1", params![id])
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute("DELETE FROM documents_fts WHERE id=?1", params![id])
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        self.delete_links(id)
+    }
+}
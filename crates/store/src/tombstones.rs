@@ -0,0 +1,119 @@
+//! Delete tombstones: applying a `DeleteDocument` op leaves a row in `tombstones` with the
+//! deleting op and the document's clock at that point. An update or create for a tombstoned
+//! id is only applied if its clock causally follows the tombstone (the author saw the delete,
+//! so it is a deliberate re-creation); one the delete already covered is dropped as
+//! superseded, and one concurrent with it (or without a clock) is a delete-vs-edit conflict.
+//! Those conflicts, like deletes that race a local edit, are registered with the other
+//! conflicts and resolved by keeping the document or the deletion. A local delete made
+//! without an op (`Store::delete_document`) has no clock to order against, so its tombstone
+//! turns every later create/update for the id into such a conflict.
+
+use crate::{causal_decision, ApplyDecision, Store, StoreError};
+use chrono::Utc;
+use notes_core::Document;
+use notes_oplog::{CausalOrder, Operation, VectorClock};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub document_id: String,
+    /// Key of the op that deleted the document; empty for a local delete without an op.
+    pub op_key: String,
+    /// The document's clock once the delete was applied.
+    pub clock: VectorClock,
+    pub deleted_at: String,
+    /// Content hash of the version that was deleted, if it was present locally.
+    pub last_hash: Option<String>,
+}
+
+impl Store {
+    pub(crate) fn init_tombstones_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS tombstones(
+                document_id TEXT PRIMARY KEY,
+                op_key TEXT NOT NULL,
+                clock TEXT NOT NULL,
+                deleted_at TEXT NOT NULL,
+                last_hash TEXT
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    pub fn tombstone(&self, id: &str) -> Result<Option<Tombstone>, StoreError> {
+        self.conn
+            .query_row(
+                "SELECT document_id, op_key, clock, deleted_at, last_hash FROM tombstones
+                 WHERE document_id=?1",
+                params![id],
+                |row| {
+                    Ok(Tombstone {
+                        document_id: row.get(0)?,
+                        op_key: row.get(1)?,
+                        clock: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+                        deleted_at: row.get(3)?,
+                        last_hash: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    pub(crate) fn record_tombstone(
+        &self,
+        id: &str,
+        op_key: &str,
+        clock: &VectorClock,
+        last_hash: Option<String>,
+    ) -> Result<(), StoreError> {
+        let clock = serde_json::to_string(clock).map_err(|e| StoreError::Db(e.to_string()))?;
+        self.conn
+            .execute(
+                "INSERT INTO tombstones(document_id, op_key, clock, deleted_at, last_hash)
+                 VALUES(?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(document_id) DO UPDATE SET op_key=excluded.op_key,
+                 clock=excluded.clock, deleted_at=excluded.deleted_at,
+                 last_hash=COALESCE(excluded.last_hash, tombstones.last_hash)",
+                params![id, op_key, clock, Utc::now().to_rfc3339(), last_hash],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    pub(crate) fn clear_tombstone(&self, id: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute("DELETE FROM tombstones WHERE document_id=?1", params![id])
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// How a create/update for a deleted document relates to its tombstone.
+pub(crate) fn tombstone_decision(op: &Operation, tombstone: &Tombstone) -> ApplyDecision {
+    if op.clock.is_empty() || tombstone.clock.is_empty() {
+        return ApplyDecision::Conflict;
+    }
+    match op.clock.compare(&tombstone.clock) {
+        CausalOrder::After => ApplyDecision::FastForward,
+        CausalOrder::Before | CausalOrder::Equal => ApplyDecision::Stale,
+        CausalOrder::Concurrent => ApplyDecision::Conflict,
+    }
+}
+
+/// How a delete relates to the local copy. A delete concurrent with the local version only
+/// goes through if its `before_hash` shows it deleted exactly that content.
+pub(crate) fn delete_decision(
+    op: &Operation,
+    current: &Document,
+    local: &VectorClock,
+) -> ApplyDecision {
+    let concurrent = !op.clock.is_empty()
+        && !local.is_empty()
+        && matches!(op.clock.compare(local), CausalOrder::Concurrent);
+    if concurrent && op.before_hash.is_none() {
+        return ApplyDecision::Conflict;
+    }
+    causal_decision(op, current, local)
+}
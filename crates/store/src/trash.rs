@@ -0,0 +1,176 @@
+//! Trash: deleting a document (by op or through `delete_document`) moves `<id>.md` into
+//! `<vault>/.trash/` and records it in the `trash` table, so deletions synced from another
+//! device can be undone everywhere. `restore` brings a document back with a regular
+//! create/update op that causally follows the delete; `purge_trash` drops entries deleted
+//! before a cutoff. Only the latest deleted version of each document is kept.
+
+use crate::{Store, StoreError};
+use chrono::Utc;
+use notes_core::Document;
+use notes_oplog::{Operation, OperationType};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub document_id: String,
+    pub title: Option<String>,
+    pub deleted_at: String,
+    /// Key of the deleting op; `None` for deletes that didn't go through an op.
+    pub op_key: Option<String>,
+}
+
+impl Store {
+    pub(crate) fn init_trash_table(conn: &Connection) -> Result<(), StoreError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS trash(
+                document_id TEXT PRIMARY KEY,
+                title TEXT,
+                deleted_at TEXT NOT NULL,
+                op_key TEXT
+            );",
+        )
+        .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    pub(crate) fn trash_path(&self, id: &str) -> PathBuf {
+        self.root.join(".trash").join(format!("{id}.md"))
+    }
+
+    /// Move `<id>.md` into the trash, if it exists.
+    pub(crate) fn move_to_trash(&self, id: &str) -> std::io::Result<()> {
+        let path = self.doc_path(id);
+        if !path.exists() {
+            return Ok(());
+        }
+        let trashed = self.trash_path(id);
+        if let Some(dir) = trashed.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(path, trashed)
+    }
+
+    pub(crate) fn record_trash(
+        &self,
+        doc: &Document,
+        op_key: Option<&str>,
+    ) -> Result<(), StoreError> {
+        self.conn
+            .execute(
+                "INSERT INTO trash(document_id, title, deleted_at, op_key) VALUES(?1, ?2, ?3, ?4)
+                 ON CONFLICT(document_id) DO UPDATE SET title=excluded.title,
+                 deleted_at=excluded.deleted_at, op_key=excluded.op_key",
+                params![
+                    doc.frontmatter.id,
+                    doc.frontmatter.title,
+                    Utc::now().to_rfc3339(),
+                    op_key
+                ],
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Trashed documents, most recently deleted first.
+    pub fn list_trash(&self) -> Result<Vec<TrashEntry>, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT document_id, title, deleted_at, op_key FROM trash
+                 ORDER BY deleted_at DESC",
+            )
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(TrashEntry {
+                    document_id: row.get(0)?,
+                    title: row.get(1)?,
+                    deleted_at: row.get(2)?,
+                    op_key: row.get(3)?,
+                })
+            })
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let mut entries = Vec::new();
+        for r in rows {
+            let entry = r.map_err(|e| StoreError::Db(e.to_string()))?;
+            // a crash between recording and moving the file leaves a row without a file
+            if self.trash_path(&entry.document_id).exists() {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Bring a trashed document back as a new op authored by `device_id`: a create, or an
+    /// update if the id has been re-created since. Returns the document and the op to
+    /// log/sync.
+    pub fn restore(
+        &mut self,
+        id: &str,
+        device_id: &str,
+    ) -> Result<(Document, Operation), StoreError> {
+        let path = self.trash_path(id);
+        if !path.exists() {
+            return Err(StoreError::NotFound);
+        }
+        let raw = fs::read_to_string(&path).map_err(|e| StoreError::Io(e.to_string()))?;
+        let trashed = Document::from_markdown_with_id(&raw, id)
+            .map_err(|e| StoreError::Document(e.to_string()))?;
+        // a local delete's tombstone has no clock the restore could follow; the restore is
+        // the deliberate undo of that delete
+        if self.tombstone(id)?.is_some_and(|t| t.op_key.is_empty()) {
+            self.clear_tombstone(id)?;
+        }
+        let current = self.load_document(id)?;
+        let mut op = Operation {
+            op_id: notes_core::generate_id(),
+            device_id: device_id.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type: match current {
+                Some(_) => OperationType::UpdateDocument,
+                None => OperationType::CreateDocument,
+            },
+            document_id: id.to_string(),
+            payload: serde_json::json!({ "frontmatter": trashed.frontmatter, "body": trashed.body }),
+            before_hash: current.map(|d| d.hash_content()),
+            after_hash: None,
+            clock: self.next_clock(id, device_id)?,
+        };
+        let doc = self.apply(op.clone())?.ok_or(StoreError::NotFound)?;
+        op.after_hash = Some(doc.hash_content());
+        self.remove_from_trash(id)?;
+        Ok((doc, op))
+    }
+
+    /// Permanently delete trash entries deleted before `cutoff` (RFC 3339); returns how many.
+    pub fn purge_trash(&self, cutoff: &str) -> Result<usize, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT document_id FROM trash WHERE deleted_at < ?1")
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        let expired = stmt
+            .query_map(params![cutoff], |row| row.get::<_, String>(0))
+            .map_err(|e| StoreError::Db(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        for id in &expired {
+            self.remove_from_trash(id)?;
+        }
+        Ok(expired.len())
+    }
+
+    fn remove_from_trash(&self, id: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.trash_path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(StoreError::Io(e.to_string()))
+            }
+            _ => {}
+        }
+        self.conn
+            .execute("DELETE FROM trash WHERE document_id=?1", params![id])
+            .map_err(|e| StoreError::Db(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! External edits: `<id>.md` files changed by other programs (editors, git, file sync) are
+//! turned into the same ops an in-app edit produces. The index keeps the content hash of what
+//! the store last wrote for each document, so a file whose bytes still hash to that value is
+//! the store's own write and is ignored; anything else becomes a create, update or delete op
+//! authored by this device and is applied through `apply`, which rewrites the file in its
+//! canonical form. `VaultWatcher` reports which documents changed, debounced, and leaves
+//! locking and logging the ops to the caller.
+
+use crate::{Store, StoreError};
+use chrono::Utc;
+use notes_core::Document;
+use notes_oplog::{Operation, OperationType};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::{params, OptionalExtension};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// The document id of a vault file path, if it is a `<id>.md` directly in `root`.
+pub fn document_id_from_path(root: &Path, path: &Path) -> Option<String> {
+    if path.parent() != Some(root) {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".md")
+        .filter(|id| !id.is_empty() && !id.contains('.'))
+        .map(str::to_string)
+}
+
+impl Store {
+    /// Content hash of the version the index knows; `None` if the document isn't indexed.
+    fn indexed_hash(&self, id: &str) -> Result<Option<String>, StoreError> {
+        self.conn
+            .query_row(
+                "SELECT hash FROM documents WHERE id=?1",
+                params![id],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map(|hash| hash.map(|h| h.unwrap_or_default()))
+            .map_err(|e| StoreError::Db(e.to_string()))
+    }
+
+    /// Compare `<id>.md` with the indexed version and, if another program changed it, apply
+    /// and return the op that records the change.
+    pub fn ingest_external_change(
+        &mut self,
+        id: &str,
+        device_id: &str,
+    ) -> Result<Option<Operation>, StoreError> {
+        let indexed = self.indexed_hash(id)?;
+        let path = self.doc_path(id);
+        let (op_type, payload) = if path.exists() {
+            let raw = fs::read(&path).map_err(|e| StoreError::Io(e.to_string()))?;
+            let text = String::from_utf8(raw).map_err(|e| StoreError::Document(e.to_string()))?;
+            let doc = Document::from_markdown_with_id(&text, id)
+                .map_err(|e| StoreError::Document(e.to_string()))?;
+            if doc.frontmatter.id != id {
+                return Err(StoreError::Document(format!(
+                    "frontmatter id {} doesn't match the file name",
+                    doc.frontmatter.id
+                )));
+            }
+            // the same content, possibly written in another frontmatter format or layout
+            if indexed.as_deref().is_some_and(|h| doc.matches_hash(h)) {
+                return Ok(None);
+            }
+            let frontmatter = serde_yaml::to_value(&doc.frontmatter)
+                .map_err(|e| StoreError::Document(e.to_string()))?;
+            let op_type = match indexed {
+                Some(_) => OperationType::UpdateDocument,
+                None => OperationType::CreateDocument,
+            };
+            let payload = serde_json::json!({ "frontmatter": frontmatter, "body": doc.body });
+            (op_type, payload)
+        } else if indexed.is_some() {
+            (OperationType::DeleteDocument, serde_json::Value::Null)
+        } else {
+            return Ok(None);
+        };
+
+        let mut op = Operation {
+            op_id: notes_core::generate_id(),
+            device_id: device_id.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            op_type,
+            document_id: id.to_string(),
+            payload,
+            before_hash: indexed.filter(|h| !h.is_empty()),
+            after_hash: None,
+            clock: self.next_clock(id, device_id)?,
+        };
+        if !matches!(op.op_type, OperationType::DeleteDocument) {
+            op.after_hash = Some(self.materialize(&op)?.hash_content());
+        }
+        self.apply(op.clone())?;
+        Ok(Some(op))
+    }
+
+    /// Ingest every document changed behind the store's back, e.g. while the app was closed.
+    /// Files that don't parse are skipped (see [`Store::reindex`] for reporting them).
+    pub fn scan_external_changes(&mut self, device_id: &str) -> Result<Vec<Operation>, StoreError> {
+        let mut ids = self.document_ids_on_disk()?;
+        ids.extend(self.list_documents()?.into_iter().map(|d| d.id));
+        let mut ops = Vec::new();
+        for id in ids {
+            match self.ingest_external_change(&id, device_id) {
+                Ok(Some(op)) => ops.push(op),
+                Ok(None) | Err(StoreError::Document(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ops)
+    }
+}
+
+/// Watches a vault root and calls back with the ids of documents whose files changed, once
+/// no further change arrived for the debounce interval. Stops when dropped.
+pub struct VaultWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl VaultWatcher {
+    pub fn start(
+        root: impl AsRef<Path>,
+        debounce: Duration,
+        mut on_change: impl FnMut(BTreeSet<String>) + Send + 'static,
+    ) -> Result<Self, StoreError> {
+        let root = fs::canonicalize(root).map_err(|e| StoreError::Io(e.to_string()))?;
+        let (tx, rx) = mpsc::channel::<notify::Result<notify::Event>>();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .map_err(|e| StoreError::Io(e.to_string()))?;
+        watcher
+            .watch(&root, RecursiveMode::NonRecursive)
+            .map_err(|e| StoreError::Io(e.to_string()))?;
+
+        std::thread::spawn(move || {
+            let collect = |event: notify::Result<notify::Event>, ids: &mut BTreeSet<String>| {
+                let Ok(event) = event else { return };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                ids.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|p| document_id_from_path(&root, p)),
+                );
+            };
+            // ends once the watcher (and with it the sender) is dropped
+            while let Ok(event) = rx.recv() {
+                let mut ids = BTreeSet::new();
+                collect(event, &mut ids);
+                while let Ok(event) = rx.recv_timeout(debounce) {
+                    collect(event, &mut ids);
+                }
+                if !ids.is_empty() {
+                    on_change(ids);
+                }
+            }
+        });
+        Ok(Self { _watcher: watcher })
+    }
+}
@@ -0,0 +1,238 @@
+use crate::SyncError;
+use notes_oplog::{Checkpoint, Operation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Anti-entropy summaries: instead of shipping the whole op-log, peers first exchange a
+// per-device range summary (op count, highest op id, digest over the op keys) and then only
+// send the ops the other side is missing. Op ids are ULIDs, so ordering by op id within one
+// device follows creation order. A summary also carries the peer's compaction checkpoint: ops
+// it folded into snapshots are neither sent to it nor counted when comparing with it.
+//
+// Summaries also carry the sender's protocol version. Version 2 switched op hashes to tagged
+// v1 content hashes, which version-1 peers compare as opaque strings and would turn every
+// edit into a conflict, so no ops are sent to a peer that reports an older version.
+
+/// Sync protocol version; 2 means `before_hash`/`after_hash` are v1 content hashes.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// What a peer holds for one origin device.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DeviceRange {
+    pub count: u64,
+    /// High-water mark: the largest op id seen from this device.
+    pub max_op_id: String,
+    /// sha256 over the sorted op ids (newline separated).
+    pub digest: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    pub devices: BTreeMap<String, DeviceRange>,
+    #[serde(default, skip_serializing_if = "Checkpoint::is_empty")]
+    pub checkpoint: Checkpoint,
+    /// The sender's [`PROTOCOL_VERSION`]; 1 for peers from before it was sent, 0 on a
+    /// placeholder summary for a peer that couldn't report one.
+    #[serde(default = "unversioned")]
+    pub protocol: u32,
+}
+
+fn unversioned() -> u32 {
+    1
+}
+
+impl SyncSummary {
+    pub fn from_ops(ops: &[Operation]) -> Self {
+        let devices = ops_by_device(ops)
+            .into_iter()
+            .map(|(device, ids)| {
+                let range = DeviceRange {
+                    count: ids.len() as u64,
+                    max_op_id: ids.last().map(|id| id.to_string()).unwrap_or_default(),
+                    digest: digest_ids(&ids),
+                };
+                (device.to_string(), range)
+            })
+            .collect();
+        Self {
+            devices,
+            checkpoint: Checkpoint::default(),
+            protocol: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Refuse a peer whose summary reports an older protocol version.
+    pub fn check_protocol(&self) -> Result<(), SyncError> {
+        if self.protocol != 0 && self.protocol < PROTOCOL_VERSION {
+            return Err(SyncError::Invalid(format!(
+                "peer speaks sync protocol {}, this device needs {PROTOCOL_VERSION}",
+                self.protocol
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+/// Ops from `local` that a peer with `remote` summary lacks, in log order.
+///
+/// Per device: identical digests mean nothing to send; if the peer's digest matches our ops
+/// up to its high-water mark it only lacks the ops after that mark; otherwise its history
+/// has holes and every op from that device is sent (the receiver deduplicates).
+pub fn missing_ops(local: &[Operation], remote: &SyncSummary) -> Vec<Operation> {
+    let local: Vec<&Operation> = local
+        .iter()
+        .filter(|op| !remote.checkpoint.covers(op))
+        .collect();
+    let mut send_all: Vec<&str> = Vec::new();
+    let mut send_after: BTreeMap<&str, &str> = BTreeMap::new();
+    for (device, ids) in ops_by_device(local.iter().copied()) {
+        match remote.devices.get(device) {
+            None => send_all.push(device),
+            Some(range) if range.digest == digest_ids(&ids) => {}
+            Some(range) => {
+                let prefix: Vec<&str> = ids
+                    .iter()
+                    .copied()
+                    .filter(|id| *id <= range.max_op_id.as_str())
+                    .collect();
+                if prefix.len() as u64 == range.count && digest_ids(&prefix) == range.digest {
+                    send_after.insert(device, range.max_op_id.as_str());
+                } else {
+                    send_all.push(device);
+                }
+            }
+        }
+    }
+    local
+        .iter()
+        .filter(|op| {
+            let device = op.device_id.as_str();
+            send_all.contains(&device)
+                || send_after
+                    .get(device)
+                    .map(|mark| op.op_id.as_str() > *mark)
+                    .unwrap_or(false)
+        })
+        .map(|op| (*op).clone())
+        .collect()
+}
+
+fn ops_by_device<'a>(
+    ops: impl IntoIterator<Item = &'a Operation>,
+) -> BTreeMap<&'a str, Vec<&'a str>> {
+    let mut by_device: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for op in ops {
+        by_device
+            .entry(op.device_id.as_str())
+            .or_default()
+            .push(op.op_id.as_str());
+    }
+    for ids in by_device.values_mut() {
+        ids.sort_unstable();
+        ids.dedup();
+    }
+    by_device
+}
+
+fn digest_ids(ids: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for id in ids {
+        hasher.update(id.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notes_oplog::{OperationType, VectorClock};
+
+    fn op(device: &str, id: &str) -> Operation {
+        Operation {
+            op_id: id.into(),
+            device_id: device.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: OperationType::CreateDocument,
+            document_id: "doc".into(),
+            payload: serde_json::Value::Null,
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        }
+    }
+
+    fn keys(ops: &[Operation]) -> Vec<String> {
+        ops.iter().map(|o| o.key()).collect()
+    }
+
+    #[test]
+    fn sends_only_what_the_peer_lacks() {
+        let local = vec![op("a", "01"), op("a", "02"), op("a", "03"), op("b", "01")];
+
+        // peer is in sync
+        let summary = SyncSummary::from_ops(&local);
+        assert!(missing_ops(&local, &summary).is_empty());
+
+        // peer is behind on device a and has never seen b
+        let summary = SyncSummary::from_ops(&local[..1]);
+        assert_eq!(
+            keys(&missing_ops(&local, &summary)),
+            vec!["a:02", "a:03", "b:01"]
+        );
+
+        // peer has a hole (a:02 missing): resend everything from a
+        let summary = SyncSummary::from_ops(&[op("a", "01"), op("a", "03"), op("b", "01")]);
+        assert_eq!(
+            keys(&missing_ops(&local, &summary)),
+            vec!["a:01", "a:02", "a:03"]
+        );
+
+        // empty summary (old peer): full push
+        assert_eq!(missing_ops(&local, &SyncSummary::default()).len(), 4);
+    }
+
+    #[test]
+    fn peers_get_snapshots_instead_of_compacted_ops() {
+        let mut local = vec![op("a", "01"), op("a", "02"), op("a", "03")];
+        for (i, op) in local.iter_mut().enumerate() {
+            for _ in 0..=i {
+                op.clock.increment("a");
+            }
+        }
+        // the peer folded a:01 and a:02 into a:03
+        let mut checkpoint = Checkpoint::default();
+        checkpoint
+            .documents
+            .insert("doc".into(), local[2].clock.clone());
+        let peer = SyncSummary::from_ops(&local[2..]).with_checkpoint(checkpoint.clone());
+        assert!(missing_ops(&local, &peer).is_empty());
+
+        let peer = SyncSummary::default().with_checkpoint(checkpoint);
+        assert_eq!(keys(&missing_ops(&local, &peer)), vec!["a:03"]);
+    }
+
+    #[test]
+    fn peers_before_v1_hashes_are_refused() {
+        let old: SyncSummary = serde_json::from_str(r#"{"devices":{}}"#).unwrap();
+        assert_eq!(old.protocol, 1);
+        assert!(old.check_protocol().is_err());
+
+        let current: SyncSummary =
+            serde_json::from_slice(&serde_json::to_vec(&SyncSummary::from_ops(&[])).unwrap())
+                .unwrap();
+        assert_eq!(current.protocol, PROTOCOL_VERSION);
+        assert!(current.check_protocol().is_ok());
+        assert!(SyncSummary::default().check_protocol().is_ok());
+    }
+}
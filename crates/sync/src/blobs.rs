@@ -0,0 +1,440 @@
+use crate::session::{read_frame, write_frame, FrameChannel};
+use crate::SyncError;
+use base64::Engine;
+use notes_oplog::{Operation, OperationType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Attachment blobs travel next to the ops, after the op exchange of a session, when both
+// sides announced a blob directory in their hello. Each side in turn asks for the blobs its
+// (now merged) ops reference but it lacks, with the offset it already holds, and the other
+// side streams them:
+//
+//   receiver                           sender
+//   Want{[(hash, offset)]}      --->
+//                               <---   Chunk{hash, offset, total, data} ...
+//                               <---   Done
+//
+// Chunks are at most `CHUNK_SIZE` bytes, so a large file never becomes one giant frame. They
+// are appended to `<hash>.download`, which survives an interrupted session so the next one
+// resumes from its length; once the last chunk arrived the file's sha256 must equal its name
+// before it is renamed into place. A chunk whose `total` differs from the size the
+// `AttachFile` op declared is refused, so a peer can't make us store more than that.
+
+pub(crate) const CHUNK_SIZE: usize = 256 * 1024;
+const DOWNLOAD_EXT: &str = "download";
+
+/// A blob the receiver wants, and how many bytes of it it already has.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BlobRequest {
+    pub hash: String,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlobFrame {
+    Want {
+        blobs: Vec<BlobRequest>,
+    },
+    Chunk {
+        hash: String,
+        offset: u64,
+        total: u64,
+        /// Base64 encoded bytes.
+        data: String,
+    },
+    Done,
+}
+
+/// Hashes of the blobs referenced by `AttachFile` ops, deduplicated.
+pub fn referenced_blobs(ops: &[Operation]) -> Vec<String> {
+    ops.iter()
+        .filter(|op| matches!(op.op_type, OperationType::AttachFile))
+        .filter_map(|op| op.payload.get("hash")?.as_str().map(str::to_string))
+        .filter(|hash| is_blob_hash(hash))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// The size each `AttachFile` op in `ops` declares for its blob.
+fn declared_sizes(ops: &[Operation]) -> BTreeMap<String, u64> {
+    ops.iter()
+        .filter(|op| matches!(op.op_type, OperationType::AttachFile))
+        .filter_map(|op| {
+            let hash = op.payload.get("hash")?.as_str()?;
+            let size = op.payload.get("size")?.as_u64()?;
+            Some((hash.to_string(), size))
+        })
+        .collect()
+}
+
+fn is_blob_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Content-addressed blob directory (`<vault>/attachments`), files named by their sha256.
+#[derive(Clone, Debug)]
+pub struct BlobDir {
+    root: PathBuf,
+}
+
+impl BlobDir {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, hash: &str) -> Result<PathBuf, SyncError> {
+        if !is_blob_hash(hash) {
+            return Err(SyncError::Invalid(format!("invalid blob hash {hash}")));
+        }
+        Ok(self.root.join(hash))
+    }
+
+    fn download_path(&self, hash: &str) -> Result<PathBuf, SyncError> {
+        Ok(self.path(hash)?.with_extension(DOWNLOAD_EXT))
+    }
+
+    pub fn has(&self, hash: &str) -> bool {
+        self.path(hash).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// Bytes of `hash` already downloaded by an earlier, interrupted transfer.
+    pub fn partial_len(&self, hash: &str) -> u64 {
+        self.download_path(hash)
+            .and_then(|p| fs::metadata(p).map_err(|e| SyncError::Io(e.to_string())))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    /// What to ask a peer for: blobs referenced by `ops` that aren't here yet.
+    pub fn wanted(&self, ops: &[Operation]) -> Vec<BlobRequest> {
+        referenced_blobs(ops)
+            .into_iter()
+            .filter(|hash| !self.has(hash))
+            .map(|hash| BlobRequest {
+                offset: self.partial_len(&hash),
+                hash,
+            })
+            .collect()
+    }
+
+    /// Up to `CHUNK_SIZE` bytes of `hash` from `offset`, plus the blob's total size; `None`
+    /// if we don't have it.
+    fn read_chunk(&self, hash: &str, offset: u64) -> Result<Option<(Vec<u8>, u64)>, SyncError> {
+        let path = self.path(hash)?;
+        let Ok(mut file) = fs::File::open(path) else {
+            return Ok(None);
+        };
+        let total = file
+            .metadata()
+            .map_err(|e| SyncError::Io(e.to_string()))?
+            .len();
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(offset.min(total)))
+            .and_then(|_| file.take(CHUNK_SIZE as u64).read_to_end(&mut data))
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        Ok(Some((data, total)))
+    }
+
+    /// Write a received chunk at `offset`. Returns `true` once the blob is complete, verified
+    /// and in place; a blob whose contents don't match its hash is discarded.
+    fn write_chunk(
+        &self,
+        hash: &str,
+        offset: u64,
+        data: &[u8],
+        total: u64,
+    ) -> Result<bool, SyncError> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(|| SyncError::Invalid(format!("blob {hash} overruns its size")))?;
+        if end > total {
+            return Err(SyncError::Invalid(format!("blob {hash} overruns its size")));
+        }
+        fs::create_dir_all(&self.root).map_err(|e| SyncError::Io(e.to_string()))?;
+        let download = self.download_path(hash)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&download)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        let len = file
+            .metadata()
+            .map_err(|e| SyncError::Io(e.to_string()))?
+            .len();
+        if offset > len {
+            return Err(SyncError::Invalid(format!("gap in blob {hash}")));
+        }
+        file.set_len(offset)
+            .and_then(|_| file.seek(SeekFrom::Start(offset)))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        if end < total {
+            return Ok(false);
+        }
+        drop(file);
+
+        let mut hasher = Sha256::new();
+        let mut file = fs::File::open(&download).map_err(|e| SyncError::Io(e.to_string()))?;
+        std::io::copy(&mut file, &mut hasher).map_err(|e| SyncError::Io(e.to_string()))?;
+        if format!("{:x}", hasher.finalize()) != hash {
+            let _ = fs::remove_file(&download);
+            return Err(SyncError::Crypto(format!(
+                "blob {hash} failed verification"
+            )));
+        }
+        fs::rename(&download, self.path(hash)?).map_err(|e| SyncError::Io(e.to_string()))?;
+        Ok(true)
+    }
+}
+
+/// Ask the peer for the blobs `ops` reference that we lack and store what it streams back.
+/// Returns the number of blobs completed.
+pub(crate) fn receive_blobs(
+    channel: &mut dyn FrameChannel,
+    dir: &BlobDir,
+    ops: &[Operation],
+) -> Result<usize, SyncError> {
+    let wanted = dir.wanted(ops);
+    let sizes = declared_sizes(ops);
+    let requested: BTreeSet<String> = wanted.iter().map(|b| b.hash.clone()).collect();
+    write_frame(channel, &BlobFrame::Want { blobs: wanted })?;
+    let mut completed = 0;
+    loop {
+        match read_frame(channel)? {
+            BlobFrame::Chunk {
+                hash,
+                offset,
+                total,
+                data,
+            } => {
+                if !requested.contains(&hash) {
+                    return Err(SyncError::Invalid(format!("unrequested blob {hash}")));
+                }
+                if sizes.get(&hash) != Some(&total) {
+                    return Err(SyncError::Invalid(format!(
+                        "blob {hash} doesn't match its declared size"
+                    )));
+                }
+                let data = base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|e| SyncError::Invalid(e.to_string()))?;
+                if dir.write_chunk(&hash, offset, &data, total)? {
+                    completed += 1;
+                }
+            }
+            BlobFrame::Done => return Ok(completed),
+            _ => return Err(SyncError::HandshakeFailed),
+        }
+    }
+}
+
+/// Answer the peer's `Want` by streaming every requested blob we have. Returns the
+/// number of blobs sent.
+pub(crate) fn send_blobs(
+    channel: &mut dyn FrameChannel,
+    dir: &BlobDir,
+) -> Result<usize, SyncError> {
+    let wanted = match read_frame(channel)? {
+        BlobFrame::Want { blobs } => blobs,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    let mut sent = 0;
+    for request in wanted {
+        let mut offset = request.offset;
+        // always send at least one chunk so a fully downloaded but unverified blob completes
+        while let Some((data, total)) = dir.read_chunk(&request.hash, offset)? {
+            let start = offset.min(total);
+            offset = start + data.len() as u64;
+            write_frame(
+                channel,
+                &BlobFrame::Chunk {
+                    hash: request.hash.clone(),
+                    offset: start,
+                    total,
+                    data: base64::engine::general_purpose::STANDARD.encode(&data),
+                },
+            )?;
+            if offset >= total || data.is_empty() {
+                sent += 1;
+                break;
+            }
+        }
+    }
+    write_frame(channel, &BlobFrame::Done)?;
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::PskChannel;
+    use crate::{DeviceIdentity, NetTransport, SyncAck, Transport, TrustStore};
+    use notes_oplog::VectorClock;
+    use std::net::TcpListener;
+    use tempfile::tempdir;
+
+    fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn attach(device: &str, blob: &[u8]) -> Operation {
+        Operation {
+            op_id: "01".into(),
+            device_id: device.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: OperationType::AttachFile,
+            document_id: "doc".into(),
+            payload: serde_json::json!({ "hash": hash(blob), "name": "file.pdf", "size": blob.len() }),
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        }
+    }
+
+    #[test]
+    fn chunks_resume_and_are_verified() {
+        let dir = tempdir().unwrap();
+        let blobs = BlobDir::new(dir.path());
+        let data = vec![7u8; CHUNK_SIZE + 10];
+        let h = hash(&data);
+
+        assert!(!blobs
+            .write_chunk(&h, 0, &data[..100], data.len() as u64)
+            .unwrap());
+        assert_eq!(blobs.partial_len(&h), 100);
+        assert!(blobs
+            .write_chunk(&h, 200, &data[200..], data.len() as u64)
+            .is_err());
+        assert!(blobs
+            .write_chunk(&h, 100, &data[100..], data.len() as u64)
+            .unwrap());
+        assert!(blobs.has(&h));
+        assert_eq!(blobs.partial_len(&h), 0);
+        assert_eq!(fs::read(dir.path().join(&h)).unwrap(), data);
+
+        // contents that don't match the name are thrown away
+        let other = hash(b"other");
+        assert!(blobs.write_chunk(&other, 0, b"forged", 6).is_err());
+        assert!(!blobs.has(&other));
+        assert_eq!(blobs.partial_len(&other), 0);
+        assert!(blobs.write_chunk("../escape", 0, b"x", 1).is_err());
+        assert!(matches!(
+            blobs.write_chunk(&h, u64::MAX - 1, b"xyz", u64::MAX),
+            Err(SyncError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn chunks_must_match_the_declared_size() {
+        let dir = tempdir().unwrap();
+        let (client, server) = (BlobDir::new(dir.path().join("a")), dir.path().join("b"));
+        let blob = b"attachment".to_vec();
+        fs::create_dir_all(&server).unwrap();
+        fs::write(server.join(hash(&blob)), &blob).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let transport = NetTransport::new(0, 0);
+            let mut channel = PskChannel::new(&transport, &mut stream);
+            let _ = send_blobs(&mut channel, &BlobDir::new(server));
+        });
+        let mut understated = attach("b", &blob);
+        understated.payload["size"] = 4.into();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let transport = NetTransport::new(0, 0);
+        let mut channel = PskChannel::new(&transport, &mut stream);
+        let err = receive_blobs(&mut channel, &client, &[understated]).unwrap_err();
+        assert!(matches!(err, SyncError::Invalid(_)));
+        assert!(!client.has(&hash(&blob)));
+        assert_eq!(client.partial_len(&hash(&blob)), 0);
+        drop(stream);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn sessions_stream_missing_blobs_both_ways() {
+        let dir = tempdir().unwrap();
+        let (client_id, server_id) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let mut client_trust = TrustStore::load_or_default(dir.path().join("client.json")).unwrap();
+        client_trust
+            .add(server_id.device_id.clone(), server_id.public_key.clone())
+            .unwrap();
+        let mut server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+        server_trust
+            .add(client_id.device_id.clone(), client_id.public_key.clone())
+            .unwrap();
+
+        // the client's blob spans several chunks and the server already holds a prefix of it
+        let pdf: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+        let png = b"png bytes".to_vec();
+        let (client_blobs, server_blobs) = (dir.path().join("a"), dir.path().join("b"));
+        fs::create_dir_all(&client_blobs).unwrap();
+        fs::create_dir_all(&server_blobs).unwrap();
+        fs::write(client_blobs.join(hash(&pdf)), &pdf).unwrap();
+        fs::write(server_blobs.join(hash(&png)), &png).unwrap();
+        BlobDir::new(&server_blobs)
+            .write_chunk(&hash(&pdf), 0, &pdf[..1000], pdf.len() as u64)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new(0, addr.port()).with_blob_dir(&client_blobs);
+        let server = NetTransport::new(0, 0).with_blob_dir(&server_blobs);
+        let handle = std::thread::spawn(move || {
+            let server_ops = std::sync::Mutex::new(vec![attach("b", &png)]);
+            let (stream, peer) = listener.accept().unwrap();
+            server.serve_connection(
+                stream,
+                peer,
+                &server_id,
+                &server_trust,
+                || server_ops.lock().unwrap().clone(),
+                |_, ops| {
+                    let accepted = ops.iter().map(|o| o.key()).collect();
+                    server_ops.lock().unwrap().extend(ops);
+                    SyncAck {
+                        accepted,
+                        rejected: Vec::new(),
+                        superseded: Vec::new(),
+                    }
+                },
+            )
+        });
+
+        let report = transport
+            .session(
+                &addr.to_string(),
+                &client_id,
+                &client_trust,
+                &[attach("a", &pdf)],
+                &mut |ops| SyncAck {
+                    accepted: ops.iter().map(|o| o.key()).collect(),
+                    rejected: Vec::new(),
+                    superseded: Vec::new(),
+                },
+            )
+            .unwrap();
+        handle.join().unwrap().unwrap();
+
+        assert_eq!((report.blobs_received, report.blobs_sent), (1, 1));
+        assert_eq!(fs::read(server_blobs.join(hash(&pdf))).unwrap(), pdf);
+        assert_eq!(
+            fs::read(client_blobs.join(hash(b"png bytes"))).unwrap(),
+            b"png bytes"
+        );
+        assert_eq!(BlobDir::new(&server_blobs).partial_len(&hash(&pdf)), 0);
+    }
+}
@@ -1,16 +1,29 @@
 use base64::Engine;
 use chacha20poly1305::{aead::Aead, aead::KeyInit, ChaCha20Poly1305, Key, Nonce};
 use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use notes_oplog::Checkpoint;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs;
-use std::io::Write;
-use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use thiserror::Error;
 
+mod antientropy;
+mod blobs;
+mod noise;
+mod pairing;
+mod session;
+
+pub use antientropy::{missing_ops, DeviceRange, SyncSummary, PROTOCOL_VERSION};
+pub use blobs::{referenced_blobs, BlobDir, BlobRequest};
+pub use noise::{x25519_public_key, NoiseTransport};
+pub use pairing::{PairingCode, PendingPairing};
+pub use session::{RejectReason, RejectedOp, SyncAck, SyncReport};
+
 // Network sync layer: discovery (UDP), sync handshakes (TCP), optional PSK crypto,
 // device identity/trust management, and sync envelopes with signatures.
 
@@ -47,6 +60,49 @@ pub enum SyncError {
 pub trait Transport {
     fn advertise(&self) -> Result<(), SyncError>;
     fn request_sync(&self, target_device: &str, envelope: &SyncEnvelope) -> Result<(), SyncError>;
+
+    /// Ask a peer which ops it already holds. Transports that can't answer return an empty
+    /// summary, which makes the caller fall back to sending everything.
+    fn fetch_summary(
+        &self,
+        _target_device: &str,
+        _requester: &DeviceIdentity,
+    ) -> Result<SyncSummary, SyncError> {
+        Ok(SyncSummary::default())
+    }
+
+    /// Exchange ops with a peer in both directions. Pulled ops are handed to `apply`, whose
+    /// verdict is reported back to the peer. The default is a push-only fallback built on
+    /// `fetch_summary`/`request_sync` that gets no ack and pulls nothing.
+    fn session(
+        &self,
+        target_device: &str,
+        identity: &DeviceIdentity,
+        _trust: &TrustStore,
+        local_ops: &[notes_oplog::Operation],
+        _apply: &mut dyn FnMut(Vec<notes_oplog::Operation>) -> SyncAck,
+    ) -> Result<SyncReport, SyncError> {
+        let summary = self
+            .fetch_summary(target_device, identity)
+            .unwrap_or_default();
+        summary.check_protocol()?;
+        let ops = missing_ops(local_ops, &summary);
+        let sent = ops.len();
+        if sent > 0 {
+            let payload = signing_bytes(&ops)?;
+            let envelope = SyncEnvelope {
+                device_id: identity.device_id.clone(),
+                public_key: identity.public_key.clone(),
+                signature: identity.sign(&payload)?,
+                ops,
+            };
+            self.request_sync(target_device, &envelope)?;
+        }
+        Ok(SyncReport {
+            sent,
+            ..SyncReport::default()
+        })
+    }
 }
 
 pub struct SyncService<T: Transport> {
@@ -65,18 +121,40 @@ impl<T: Transport> SyncService<T> {
         }
     }
 
-    /// Advertise then send an envelope to a peer.
-    pub fn pair_and_sync(&self, target_device: &str) -> Result<(), SyncError> {
+    /// Advertise, fetch the peer's summary, then send an envelope with only the ops it lacks.
+    /// Returns the number of ops sent; peers that can't report a summary get everything.
+    pub fn pair_and_sync(&self, target_device: &str) -> Result<usize, SyncError> {
         self.transport.advertise()?;
-        let payload = serde_json::to_vec(&self.ops).map_err(|e| SyncError::Io(e.to_string()))?;
+        let summary = self
+            .transport
+            .fetch_summary(target_device, &self.device)
+            .unwrap_or_default();
+        summary.check_protocol()?;
+        let ops = missing_ops(&self.ops, &summary);
+        if ops.is_empty() {
+            return Ok(0);
+        }
+        let payload = signing_bytes(&ops)?;
         let envelope = SyncEnvelope {
             device_id: self.device.device_id.clone(),
             public_key: self.device.public_key.clone(),
             signature: self.device.sign(&payload)?,
-            ops: self.ops.clone(),
+            ops,
         };
         self.transport.request_sync(target_device, &envelope)?;
-        Ok(())
+        Ok(envelope.ops.len())
+    }
+
+    /// Two-way sync with a trusted peer: push what it lacks, pull what we lack (handed to
+    /// `apply`), and report both sides' verdicts.
+    pub fn sync(
+        &self,
+        target_device: &str,
+        trust: &TrustStore,
+        mut apply: impl FnMut(Vec<notes_oplog::Operation>) -> SyncAck,
+    ) -> Result<SyncReport, SyncError> {
+        self.transport
+            .session(target_device, &self.device, trust, &self.ops, &mut apply)
     }
 
     pub fn device(&self) -> &DeviceIdentity {
@@ -89,6 +167,8 @@ pub struct NetTransport {
     discovery_port: u16,
     sync_port: u16,
     psk: Option<[u8; 32]>,
+    blob_dir: Option<BlobDir>,
+    checkpoint: Checkpoint,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -113,7 +193,21 @@ impl NetTransport {
 
     /// Construct a transport with optional 32-byte PSK for encrypted envelopes.
     pub fn new_with_psk(discovery_port: u16, sync_port: u16, psk: Option<[u8; 32]>) -> Self {
-        Self { discovery_port, sync_port, psk }
+        Self { discovery_port, sync_port, psk, blob_dir: None, checkpoint: Checkpoint::default() }
+    }
+
+    /// Exchange attachment blobs from `dir` (the vault's content-addressed attachment
+    /// directory) after the ops in sync sessions.
+    pub fn with_blob_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.blob_dir = Some(BlobDir::new(dir));
+        self
+    }
+
+    /// Announce the local op-log's compaction checkpoint in sync summaries, so peers send
+    /// snapshots instead of the ops it folded away.
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = checkpoint;
+        self
     }
 
     pub fn listen_discovery(&self, timeout: Duration) -> Result<Vec<DiscoveredPeer>, SyncError> {
@@ -184,71 +278,224 @@ impl NetTransport {
         });
     }
 
-    pub fn serve_once(
-        &self,
-        trust_path: &Path,
-        op_handler: impl Fn(SocketAddr, Vec<notes_oplog::Operation>),
-    ) -> Result<(), SyncError> {
+    /// Bind the sync port. The listener is non-blocking so `serve_once` can poll it; keep it
+    /// around between calls so peers connecting in between aren't refused.
+    pub fn listen(&self) -> Result<TcpListener, SyncError> {
         let listener = TcpListener::bind(("0.0.0.0", self.sync_port))
             .map_err(|e| SyncError::Io(e.to_string()))?;
         listener
             .set_nonblocking(true)
             .map_err(|e| SyncError::Io(e.to_string()))?;
-        if let Ok((mut stream, addr)) = listener.accept() {
-            let mut buf = Vec::new();
-            std::io::copy(&mut stream, &mut buf).map_err(|e| SyncError::Io(e.to_string()))?;
-            let payload = if let Some(psk) = self.psk {
-                if buf.len() < 13 {
-                    return Err(SyncError::Crypto("encrypted payload too short".into()));
-                }
-                let version = buf[0];
-                if version != 1 {
-                    return Err(SyncError::Crypto("unsupported crypto version".into()));
-                }
-                let nonce_bytes: [u8; 12] = buf[1..13]
-                    .try_into()
-                    .map_err(|_| SyncError::Crypto("bad nonce".into()))?;
-                let cipher = ChaCha20Poly1305::new(Key::from_slice(&psk));
-                cipher
-                    .decrypt(Nonce::from_slice(&nonce_bytes), &buf[13..])
-                    .map_err(|e| SyncError::Crypto(e.to_string()))?
-            } else {
-                buf
-            };
-            let envelope: SyncEnvelope =
-                serde_json::from_slice(&payload).map_err(|e| SyncError::Io(e.to_string()))?;
-            let trust = TrustStore::load_or_default(trust_path)
-                .map_err(|e| SyncError::Io(e.to_string()))?;
-            if !trust.is_trusted(&envelope.device_id, &envelope.public_key) {
-                return Err(SyncError::NotTrusted);
+        Ok(listener)
+    }
+
+    /// Accept at most one pending connection on `listener` and serve it with
+    /// [`NetTransport::serve_connection`], checking peers against the trust store at
+    /// `trust_path`. Returns an incoming pairing request, if that's what the peer sent.
+    pub fn serve_once(
+        &self,
+        listener: &TcpListener,
+        trust_path: &Path,
+        identity: &DeviceIdentity,
+        local_ops: impl Fn() -> Vec<notes_oplog::Operation>,
+        op_handler: impl FnMut(SocketAddr, Vec<notes_oplog::Operation>) -> SyncAck,
+    ) -> Result<Option<PendingPairing>, SyncError> {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let trust = TrustStore::load_or_default(trust_path)?;
+                self.serve_connection(stream, addr, identity, &trust, local_ops, op_handler)
             }
-            let vk_bytes =
-                hex::decode(&envelope.public_key).map_err(|e| SyncError::Io(e.to_string()))?;
-            let vk = PublicKey::from_bytes(
-                vk_bytes
-                    .as_slice()
-                    .try_into()
-                    .map_err(|_| SyncError::Invalid("bad key".into()))?,
-            )
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Serve one connection: a session over Noise or PSK framing (pushed ops go through
+    /// `op_handler`, whose ack is returned to the peer, then the peer is sent what it lacks
+    /// from `local_ops`), a pairing request (returned once its code is ready, see
+    /// [`PendingPairing`]), or a legacy one-shot message (summary request or bare envelope, no
+    /// ack).
+    pub fn serve_connection(
+        &self,
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        identity: &DeviceIdentity,
+        trust: &TrustStore,
+        local_ops: impl Fn() -> Vec<notes_oplog::Operation>,
+        mut op_handler: impl FnMut(SocketAddr, Vec<notes_oplog::Operation>) -> SyncAck,
+    ) -> Result<Option<PendingPairing>, SyncError> {
+        stream
+            .set_nonblocking(false)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+        stream.set_write_timeout(Some(Duration::from_secs(5))).ok();
+        let mut buf = Vec::new();
+        (&mut stream)
+            .take(session::SESSION_MAGIC.len() as u64)
+            .read_to_end(&mut buf)
             .map_err(|e| SyncError::Io(e.to_string()))?;
-            let sig_bytes = base64::engine::general_purpose::STANDARD
-                .decode(&envelope.signature)
-                .map_err(|e| SyncError::Io(e.to_string()))?;
-            let sig = Signature::from_bytes(
-                sig_bytes
-                    .as_slice()
-                    .try_into()
-                    .map_err(|_| SyncError::Invalid("bad sig".into()))?,
+        if buf == session::SESSION_MAGIC {
+            // the peer applies pulled ops before its final ack
+            stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+            let mut channel = session::PskChannel::new(self, &mut stream);
+            return session::serve_session(
+                &mut channel,
+                addr,
+                identity,
+                trust,
+                local_ops,
+                op_handler,
+                self,
             )
+            .map(|_| None);
+        }
+        if buf == noise::NOISE_MAGIC {
+            stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+            let mut channel = noise::accept(stream, identity)?;
+            channel.verify_if_trusted(trust);
+            return session::serve_session(
+                &mut channel,
+                addr,
+                identity,
+                trust,
+                local_ops,
+                op_handler,
+                self,
+            )
+            .map(|_| None);
+        }
+        if buf == pairing::PAIRING_MAGIC {
+            return pairing::respond(stream, identity).map(Some);
+        }
+
+        stream
+            .read_to_end(&mut buf)
             .map_err(|e| SyncError::Io(e.to_string()))?;
-            let payload =
-                serde_json::to_vec(&envelope.ops).map_err(|e| SyncError::Io(e.to_string()))?;
-            vk.verify(&payload, &sig)
-                .map_err(|_| SyncError::NotTrusted)?;
-            op_handler(addr, envelope.ops);
+        let payload = self.open(&buf)?;
+        let request: SyncRequest =
+            serde_json::from_slice(&payload).map_err(|e| SyncError::Io(e.to_string()))?;
+        match request {
+            SyncRequest::Summary { summary_request } => {
+                summary_request.verify()?;
+                if !trust.is_trusted(&summary_request.device_id, &summary_request.public_key) {
+                    return Err(SyncError::NotTrusted);
+                }
+                let summary =
+                    SyncSummary::from_ops(&local_ops()).with_checkpoint(self.checkpoint.clone());
+                let reply =
+                    serde_json::to_vec(&summary).map_err(|e| SyncError::Io(e.to_string()))?;
+                stream
+                    .write_all(&self.seal(&reply)?)
+                    .map_err(|e| SyncError::Io(e.to_string()))?;
+            }
+            SyncRequest::Envelope(envelope) => {
+                if !trust.is_trusted(&envelope.device_id, &envelope.public_key) {
+                    return Err(SyncError::NotTrusted);
+                }
+                envelope.verify()?;
+                op_handler(addr, envelope.ops);
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_addr(&self, target_device: &str) -> Result<SocketAddr, SyncError> {
+        if target_device.contains(':') {
+            target_device.parse().map_err(|_| SyncError::Addr)
+        } else {
+            format!("{}:{}", target_device, self.sync_port)
+                .parse()
+                .map_err(|_| SyncError::Addr)
+        }
+    }
+
+    /// Encrypt with the PSK when configured: `version(1) | nonce(12) | ciphertext`.
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, SyncError> {
+        let Some(psk) = self.psk else {
+            return Ok(plaintext.to_vec());
+        };
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&psk));
+        let ct = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|e| SyncError::Crypto(e.to_string()))?;
+        let mut out = Vec::with_capacity(1 + nonce.len() + ct.len());
+        out.push(1); // version
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ct);
+        Ok(out)
+    }
+
+    fn open(&self, buf: &[u8]) -> Result<Vec<u8>, SyncError> {
+        let Some(psk) = self.psk else {
+            return Ok(buf.to_vec());
+        };
+        if buf.len() < 13 {
+            return Err(SyncError::Crypto("encrypted payload too short".into()));
+        }
+        let version = buf[0];
+        if version != 1 {
+            return Err(SyncError::Crypto("unsupported crypto version".into()));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&psk));
+        cipher
+            .decrypt(Nonce::from_slice(&buf[1..13]), &buf[13..])
+            .map_err(|e| SyncError::Crypto(e.to_string()))
+    }
+}
+
+impl SyncEnvelope {
+    /// Check the ed25519 signature over [`signing_bytes`] of the ops against `public_key`.
+    pub fn verify(&self) -> Result<(), SyncError> {
+        verify_signature(
+            &self.public_key,
+            &signing_bytes(&self.ops)?,
+            &self.signature,
+        )
+    }
+}
+
+/// Check a base64 ed25519 `signature` over `data` against the hex `public_key`.
+fn verify_signature(public_key: &str, data: &[u8], signature: &str) -> Result<(), SyncError> {
+    let vk_bytes = hex::decode(public_key).map_err(|e| SyncError::Io(e.to_string()))?;
+    let vk = PublicKey::from_bytes(&vk_bytes).map_err(|e| SyncError::Io(e.to_string()))?;
+    let sig_bytes = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|e| SyncError::Io(e.to_string()))?;
+    let sig = Signature::from_bytes(&sig_bytes).map_err(|e| SyncError::Io(e.to_string()))?;
+    vk.verify(data, &sig).map_err(|_| SyncError::NotTrusted)
+}
+
+/// The bytes an envelope's signature covers: the ops as JSON, with the keys of every object
+/// inside a payload sorted. That's what peers built without serde_json's `preserve_order`
+/// produce, so signatures don't depend on the order a payload's keys were inserted in.
+pub fn signing_bytes(ops: &[notes_oplog::Operation]) -> Result<Vec<u8>, SyncError> {
+    fn sorted(value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                serde_json::Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(k, v)| (k.clone(), sorted(v)))
+                        .collect(),
+                )
+            }
+            serde_json::Value::Array(items) => {
+                serde_json::Value::Array(items.iter().map(sorted).collect())
+            }
+            other => other.clone(),
         }
-        Ok(())
     }
+    let canonical: Vec<_> = ops
+        .iter()
+        .map(|op| notes_oplog::Operation {
+            payload: sorted(&op.payload),
+            ..op.clone()
+        })
+        .collect();
+    serde_json::to_vec(&canonical).map_err(|e| SyncError::Io(e.to_string()))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -257,6 +504,64 @@ struct DiscoveryPacket {
     public_key: String,
 }
 
+/// Identifies the requesting device when asking a peer for its [`SyncSummary`]. It is signed
+/// together with the time it was made, and the peer checks both before looking the device up
+/// in its trust store, so the claimed identity can't be borrowed or a captured request replayed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SummaryRequest {
+    pub device_id: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub requested_at: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl SummaryRequest {
+    /// How far `requested_at` may be from the receiver's clock, in seconds.
+    const MAX_SKEW_SECS: i64 = 300;
+
+    pub fn signed(identity: &DeviceIdentity) -> Result<Self, SyncError> {
+        let mut request = Self {
+            device_id: identity.device_id.clone(),
+            public_key: identity.public_key.clone(),
+            requested_at: chrono::Utc::now().to_rfc3339(),
+            signature: String::new(),
+        };
+        request.signature = identity.sign(&request.signing_bytes())?;
+        Ok(request)
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        format!(
+            "summary-request\n{}\n{}\n{}",
+            self.device_id, self.public_key, self.requested_at
+        )
+        .into_bytes()
+    }
+
+    /// Check the signature against `public_key` and that the request is recent.
+    pub fn verify(&self) -> Result<(), SyncError> {
+        verify_signature(&self.public_key, &self.signing_bytes(), &self.signature)?;
+        let requested_at = chrono::DateTime::parse_from_rfc3339(&self.requested_at)
+            .map_err(|_| SyncError::NotTrusted)?;
+        let skew = chrono::Utc::now().signed_duration_since(requested_at);
+        if skew.num_seconds().abs() > Self::MAX_SKEW_SECS {
+            return Err(SyncError::NotTrusted);
+        }
+        Ok(())
+    }
+}
+
+// Older clients send a bare `SyncEnvelope`; summary requests are wrapped so the two can't be
+// confused.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SyncRequest {
+    Envelope(SyncEnvelope),
+    Summary { summary_request: SummaryRequest },
+}
+
 impl Transport for NetTransport {
     fn advertise(&self) -> Result<(), SyncError> {
         let socket = UdpSocket::bind(("0.0.0.0", 0)).map_err(|e| SyncError::Io(e.to_string()))?;
@@ -275,38 +580,68 @@ impl Transport for NetTransport {
     }
 
     fn request_sync(&self, target_device: &str, envelope: &SyncEnvelope) -> Result<(), SyncError> {
-        let addr: SocketAddr = if target_device.contains(':') {
-            target_device.parse().map_err(|_| SyncError::Addr)?
-        } else {
-            format!("{}:{}", target_device, self.sync_port)
-                .parse()
-                .map_err(|_| SyncError::Addr)?
-        };
+        let addr = self.resolve_addr(target_device)?;
         let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
             .map_err(|_| SyncError::Timeout)?;
         stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
         let mut stream = stream;
         let handshake = serde_json::to_vec(envelope).map_err(|e| SyncError::Io(e.to_string()))?;
-        let packet = if let Some(psk) = self.psk {
-            let mut nonce = [0u8; 12];
-            rand::thread_rng().fill_bytes(&mut nonce);
-            let cipher = ChaCha20Poly1305::new(Key::from_slice(&psk));
-            let ct = cipher
-                .encrypt(Nonce::from_slice(&nonce), handshake.as_slice())
-                .map_err(|e| SyncError::Crypto(e.to_string()))?;
-            let mut out = Vec::with_capacity(1 + nonce.len() + ct.len());
-            out.push(1); // version
-            out.extend_from_slice(&nonce);
-            out.extend_from_slice(&ct);
-            out
-        } else {
-            handshake
-        };
         stream
-            .write_all(&packet)
+            .write_all(&self.seal(&handshake)?)
             .map_err(|e| SyncError::Io(e.to_string()))?;
         Ok(())
     }
+
+    fn fetch_summary(
+        &self,
+        target_device: &str,
+        requester: &DeviceIdentity,
+    ) -> Result<SyncSummary, SyncError> {
+        let addr = self.resolve_addr(target_device)?;
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
+            .map_err(|_| SyncError::Timeout)?;
+        stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+        let request = SyncRequest::Summary {
+            summary_request: SummaryRequest::signed(requester)?,
+        };
+        let raw = serde_json::to_vec(&request).map_err(|e| SyncError::Io(e.to_string()))?;
+        stream
+            .write_all(&self.seal(&raw)?)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        stream
+            .shutdown(Shutdown::Write)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        let mut buf = Vec::new();
+        stream
+            .read_to_end(&mut buf)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        if buf.is_empty() {
+            return Err(SyncError::HandshakeFailed);
+        }
+        serde_json::from_slice(&self.open(&buf)?).map_err(|e| SyncError::Io(e.to_string()))
+    }
+
+    fn session(
+        &self,
+        target_device: &str,
+        identity: &DeviceIdentity,
+        trust: &TrustStore,
+        local_ops: &[notes_oplog::Operation],
+        apply: &mut dyn FnMut(Vec<notes_oplog::Operation>) -> SyncAck,
+    ) -> Result<SyncReport, SyncError> {
+        let addr = self.resolve_addr(target_device)?;
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
+            .map_err(|_| SyncError::Timeout)?;
+        stream.set_write_timeout(Some(Duration::from_secs(5))).ok();
+        // the peer applies our ops before acking, so give it more time than a plain read
+        stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+        stream
+            .write_all(session::SESSION_MAGIC)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        let mut channel = session::PskChannel::new(self, &mut stream);
+        session::run_session(&mut channel, identity, trust, local_ops, apply, self)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -408,7 +743,7 @@ impl TrustStore {
         }
         let raw = serde_json::to_string_pretty(&self.devices)
             .map_err(|e| SyncError::Io(e.to_string()))?;
-        fs::write(&self.path, raw).map_err(|e| SyncError::Io(e.to_string()))
+        notes_core::write_atomic(&self.path, raw).map_err(|e| SyncError::Io(e.to_string()))
     }
 }
 
@@ -458,7 +793,8 @@ impl DeviceIdentity {
                     identity.public_key = hex::encode(public.to_bytes());
                     let serialized = serde_json::to_string_pretty(&identity)
                         .map_err(|e| SyncError::Io(e.to_string()))?;
-                    fs::write(path, serialized).map_err(|e| SyncError::Io(e.to_string()))?;
+                    notes_core::write_atomic(path, serialized)
+                        .map_err(|e| SyncError::Io(e.to_string()))?;
                 }
                 return Ok(identity);
             }
@@ -466,7 +802,7 @@ impl DeviceIdentity {
         let identity = Self::generate();
         let serialized =
             serde_json::to_string_pretty(&identity).map_err(|e| SyncError::Io(e.to_string()))?;
-        fs::write(path, serialized).map_err(|e| SyncError::Io(e.to_string()))?;
+        notes_core::write_atomic(path, serialized).map_err(|e| SyncError::Io(e.to_string()))?;
         Ok(identity)
     }
 
@@ -540,4 +876,115 @@ mod tests {
         let data = handle.join().unwrap();
         assert!(data.contains("device_id"));
     }
+
+    #[test]
+    fn fetch_summary_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new_with_psk(0, addr.port(), Some([7u8; 32]));
+        let server = transport.clone();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            let request: SyncRequest = serde_json::from_slice(&server.open(&buf).unwrap()).unwrap();
+            let mut summary = SyncSummary::default();
+            summary.devices.insert(
+                "peer".into(),
+                DeviceRange {
+                    count: 1,
+                    max_op_id: "01".into(),
+                    digest: "d".into(),
+                },
+            );
+            let reply = serde_json::to_vec(&summary).unwrap();
+            stream.write_all(&server.seal(&reply).unwrap()).unwrap();
+            request
+        });
+        let identity = DeviceIdentity::generate();
+        let summary = transport
+            .fetch_summary(&addr.to_string(), &identity)
+            .unwrap();
+        assert_eq!(summary.devices["peer"].max_op_id, "01");
+        match handle.join().unwrap() {
+            SyncRequest::Summary { summary_request } => {
+                assert_eq!(summary_request.device_id, identity.device_id);
+                summary_request.verify().unwrap();
+            }
+            SyncRequest::Envelope(_) => panic!("expected a summary request"),
+        }
+    }
+
+    #[test]
+    fn summary_requests_must_be_signed_and_fresh() {
+        let identity = DeviceIdentity::generate();
+        let request = SummaryRequest::signed(&identity).unwrap();
+        request.verify().unwrap();
+
+        let impostor = DeviceIdentity::generate();
+        let claimed = SummaryRequest {
+            device_id: identity.device_id.clone(),
+            public_key: identity.public_key.clone(),
+            ..SummaryRequest::signed(&impostor).unwrap()
+        };
+        assert!(claimed.verify().is_err());
+        let unsigned = SummaryRequest {
+            signature: String::new(),
+            ..request.clone()
+        };
+        assert!(unsigned.verify().is_err());
+
+        let mut replayed = SummaryRequest {
+            requested_at: (chrono::Utc::now() - chrono::Duration::hours(1)).to_rfc3339(),
+            ..request
+        };
+        replayed.signature = identity.sign(&replayed.signing_bytes()).unwrap();
+        assert!(replayed.verify().is_err());
+    }
+
+    #[test]
+    fn signatures_ignore_payload_key_order() {
+        let op = |payload: &str| notes_oplog::Operation {
+            op_id: "01".into(),
+            device_id: "dev".into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: notes_oplog::OperationType::UpdateDocument,
+            document_id: "doc".into(),
+            payload: serde_json::from_str(payload).unwrap(),
+            before_hash: None,
+            after_hash: None,
+            clock: Default::default(),
+        };
+        let identity = DeviceIdentity::generate();
+        let envelope = session::signed_envelope(
+            &identity,
+            vec![op(
+                r#"{"title":"t","body":"b","meta":{"z":1,"a":[{"y":2,"x":3}]}}"#,
+            )],
+        )
+        .unwrap();
+        envelope.verify().unwrap();
+
+        // The bytes match what a peer with sorted maps serializes...
+        let sorted = op(r#"{"body":"b","meta":{"a":[{"x":3,"y":2}],"z":1},"title":"t"}"#);
+        assert_eq!(
+            signing_bytes(&envelope.ops).unwrap(),
+            serde_json::to_vec(&[sorted]).unwrap()
+        );
+        // ...and survive the payload's keys arriving in another order.
+        let reordered = SyncEnvelope {
+            ops: vec![op(
+                r#"{"meta":{"a":[{"x":3,"y":2}],"z":1},"body":"b","title":"t"}"#,
+            )],
+            ..envelope.clone()
+        };
+        reordered.verify().unwrap();
+        let tampered = SyncEnvelope {
+            ops: vec![op(
+                r#"{"title":"t","body":"c","meta":{"z":1,"a":[{"y":2,"x":3}]}}"#,
+            )],
+            ..envelope
+        };
+        assert!(tampered.verify().is_err());
+    }
 }
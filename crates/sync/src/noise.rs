@@ -0,0 +1,589 @@
+use crate::session::{
+    self, FrameChannel, PeerIdentity, MAX_FRAME_LEN, MAX_UNAUTHENTICATED_FRAME_LEN,
+};
+use crate::{
+    DeviceIdentity, NetTransport, SyncAck, SyncEnvelope, SyncError, SyncReport, Transport,
+    TrustStore,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use notes_oplog::Operation;
+use sha2::{Digest, Sha512};
+use snow::{HandshakeState, TransportState};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Noise transport: sessions run over Noise_XX (first contact) or Noise_IK (the initiator
+// already knows the responder's key), with each device's static X25519 key derived from its
+// ed25519 identity. Handshake payloads carry `{device_id, public_key}`; a payload is only
+// believed if its public key maps to the static key the handshake proved, and the peer must
+// be in the `TrustStore`. After the magic and a pattern byte, every Noise message is sent as
+// `len(u16 BE) | message`; session frames are split across as many messages as needed.
+
+pub(crate) const NOISE_MAGIC: &[u8; 4] = b"NSN\x01";
+const PATTERN_XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const NOISE_MAX_MSG: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Xx = 0,
+    Ik = 1,
+}
+
+/// The X25519 public key matching a hex ed25519 public key (Edwards to Montgomery map).
+pub fn x25519_public_key(ed25519_public_key: &str) -> Result<[u8; 32], SyncError> {
+    let bytes: [u8; 32] = hex::decode(ed25519_public_key)
+        .map_err(|e| SyncError::Crypto(e.to_string()))?
+        .try_into()
+        .map_err(|_| SyncError::Crypto("ed25519 public key must be 32 bytes".into()))?;
+    let point = CompressedEdwardsY(bytes)
+        .decompress()
+        .ok_or_else(|| SyncError::Crypto("invalid ed25519 public key".into()))?;
+    Ok(point.to_montgomery().to_bytes())
+}
+
+impl DeviceIdentity {
+    /// Static X25519 secret for Noise, derived from the ed25519 seed the same way ed25519
+    /// derives its signing scalar; its public half is `x25519_public_key(&self.public_key)`.
+    pub fn x25519_secret(&self) -> Result<[u8; 32], SyncError> {
+        let seed = hex::decode(&self.secret_key).map_err(|e| SyncError::Crypto(e.to_string()))?;
+        let hash = Sha512::digest(&seed);
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&hash[..32]);
+        secret[0] &= 248;
+        secret[31] &= 127;
+        secret[31] |= 64;
+        Ok(secret)
+    }
+
+    fn peer_identity(&self) -> PeerIdentity {
+        PeerIdentity {
+            device_id: self.device_id.clone(),
+            public_key: self.public_key.clone(),
+        }
+    }
+}
+
+/// Session frames over an established Noise transport. The handshake proves the peer's key
+/// but not that we trust it, so frames are capped at [`MAX_UNAUTHENTICATED_FRAME_LEN`] until
+/// the session has verified the peer.
+pub(crate) struct NoiseChannel {
+    pub(crate) stream: TcpStream,
+    state: TransportState,
+    peer: PeerIdentity,
+    /// Hash of the whole handshake transcript, identical on both ends.
+    pub(crate) handshake_hash: Vec<u8>,
+    verified: bool,
+}
+
+impl NoiseChannel {
+    /// Lift the frame cap right away if the key the handshake proved belongs to a trusted
+    /// device, so its first frame may be a large envelope.
+    pub(crate) fn verify_if_trusted(&mut self, trust: &TrustStore) {
+        if trust.is_trusted(&self.peer.device_id, &self.peer.public_key) {
+            self.verified = true;
+        }
+    }
+}
+
+impl FrameChannel for NoiseChannel {
+    fn send(&mut self, data: &[u8]) -> Result<(), SyncError> {
+        if data.len() > MAX_FRAME_LEN {
+            return Err(SyncError::Invalid("frame too large".into()));
+        }
+        let mut plain = (data.len() as u32).to_be_bytes().to_vec();
+        plain.extend_from_slice(data);
+        let mut buf = vec![0u8; NOISE_MAX_MSG];
+        for chunk in plain.chunks(NOISE_MAX_MSG - NOISE_TAG_LEN) {
+            let n = self
+                .state
+                .write_message(chunk, &mut buf)
+                .map_err(|e| SyncError::Crypto(e.to_string()))?;
+            write_message(&mut self.stream, &buf[..n])?;
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, SyncError> {
+        let mut plain = Vec::new();
+        let mut buf = vec![0u8; NOISE_MAX_MSG];
+        loop {
+            let message = read_message(&mut self.stream)?;
+            let n = self
+                .state
+                .read_message(&message, &mut buf)
+                .map_err(|e| SyncError::Crypto(e.to_string()))?;
+            plain.extend_from_slice(&buf[..n]);
+            if plain.len() < 4 {
+                continue;
+            }
+            let len = u32::from_be_bytes([plain[0], plain[1], plain[2], plain[3]]) as usize;
+            let limit = match self.verified {
+                true => MAX_FRAME_LEN,
+                false => MAX_UNAUTHENTICATED_FRAME_LEN,
+            };
+            if len > limit {
+                return Err(SyncError::Invalid("frame too large".into()));
+            }
+            if plain.len() >= 4 + len {
+                plain.truncate(4 + len);
+                return Ok(plain.split_off(4));
+            }
+        }
+    }
+
+    fn authenticated_peer(&self) -> Option<&PeerIdentity> {
+        Some(&self.peer)
+    }
+
+    fn peer_verified(&mut self) {
+        self.verified = true;
+    }
+}
+
+fn write_message(stream: &mut TcpStream, message: &[u8]) -> Result<(), SyncError> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| SyncError::Invalid("noise message too large".into()))?;
+    stream
+        .write_all(&len.to_be_bytes())
+        .and_then(|_| stream.write_all(message))
+        .map_err(|e| SyncError::Io(e.to_string()))
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, SyncError> {
+    let mut len = [0u8; 2];
+    stream
+        .read_exact(&mut len)
+        .map_err(|e| SyncError::Io(e.to_string()))?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream
+        .read_exact(&mut message)
+        .map_err(|e| SyncError::Io(e.to_string()))?;
+    Ok(message)
+}
+
+fn builder(pattern: &str) -> Result<snow::Builder<'static>, SyncError> {
+    let params = pattern
+        .parse()
+        .map_err(|e: snow::Error| SyncError::Crypto(e.to_string()))?;
+    Ok(snow::Builder::new(params))
+}
+
+/// Parse a handshake payload and check it names the key the handshake authenticated.
+fn authenticate(hs: &HandshakeState, payload: &[u8]) -> Result<PeerIdentity, SyncError> {
+    let peer: PeerIdentity =
+        serde_json::from_slice(payload).map_err(|_| SyncError::HandshakeFailed)?;
+    let remote_static = hs.get_remote_static().ok_or(SyncError::HandshakeFailed)?;
+    if x25519_public_key(&peer.public_key)?.as_slice() != remote_static {
+        return Err(SyncError::NotTrusted);
+    }
+    Ok(peer)
+}
+
+struct Handshake {
+    state: HandshakeState,
+    buf: Vec<u8>,
+}
+
+impl Handshake {
+    fn new(state: HandshakeState) -> Self {
+        Self {
+            state,
+            buf: vec![0u8; NOISE_MAX_MSG],
+        }
+    }
+
+    fn send(&mut self, stream: &mut TcpStream, payload: &[u8]) -> Result<(), SyncError> {
+        let n = self
+            .state
+            .write_message(payload, &mut self.buf)
+            .map_err(|e| SyncError::Crypto(e.to_string()))?;
+        write_message(stream, &self.buf[..n])
+    }
+
+    fn recv(&mut self, stream: &mut TcpStream) -> Result<Vec<u8>, SyncError> {
+        let message = read_message(stream)?;
+        let n = self
+            .state
+            .read_message(&message, &mut self.buf)
+            .map_err(|_| SyncError::HandshakeFailed)?;
+        Ok(self.buf[..n].to_vec())
+    }
+
+    fn finish(self, stream: TcpStream, peer: PeerIdentity) -> Result<NoiseChannel, SyncError> {
+        let handshake_hash = self.state.get_handshake_hash().to_vec();
+        let state = self
+            .state
+            .into_transport_mode()
+            .map_err(|e| SyncError::Crypto(e.to_string()))?;
+        Ok(NoiseChannel {
+            stream,
+            state,
+            peer,
+            handshake_hash,
+            verified: false,
+        })
+    }
+}
+
+/// Initiator handshake, announced with `magic`. Uses IK when `responder_key` (hex ed25519) is
+/// given, XX otherwise. With a trust store the responder must be trusted before our identity
+/// is revealed (XX) or used (IK); without one (pairing) the user verifies the peer instead.
+pub(crate) fn connect(
+    mut stream: TcpStream,
+    magic: &[u8; 4],
+    identity: &DeviceIdentity,
+    trust: Option<&TrustStore>,
+    responder_key: Option<&str>,
+) -> Result<NoiseChannel, SyncError> {
+    let trusted =
+        |peer: &PeerIdentity| trust.is_none_or(|t| t.is_trusted(&peer.device_id, &peer.public_key));
+    let secret = identity.x25519_secret()?;
+    let payload =
+        serde_json::to_vec(&identity.peer_identity()).map_err(|e| SyncError::Io(e.to_string()))?;
+    let pattern = if responder_key.is_some() {
+        Pattern::Ik
+    } else {
+        Pattern::Xx
+    };
+    stream
+        .write_all(magic)
+        .and_then(|_| stream.write_all(&[pattern as u8]))
+        .map_err(|e| SyncError::Io(e.to_string()))?;
+
+    let (hs, peer) = match responder_key {
+        Some(key) => {
+            let remote = x25519_public_key(key)?;
+            let state = builder(PATTERN_IK)?
+                .local_private_key(&secret)
+                .remote_public_key(&remote)
+                .build_initiator()
+                .map_err(|e| SyncError::Crypto(e.to_string()))?;
+            let mut hs = Handshake::new(state);
+            hs.send(&mut stream, &payload)?;
+            let reply = hs.recv(&mut stream)?;
+            let peer = authenticate(&hs.state, &reply)?;
+            if peer.public_key != key || !trusted(&peer) {
+                return Err(SyncError::NotTrusted);
+            }
+            (hs, peer)
+        }
+        None => {
+            let state = builder(PATTERN_XX)?
+                .local_private_key(&secret)
+                .build_initiator()
+                .map_err(|e| SyncError::Crypto(e.to_string()))?;
+            let mut hs = Handshake::new(state);
+            hs.send(&mut stream, &[])?;
+            let reply = hs.recv(&mut stream)?;
+            let peer = authenticate(&hs.state, &reply)?;
+            if !trusted(&peer) {
+                return Err(SyncError::NotTrusted);
+            }
+            hs.send(&mut stream, &payload)?;
+            (hs, peer)
+        }
+    };
+    hs.finish(stream, peer)
+}
+
+/// Responder handshake (magic already consumed). Trust is enforced by the session on top,
+/// which answers untrusted peers with an error frame over the established channel.
+pub(crate) fn accept(
+    mut stream: TcpStream,
+    identity: &DeviceIdentity,
+) -> Result<NoiseChannel, SyncError> {
+    let secret = identity.x25519_secret()?;
+    let payload =
+        serde_json::to_vec(&identity.peer_identity()).map_err(|e| SyncError::Io(e.to_string()))?;
+    let mut pattern = [0u8; 1];
+    stream
+        .read_exact(&mut pattern)
+        .map_err(|e| SyncError::Io(e.to_string()))?;
+    let name = match pattern[0] {
+        p if p == Pattern::Xx as u8 => PATTERN_XX,
+        p if p == Pattern::Ik as u8 => PATTERN_IK,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    let state = builder(name)?
+        .local_private_key(&secret)
+        .build_responder()
+        .map_err(|e| SyncError::Crypto(e.to_string()))?;
+    let mut hs = Handshake::new(state);
+    let peer = if name == PATTERN_IK {
+        let hello = hs.recv(&mut stream)?;
+        let peer = authenticate(&hs.state, &hello)?;
+        hs.send(&mut stream, &payload)?;
+        peer
+    } else {
+        hs.recv(&mut stream)?;
+        hs.send(&mut stream, &payload)?;
+        let hello = hs.recv(&mut stream)?;
+        authenticate(&hs.state, &hello)?
+    };
+    hs.finish(stream, peer)
+}
+
+/// Sync transport that runs sessions over Noise, mutually authenticated against the trust
+/// store. Discovery and the listening side are shared with [`NetTransport`], which accepts
+/// Noise, framed PSK and legacy connections alike.
+#[derive(Clone, Debug)]
+pub struct NoiseTransport {
+    net: NetTransport,
+    identity: DeviceIdentity,
+    trust_path: PathBuf,
+    /// Known public keys by target address; sessions with these peers use IK.
+    peer_keys: HashMap<String, String>,
+}
+
+impl NoiseTransport {
+    pub fn new(
+        discovery_port: u16,
+        sync_port: u16,
+        identity: DeviceIdentity,
+        trust_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self::from_net(
+            NetTransport::new(discovery_port, sync_port),
+            identity,
+            trust_path,
+        )
+    }
+
+    /// Run Noise sessions on `net`'s ports. Its PSK, if any, is not used.
+    pub fn from_net(
+        net: NetTransport,
+        identity: DeviceIdentity,
+        trust_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            net,
+            identity,
+            trust_path: trust_path.into(),
+            peer_keys: HashMap::new(),
+        }
+    }
+
+    /// Remember `target`'s public key (e.g. from discovery) so the handshake can use IK.
+    pub fn with_peer_key(mut self, target: &str, public_key: &str) -> Self {
+        self.peer_keys
+            .insert(target.to_string(), public_key.to_string());
+        self
+    }
+
+    fn open_channel(
+        &self,
+        target_device: &str,
+        identity: &DeviceIdentity,
+        trust: &TrustStore,
+    ) -> Result<NoiseChannel, SyncError> {
+        let addr = self.net.resolve_addr(target_device)?;
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
+            .map_err(|_| SyncError::Timeout)?;
+        stream.set_write_timeout(Some(Duration::from_secs(5))).ok();
+        // the peer applies our ops before acking, so give it more time than a plain read
+        stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+        // only use IK for keys we trust; anything else goes through XX and fails there
+        let responder_key = self
+            .peer_keys
+            .get(target_device)
+            .filter(|key| trust.list().iter().any(|d| &d.public_key == *key))
+            .map(String::as_str);
+        connect(stream, NOISE_MAGIC, identity, Some(trust), responder_key)
+    }
+}
+
+impl Transport for NoiseTransport {
+    fn advertise(&self) -> Result<(), SyncError> {
+        self.net.advertise()
+    }
+
+    fn request_sync(&self, target_device: &str, envelope: &SyncEnvelope) -> Result<(), SyncError> {
+        let trust = TrustStore::load_or_default(&self.trust_path)?;
+        let mut channel = self.open_channel(target_device, &self.identity, &trust)?;
+        session::push_envelope(&mut channel, envelope).map(|_| ())
+    }
+
+    fn session(
+        &self,
+        target_device: &str,
+        identity: &DeviceIdentity,
+        trust: &TrustStore,
+        local_ops: &[Operation],
+        apply: &mut dyn FnMut(Vec<Operation>) -> SyncAck,
+    ) -> Result<SyncReport, SyncError> {
+        let mut channel = self.open_channel(target_device, identity, trust)?;
+        session::run_session(&mut channel, identity, trust, local_ops, apply, &self.net)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notes_oplog::{OperationType, VectorClock};
+    use std::net::TcpListener;
+    use tempfile::tempdir;
+
+    fn op(device: &str, id: &str) -> Operation {
+        Operation {
+            op_id: id.into(),
+            device_id: device.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: OperationType::CreateDocument,
+            document_id: format!("doc-{device}"),
+            payload: serde_json::Value::Null,
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        }
+    }
+
+    #[test]
+    fn derived_x25519_keys_match() {
+        let identity = DeviceIdentity::generate();
+        let secret = identity.x25519_secret().unwrap();
+        let public = curve25519_dalek::constants::X25519_BASEPOINT
+            * curve25519_dalek::scalar::Scalar::from_bits(secret);
+        assert_eq!(
+            public.to_bytes(),
+            x25519_public_key(&identity.public_key).unwrap()
+        );
+    }
+
+    fn serve(
+        listener: TcpListener,
+        identity: DeviceIdentity,
+        trust: TrustStore,
+        ops: Vec<Operation>,
+    ) -> std::thread::JoinHandle<Result<(), SyncError>> {
+        std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().unwrap();
+            NetTransport::new(0, 0)
+                .serve_connection(
+                    stream,
+                    addr,
+                    &identity,
+                    &trust,
+                    || ops.clone(),
+                    |_, ops| SyncAck {
+                        accepted: ops.iter().map(|o| o.key()).collect(),
+                        rejected: Vec::new(),
+                        superseded: Vec::new(),
+                    },
+                )
+                .map(|_| ())
+        })
+    }
+
+    #[test]
+    fn noise_sessions_over_xx_and_ik() {
+        let dir = tempdir().unwrap();
+        let (client, server) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let client_trust_path = dir.path().join("client.json");
+        let mut client_trust = TrustStore::load_or_default(&client_trust_path).unwrap();
+        client_trust
+            .add(server.device_id.clone(), server.public_key.clone())
+            .unwrap();
+        let mut server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+        server_trust
+            .add(client.device_id.clone(), client.public_key.clone())
+            .unwrap();
+
+        for use_ik in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+            let handle = serve(listener, server.clone(), server_trust, vec![op("b", "01")]);
+            let mut transport = NoiseTransport::new(0, 0, client.clone(), &client_trust_path);
+            if use_ik {
+                transport = transport.with_peer_key(&addr, &server.public_key);
+            }
+            let report = transport
+                .session(
+                    &addr,
+                    &client,
+                    &client_trust,
+                    &[op("a", "01")],
+                    &mut |ops| SyncAck {
+                        accepted: ops.iter().map(|o| o.key()).collect(),
+                        rejected: Vec::new(),
+                        superseded: Vec::new(),
+                    },
+                )
+                .unwrap();
+            handle.join().unwrap().unwrap();
+            assert_eq!(report.push_ack.unwrap().accepted, vec!["a:01"]);
+            assert_eq!(report.pull_ack.accepted, vec!["b:01"]);
+        }
+    }
+
+    #[test]
+    fn noise_large_frames_wait_for_verification() {
+        let (client, server) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut frames = Vec::new();
+            for verified in [false, true] {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.read_exact(&mut [0u8; 4]).unwrap();
+                let mut channel = accept(stream, &server).unwrap();
+                if verified {
+                    channel.peer_verified();
+                }
+                frames.push(channel.recv());
+            }
+            frames
+        });
+        let large = vec![7u8; 2 * MAX_UNAUTHENTICATED_FRAME_LEN];
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut channel = connect(stream, NOISE_MAGIC, &client, None, None).unwrap();
+            // the unverified side gives up after the header, so the rest may not get through
+            let _ = channel.send(&large);
+        }
+
+        let frames = handle.join().unwrap();
+        assert!(matches!(frames[0], Err(SyncError::Invalid(_))));
+        assert_eq!(frames[1].as_ref().unwrap().len(), large.len());
+    }
+
+    #[test]
+    fn noise_rejects_untrusted_peers() {
+        let dir = tempdir().unwrap();
+        let (client, server) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let mut client_trust = TrustStore::load_or_default(dir.path().join("client.json")).unwrap();
+        client_trust
+            .add(server.device_id.clone(), server.public_key.clone())
+            .unwrap();
+        // the server doesn't trust the client
+        let server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = serve(listener, server.clone(), server_trust, Vec::new());
+        let transport = NoiseTransport::new(0, 0, client.clone(), dir.path().join("client.json"));
+        let result = transport.session(&addr, &client, &client_trust, &[], &mut |_| {
+            SyncAck::default()
+        });
+        assert!(matches!(result, Err(SyncError::NotTrusted)));
+        assert!(matches!(handle.join().unwrap(), Err(SyncError::NotTrusted)));
+
+        // a client that doesn't trust the server aborts during the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+        server_trust
+            .add(client.device_id.clone(), client.public_key.clone())
+            .unwrap();
+        let handle = serve(listener, server, server_trust, Vec::new());
+        let empty = TrustStore::load_or_default(dir.path().join("none.json")).unwrap();
+        let result = transport.session(&addr, &client, &empty, &[], &mut |_| SyncAck::default());
+        assert!(matches!(result, Err(SyncError::NotTrusted)));
+        assert!(handle.join().unwrap().is_err());
+    }
+}
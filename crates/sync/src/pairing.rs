@@ -0,0 +1,293 @@
+use crate::noise::{self, NoiseChannel};
+use crate::session::FrameChannel;
+use crate::{DeviceIdentity, NetTransport, SyncError, TrustStore};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Pairing: two devices that don't trust each other yet run an untrusted Noise XX handshake
+// (on the sync port, announced with its own magic), then a commit/reveal nonce exchange:
+//
+//   initiator                          responder
+//                               <---   Commit{sha256(nonce_r)}
+//   Nonce{nonce_i}              --->
+//                               <---   Reveal{nonce_r}
+//
+// Both sides derive the short authentication string from the handshake hash and both
+// nonces. The responder commits before seeing the initiator's nonce and the initiator's nonce
+// is fixed before the reveal, so a man in the middle can't grind its keys towards matching
+// codes on both legs. Users compare the codes, each side sends its decision, and only when
+// both confirmed does each device add the other to its `TrustStore`.
+
+pub(crate) const PAIRING_MAGIC: &[u8; 4] = b"NSP\x01";
+/// How long to wait for the other user to confirm.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+
+const SAS_EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐎", "🦄", "🐷", "🐘", "🐰", "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌", "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🎩", "👓", "🔧", "🎅", "👍", "☂️", "⌛", "⏰", "🎁", "💡", "📕", "✏️", "📎", "✂️", "🔒", "🔑",
+    "🔨", "☎️", "🏁", "🚂", "🚲", "✈️", "🚀", "🏆", "⚽", "🎸", "🎺", "🔔", "⚓", "🎧", "📁", "📌",
+];
+
+/// Short authentication string both users compare before trusting each other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PairingCode {
+    /// Six decimal digits.
+    pub digits: String,
+    /// The same check as seven emoji, for people who prefer pictures.
+    pub emoji: Vec<String>,
+}
+
+impl PairingCode {
+    fn derive(handshake_hash: &[u8], nonce_i: &[u8], nonce_r: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"notes-sync pairing v1");
+        hasher.update(handshake_hash);
+        hasher.update(nonce_i);
+        hasher.update(nonce_r);
+        let sas = hasher.finalize();
+        let number = u32::from_be_bytes([sas[0], sas[1], sas[2], sas[3]]) % 1_000_000;
+        // 7 x 6 bits from the next 6 bytes
+        let bits = sas[4..10]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        let emoji = (0..7)
+            .map(|i| SAS_EMOJI[((bits >> (42 - 6 * (i + 1))) & 0x3f) as usize].to_string())
+            .collect();
+        Self {
+            digits: format!("{number:06}"),
+            emoji,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PairingMessage {
+    Commit { commitment: String },
+    Nonce { nonce: String },
+    Reveal { nonce: String },
+    Decision { accept: bool },
+}
+
+fn send(channel: &mut NoiseChannel, message: &PairingMessage) -> Result<(), SyncError> {
+    let raw = serde_json::to_vec(message).map_err(|e| SyncError::Io(e.to_string()))?;
+    channel.send(&raw)
+}
+
+fn recv(channel: &mut NoiseChannel) -> Result<PairingMessage, SyncError> {
+    serde_json::from_slice(&channel.recv()?).map_err(|_| SyncError::HandshakeFailed)
+}
+
+fn nonce() -> [u8; 32] {
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// A pairing whose code is ready to be shown; finish it with [`PendingPairing::confirm`].
+pub struct PendingPairing {
+    channel: NoiseChannel,
+    code: PairingCode,
+}
+
+impl PendingPairing {
+    pub fn device_id(&self) -> &str {
+        &self.peer().device_id
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.peer().public_key
+    }
+
+    pub fn code(&self) -> &PairingCode {
+        &self.code
+    }
+
+    fn peer(&self) -> &crate::session::PeerIdentity {
+        self.channel
+            .authenticated_peer()
+            .expect("noise channels are authenticated")
+    }
+
+    /// Send our decision and wait for the peer's. Returns `true` and adds the peer to `trust`
+    /// only if both users confirmed the code.
+    pub fn confirm(mut self, accept: bool, trust: &mut TrustStore) -> Result<bool, SyncError> {
+        self.channel
+            .stream
+            .set_read_timeout(Some(CONFIRM_TIMEOUT))
+            .ok();
+        send(&mut self.channel, &PairingMessage::Decision { accept })?;
+        let peer_accepts = match recv(&mut self.channel)? {
+            PairingMessage::Decision { accept } => accept,
+            _ => return Err(SyncError::HandshakeFailed),
+        };
+        if !(accept && peer_accepts) {
+            return Ok(false);
+        }
+        let (device_id, public_key) = (self.device_id().to_string(), self.public_key().to_string());
+        trust.add(device_id, public_key)?;
+        Ok(true)
+    }
+}
+
+/// Initiator side: handshake, then send our nonce between the peer's commit and reveal.
+pub(crate) fn initiate(
+    stream: TcpStream,
+    identity: &DeviceIdentity,
+) -> Result<PendingPairing, SyncError> {
+    let mut channel = noise::connect(stream, PAIRING_MAGIC, identity, None, None)?;
+    let commitment = match recv(&mut channel)? {
+        PairingMessage::Commit { commitment } => commitment,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    let nonce_i = nonce();
+    send(
+        &mut channel,
+        &PairingMessage::Nonce {
+            nonce: hex::encode(nonce_i),
+        },
+    )?;
+    let nonce_r = match recv(&mut channel)? {
+        PairingMessage::Reveal { nonce } => {
+            hex::decode(nonce).map_err(|_| SyncError::HandshakeFailed)?
+        }
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    if hex::encode(Sha256::digest(&nonce_r)) != commitment {
+        return Err(SyncError::Crypto("pairing commitment mismatch".into()));
+    }
+    let code = PairingCode::derive(&channel.handshake_hash, &nonce_i, &nonce_r);
+    Ok(PendingPairing { channel, code })
+}
+
+/// Responder side (magic already consumed): handshake, commit, then reveal.
+pub(crate) fn respond(
+    stream: TcpStream,
+    identity: &DeviceIdentity,
+) -> Result<PendingPairing, SyncError> {
+    let mut channel = noise::accept(stream, identity)?;
+    let nonce_r = nonce();
+    send(
+        &mut channel,
+        &PairingMessage::Commit {
+            commitment: hex::encode(Sha256::digest(nonce_r)),
+        },
+    )?;
+    let nonce_i = match recv(&mut channel)? {
+        PairingMessage::Nonce { nonce } => {
+            hex::decode(nonce).map_err(|_| SyncError::HandshakeFailed)?
+        }
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    send(
+        &mut channel,
+        &PairingMessage::Reveal {
+            nonce: hex::encode(nonce_r),
+        },
+    )?;
+    let code = PairingCode::derive(&channel.handshake_hash, &nonce_i, &nonce_r);
+    Ok(PendingPairing { channel, code })
+}
+
+impl NetTransport {
+    /// Start pairing with a device found via `listen_discovery`. Show the returned code to
+    /// the user and finish with [`PendingPairing::confirm`].
+    pub fn pair_with(
+        &self,
+        target_device: &str,
+        identity: &DeviceIdentity,
+    ) -> Result<PendingPairing, SyncError> {
+        let addr = self.resolve_addr(target_device)?;
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
+            .map_err(|_| SyncError::Timeout)?;
+        stream.set_write_timeout(Some(Duration::from_secs(5))).ok();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+        initiate(stream, identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tempfile::tempdir;
+
+    fn pair(
+        accept_a: bool,
+        accept_b: bool,
+    ) -> (
+        bool,
+        bool,
+        TrustStore,
+        TrustStore,
+        DeviceIdentity,
+        DeviceIdentity,
+    ) {
+        let dir = tempdir().unwrap();
+        let (a, b) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new(0, addr.port());
+
+        let b_identity = b.clone();
+        let b_trust_path = dir.path().join("b.json");
+        let responder = std::thread::spawn(move || {
+            let (stream, peer) = listener.accept().unwrap();
+            let pending = NetTransport::new(0, 0)
+                .serve_connection(
+                    stream,
+                    peer,
+                    &b_identity,
+                    &TrustStore::load_or_default(&b_trust_path).unwrap(),
+                    Vec::new,
+                    |_, _| crate::SyncAck::default(),
+                )
+                .unwrap()
+                .expect("pairing request");
+            let code = pending.code().clone();
+            let mut trust = TrustStore::load_or_default(&b_trust_path).unwrap();
+            let ok = pending.confirm(accept_b, &mut trust).unwrap();
+            (code, ok, trust)
+        });
+
+        let pending = transport.pair_with(&addr.to_string(), &a).unwrap();
+        assert_eq!(pending.device_id(), b.device_id);
+        let code = pending.code().clone();
+        let mut trust_a = TrustStore::load_or_default(dir.path().join("a.json")).unwrap();
+        let ok_a = pending.confirm(accept_a, &mut trust_a).unwrap();
+        let (code_b, ok_b, trust_b) = responder.join().unwrap();
+
+        assert_eq!(code, code_b);
+        assert_eq!(code.digits.len(), 6);
+        assert_eq!(code.emoji.len(), 7);
+        (ok_a, ok_b, trust_a, trust_b, a, b)
+    }
+
+    #[test]
+    fn pairing_adds_both_devices_on_confirmation() {
+        let (ok_a, ok_b, trust_a, trust_b, a, b) = pair(true, true);
+        assert!(ok_a && ok_b);
+        assert!(trust_a.is_trusted(&b.device_id, &b.public_key));
+        assert!(trust_b.is_trusted(&a.device_id, &a.public_key));
+    }
+
+    #[test]
+    fn pairing_rejected_by_either_side_trusts_nobody() {
+        let (ok_a, ok_b, trust_a, trust_b, _, _) = pair(true, false);
+        assert!(!ok_a && !ok_b);
+        assert!(trust_a.list().is_empty());
+        assert!(trust_b.list().is_empty());
+    }
+
+    #[test]
+    fn codes_depend_on_every_input() {
+        let base = PairingCode::derive(b"hash", b"i", b"r");
+        assert_ne!(base, PairingCode::derive(b"hasH", b"i", b"r"));
+        assert_ne!(base, PairingCode::derive(b"hash", b"I", b"r"));
+        assert_ne!(base, PairingCode::derive(b"hash", b"i", b"R"));
+    }
+}
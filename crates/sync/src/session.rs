@@ -0,0 +1,614 @@
+use crate::blobs;
+use crate::{
+    missing_ops, signing_bytes, DeviceIdentity, NetTransport, SyncEnvelope, SyncError, SyncSummary,
+    TrustStore,
+};
+use notes_oplog::{Checkpoint, Operation};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+// Framed sync sessions. Every message is a JSON `Frame` carried by a `FrameChannel`: either
+// `len(u32 BE) | sealed JSON` after the session magic (sealed with the PSK when one is
+// configured), or Noise transport messages (see `noise.rs`). A session is symmetric:
+//
+//   initiator                          responder
+//   Hello{identity, summary}    --->
+//                               <---   Hello{identity, summary} | Error
+//   Envelope{ops peer lacks}    --->
+//                               <---   Ack
+//                               <---   Envelope{ops initiator lacks}
+//   Ack                         --->
+//
+// When both hellos announce a blob directory, attachment blobs follow (see `blobs.rs`).
+// An initiator may instead open with a bare Envelope (one-shot push), answered with an Ack.
+// Connections that don't start with a known magic are handled as legacy unframed pushes.
+
+pub(crate) const SESSION_MAGIC: &[u8; 4] = b"NSS\x01";
+pub(crate) const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+/// Largest frame a PSK channel takes before the peer has authenticated; a hello fits easily.
+pub(crate) const MAX_UNAUTHENTICATED_FRAME_LEN: usize = 1024 * 1024;
+/// Frames are read in pieces of this size, so a frame only costs memory as it arrives.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Why a peer refused an op.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "reason", content = "detail", rename_all = "snake_case")]
+pub enum RejectReason {
+    NotTrusted,
+    BadSignature,
+    Conflict,
+    HashMismatch,
+    Invalid(String),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NotTrusted => write!(f, "not trusted"),
+            RejectReason::BadSignature => write!(f, "bad signature"),
+            RejectReason::Conflict => write!(f, "conflict"),
+            RejectReason::HashMismatch => write!(f, "hash mismatch"),
+            RejectReason::Invalid(detail) => write!(f, "invalid: {detail}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RejectedOp {
+    pub op_key: String,
+    pub reason: RejectReason,
+}
+
+/// Receiver's verdict on an envelope, by op key.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SyncAck {
+    pub accepted: Vec<String>,
+    pub rejected: Vec<RejectedOp>,
+    /// Valid ops the receiver's version already supersedes: kept in its history, but nothing
+    /// was written for them.
+    #[serde(default)]
+    pub superseded: Vec<String>,
+}
+
+impl SyncAck {
+    pub fn reject_all(ops: &[Operation], reason: RejectReason) -> Self {
+        Self {
+            accepted: Vec::new(),
+            rejected: ops
+                .iter()
+                .map(|op| RejectedOp {
+                    op_key: op.key(),
+                    reason: reason.clone(),
+                })
+                .collect(),
+            superseded: Vec::new(),
+        }
+    }
+}
+
+/// Outcome of one sync with a peer, seen from the initiator.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SyncReport {
+    /// Number of ops pushed to the peer.
+    pub sent: usize,
+    /// The peer's verdict on the pushed ops; `None` when the transport has no acks.
+    pub push_ack: Option<SyncAck>,
+    /// Number of ops pulled from the peer.
+    pub received: usize,
+    /// Our verdict on the pulled ops.
+    pub pull_ack: SyncAck,
+    /// Attachment blobs streamed to the peer.
+    #[serde(default)]
+    pub blobs_sent: usize,
+    /// Attachment blobs received from the peer and verified.
+    #[serde(default)]
+    pub blobs_received: usize,
+    /// The peer's compaction checkpoint, for `OpLog::adopt_checkpoint`.
+    #[serde(default)]
+    pub peer_checkpoint: Checkpoint,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SessionHello {
+    device_id: String,
+    public_key: String,
+    summary: SyncSummary,
+    /// Whether this side exchanges attachment blobs after the ops.
+    #[serde(default)]
+    blobs: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    Hello(SessionHello),
+    Envelope(SyncEnvelope),
+    Ack(SyncAck),
+    Error { reason: RejectReason },
+}
+
+/// A bidirectional, message-oriented connection to a peer.
+pub(crate) trait FrameChannel {
+    fn send(&mut self, data: &[u8]) -> Result<(), SyncError>;
+    fn recv(&mut self) -> Result<Vec<u8>, SyncError>;
+    /// Peer identity proven by the channel's own handshake, if it has one.
+    fn authenticated_peer(&self) -> Option<&PeerIdentity> {
+        None
+    }
+
+    /// The session found the peer in the trust store.
+    fn peer_verified(&mut self) {}
+}
+
+/// A device as identified by its id and hex ed25519 public key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct PeerIdentity {
+    pub device_id: String,
+    pub public_key: String,
+}
+
+/// Length-prefixed frames over TCP, sealed with the transport's PSK when configured. Until a
+/// frame has opened with the PSK (or, without one, the session has verified the peer), frames
+/// are capped at [`MAX_UNAUTHENTICATED_FRAME_LEN`].
+pub(crate) struct PskChannel<'a> {
+    pub transport: &'a NetTransport,
+    pub stream: &'a mut TcpStream,
+    authenticated: bool,
+}
+
+impl<'a> PskChannel<'a> {
+    pub(crate) fn new(transport: &'a NetTransport, stream: &'a mut TcpStream) -> Self {
+        Self {
+            transport,
+            stream,
+            authenticated: false,
+        }
+    }
+}
+
+impl FrameChannel for PskChannel<'_> {
+    fn send(&mut self, data: &[u8]) -> Result<(), SyncError> {
+        let sealed = self.transport.seal(data)?;
+        let len = u32::try_from(sealed.len())
+            .ok()
+            .filter(|len| *len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| SyncError::Invalid("frame too large".into()))?;
+        self.stream
+            .write_all(&len.to_be_bytes())
+            .and_then(|_| self.stream.write_all(&sealed))
+            .map_err(|e| SyncError::Io(e.to_string()))
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, SyncError> {
+        let mut len = [0u8; 4];
+        self.stream
+            .read_exact(&mut len)
+            .map_err(|e| SyncError::Io(e.to_string()))?;
+        let len = u32::from_be_bytes(len) as usize;
+        let limit = match self.authenticated {
+            true => MAX_FRAME_LEN,
+            false => MAX_UNAUTHENTICATED_FRAME_LEN,
+        };
+        if len > limit {
+            return Err(SyncError::Invalid("frame too large".into()));
+        }
+        let mut buf = Vec::new();
+        let mut chunk = vec![0u8; len.min(READ_CHUNK)];
+        while buf.len() < len {
+            let n = (len - buf.len()).min(chunk.len());
+            self.stream
+                .read_exact(&mut chunk[..n])
+                .map_err(|e| SyncError::Io(e.to_string()))?;
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let plain = self.transport.open(&buf)?;
+        if self.transport.psk.is_some() {
+            self.authenticated = true;
+        }
+        Ok(plain)
+    }
+
+    fn peer_verified(&mut self) {
+        self.authenticated = true;
+    }
+}
+
+pub(crate) fn write_frame<T: Serialize>(
+    channel: &mut dyn FrameChannel,
+    frame: &T,
+) -> Result<(), SyncError> {
+    let raw = serde_json::to_vec(frame).map_err(|e| SyncError::Io(e.to_string()))?;
+    channel.send(&raw)
+}
+
+pub(crate) fn read_frame<T: DeserializeOwned>(
+    channel: &mut dyn FrameChannel,
+) -> Result<T, SyncError> {
+    serde_json::from_slice(&channel.recv()?).map_err(|e| SyncError::Io(e.to_string()))
+}
+
+pub(crate) fn signed_envelope(
+    identity: &DeviceIdentity,
+    ops: Vec<Operation>,
+) -> Result<SyncEnvelope, SyncError> {
+    let payload = signing_bytes(&ops)?;
+    Ok(SyncEnvelope {
+        device_id: identity.device_id.clone(),
+        public_key: identity.public_key.clone(),
+        signature: identity.sign(&payload)?,
+        ops,
+    })
+}
+
+/// The claimed peer must be trusted and, if the channel authenticated someone, be them.
+fn check_peer(
+    channel: &dyn FrameChannel,
+    trust: &TrustStore,
+    device_id: &str,
+    public_key: &str,
+) -> bool {
+    let matches_channel = channel
+        .authenticated_peer()
+        .map(|p| p.device_id == device_id && p.public_key == public_key)
+        .unwrap_or(true);
+    matches_channel && trust.is_trusted(device_id, public_key)
+}
+
+/// Our hello: identity, what we hold (and compacted), and whether we exchange blobs.
+fn hello(identity: &DeviceIdentity, local_ops: &[Operation], net: &NetTransport) -> Frame {
+    Frame::Hello(SessionHello {
+        device_id: identity.device_id.clone(),
+        public_key: identity.public_key.clone(),
+        summary: SyncSummary::from_ops(local_ops).with_checkpoint(net.checkpoint.clone()),
+        blobs: net.blob_dir.is_some(),
+    })
+}
+
+/// Run the initiator side of a session; blob directory and checkpoint come from `net`.
+pub(crate) fn run_session(
+    channel: &mut dyn FrameChannel,
+    identity: &DeviceIdentity,
+    trust: &TrustStore,
+    local_ops: &[Operation],
+    apply: &mut dyn FnMut(Vec<Operation>) -> SyncAck,
+    net: &NetTransport,
+) -> Result<SyncReport, SyncError> {
+    write_frame(channel, &hello(identity, local_ops, net))?;
+    let peer = match read_frame(channel)? {
+        Frame::Hello(hello) => hello,
+        Frame::Error {
+            reason: RejectReason::NotTrusted,
+        } => return Err(SyncError::NotTrusted),
+        Frame::Error { reason } => return Err(SyncError::Invalid(reason.to_string())),
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    if !check_peer(channel, trust, &peer.device_id, &peer.public_key) {
+        return Err(SyncError::NotTrusted);
+    }
+    channel.peer_verified();
+    peer.summary.check_protocol()?;
+
+    let outgoing = missing_ops(local_ops, &peer.summary);
+    let sent = outgoing.len();
+    write_frame(
+        channel,
+        &Frame::Envelope(signed_envelope(identity, outgoing)?),
+    )?;
+    let push_ack = match read_frame(channel)? {
+        Frame::Ack(ack) => ack,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+
+    let incoming = match read_frame(channel)? {
+        Frame::Envelope(envelope) => envelope,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    let received = incoming.ops.len();
+    let pull_ack = match check_envelope(&incoming, &peer) {
+        Ok(()) if incoming.ops.is_empty() => SyncAck::default(),
+        Ok(()) => apply(incoming.ops.clone()),
+        Err(reason) => SyncAck::reject_all(&incoming.ops, reason),
+    };
+    write_frame(channel, &Frame::Ack(pull_ack.clone()))?;
+
+    let (mut blobs_sent, mut blobs_received) = (0, 0);
+    if let Some(dir) = net.blob_dir.as_ref().filter(|_| peer.blobs) {
+        let mut merged = local_ops.to_vec();
+        merged.extend(
+            incoming
+                .ops
+                .into_iter()
+                .filter(|op| pull_ack.accepted.contains(&op.key())),
+        );
+        blobs_received = blobs::receive_blobs(channel, dir, &merged)?;
+        blobs_sent = blobs::send_blobs(channel, dir)?;
+    }
+
+    Ok(SyncReport {
+        sent,
+        push_ack: Some(push_ack),
+        received,
+        pull_ack,
+        blobs_sent,
+        blobs_received,
+        peer_checkpoint: peer.summary.checkpoint,
+    })
+}
+
+/// Push one envelope without a session and return the peer's ack.
+pub(crate) fn push_envelope(
+    channel: &mut dyn FrameChannel,
+    envelope: &SyncEnvelope,
+) -> Result<SyncAck, SyncError> {
+    write_frame(channel, &Frame::Envelope(envelope.clone()))?;
+    match read_frame(channel)? {
+        Frame::Ack(ack) => Ok(ack),
+        Frame::Error {
+            reason: RejectReason::NotTrusted,
+        } => Err(SyncError::NotTrusted),
+        Frame::Error { reason } => Err(SyncError::Invalid(reason.to_string())),
+        _ => Err(SyncError::HandshakeFailed),
+    }
+}
+
+/// Run the responder side of a session. `op_handler` decides which pushed ops are accepted;
+/// `local_ops` is queried again after it ran so the reply doesn't echo the pushed ops back.
+pub(crate) fn serve_session(
+    channel: &mut dyn FrameChannel,
+    addr: SocketAddr,
+    identity: &DeviceIdentity,
+    trust: &TrustStore,
+    local_ops: impl Fn() -> Vec<Operation>,
+    mut op_handler: impl FnMut(SocketAddr, Vec<Operation>) -> SyncAck,
+    net: &NetTransport,
+) -> Result<(), SyncError> {
+    let peer = match read_frame(channel)? {
+        Frame::Hello(hello) => hello,
+        Frame::Envelope(envelope) => {
+            if !check_peer(channel, trust, &envelope.device_id, &envelope.public_key) {
+                let _ = write_frame(
+                    channel,
+                    &Frame::Error {
+                        reason: RejectReason::NotTrusted,
+                    },
+                );
+                return Err(SyncError::NotTrusted);
+            }
+            let ack = match envelope.verify() {
+                Ok(()) => op_handler(addr, envelope.ops),
+                Err(_) => SyncAck::reject_all(&envelope.ops, RejectReason::BadSignature),
+            };
+            return write_frame(channel, &Frame::Ack(ack));
+        }
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    if !check_peer(channel, trust, &peer.device_id, &peer.public_key) {
+        let _ = write_frame(
+            channel,
+            &Frame::Error {
+                reason: RejectReason::NotTrusted,
+            },
+        );
+        return Err(SyncError::NotTrusted);
+    }
+    channel.peer_verified();
+    if let Err(e) = peer.summary.check_protocol() {
+        let _ = write_frame(
+            channel,
+            &Frame::Error {
+                reason: RejectReason::Invalid(e.to_string()),
+            },
+        );
+        return Err(e);
+    }
+    write_frame(channel, &hello(identity, &local_ops(), net))?;
+
+    let incoming = match read_frame(channel)? {
+        Frame::Envelope(envelope) => envelope,
+        _ => return Err(SyncError::HandshakeFailed),
+    };
+    let ack = match check_envelope(&incoming, &peer) {
+        Ok(()) if incoming.ops.is_empty() => SyncAck::default(),
+        Ok(()) => op_handler(addr, incoming.ops.clone()),
+        Err(reason) => SyncAck::reject_all(&incoming.ops, reason),
+    };
+    write_frame(channel, &Frame::Ack(ack))?;
+
+    let pushed: HashSet<String> = incoming.ops.iter().map(|op| op.key()).collect();
+    let outgoing: Vec<Operation> = missing_ops(&local_ops(), &peer.summary)
+        .into_iter()
+        .filter(|op| !pushed.contains(&op.key()))
+        .collect();
+    write_frame(
+        channel,
+        &Frame::Envelope(signed_envelope(identity, outgoing)?),
+    )?;
+    match read_frame(channel)? {
+        Frame::Ack(_) => {}
+        _ => return Err(SyncError::HandshakeFailed),
+    }
+
+    if let Some(dir) = net.blob_dir.as_ref().filter(|_| peer.blobs) {
+        blobs::send_blobs(channel, dir)?;
+        blobs::receive_blobs(channel, dir, &local_ops())?;
+    }
+    Ok(())
+}
+
+/// The envelope must come from the device that said hello and carry a valid signature.
+fn check_envelope(envelope: &SyncEnvelope, peer: &SessionHello) -> Result<(), RejectReason> {
+    if envelope.device_id != peer.device_id || envelope.public_key != peer.public_key {
+        return Err(RejectReason::NotTrusted);
+    }
+    envelope.verify().map_err(|_| RejectReason::BadSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transport;
+    use notes_oplog::{OperationType, VectorClock};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    fn op(device: &str, id: &str) -> Operation {
+        Operation {
+            op_id: id.into(),
+            device_id: device.into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            op_type: OperationType::CreateDocument,
+            document_id: format!("doc-{device}"),
+            payload: serde_json::Value::Null,
+            before_hash: None,
+            after_hash: None,
+            clock: VectorClock::new(),
+        }
+    }
+
+    #[test]
+    fn session_pushes_pulls_and_acks() {
+        let dir = tempdir().unwrap();
+        let (client_id, server_id) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let mut client_trust = TrustStore::load_or_default(dir.path().join("client.json")).unwrap();
+        client_trust
+            .add(server_id.device_id.clone(), server_id.public_key.clone())
+            .unwrap();
+        let mut server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+        server_trust
+            .add(client_id.device_id.clone(), client_id.public_key.clone())
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new_with_psk(0, addr.port(), Some([3u8; 32]));
+        let server = transport.clone();
+        let handle = std::thread::spawn(move || {
+            let server_ops = Mutex::new(vec![op("b", "01")]);
+            let (stream, peer) = listener.accept().unwrap();
+            server.serve_connection(
+                stream,
+                peer,
+                &server_id,
+                &server_trust,
+                || server_ops.lock().unwrap().clone(),
+                |_, ops| {
+                    let mut ack = SyncAck::default();
+                    for op in ops {
+                        if op.op_id == "02" {
+                            ack.rejected.push(RejectedOp {
+                                op_key: op.key(),
+                                reason: RejectReason::Conflict,
+                            });
+                        } else {
+                            ack.accepted.push(op.key());
+                            server_ops.lock().unwrap().push(op);
+                        }
+                    }
+                    ack
+                },
+            )
+        });
+
+        let mut pulled = Vec::new();
+        let report = transport
+            .session(
+                &addr.to_string(),
+                &client_id,
+                &client_trust,
+                &[op("a", "01"), op("a", "02")],
+                &mut |ops| {
+                    let ack = SyncAck {
+                        accepted: ops.iter().map(|o| o.key()).collect(),
+                        rejected: Vec::new(),
+                        superseded: Vec::new(),
+                    };
+                    pulled.extend(ops);
+                    ack
+                },
+            )
+            .unwrap();
+        handle.join().unwrap().unwrap();
+
+        assert_eq!(report.sent, 2);
+        let push_ack = report.push_ack.unwrap();
+        assert_eq!(push_ack.accepted, vec!["a:01"]);
+        assert_eq!(push_ack.rejected[0].op_key, "a:02");
+        assert_eq!(push_ack.rejected[0].reason, RejectReason::Conflict);
+        assert_eq!(report.received, 1);
+        assert_eq!(report.pull_ack.accepted, vec!["b:01"]);
+        assert_eq!(pulled.len(), 1);
+    }
+
+    #[test]
+    fn session_refused_by_untrusting_peer() {
+        let dir = tempdir().unwrap();
+        let (client_id, server_id) = (DeviceIdentity::generate(), DeviceIdentity::generate());
+        let client_trust = TrustStore::load_or_default(dir.path().join("client.json")).unwrap();
+        let server_trust = TrustStore::load_or_default(dir.path().join("server.json")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new(0, addr.port());
+        let server = transport.clone();
+        let handle = std::thread::spawn(move || {
+            let (stream, peer) = listener.accept().unwrap();
+            server.serve_connection(stream, peer, &server_id, &server_trust, Vec::new, |_, _| {
+                SyncAck::default()
+            })
+        });
+        let result = transport.session(
+            &addr.to_string(),
+            &client_id,
+            &client_trust,
+            &[op("a", "01")],
+            &mut |_| SyncAck::default(),
+        );
+        assert!(matches!(result, Err(SyncError::NotTrusted)));
+        assert!(matches!(handle.join().unwrap(), Err(SyncError::NotTrusted)));
+    }
+
+    #[test]
+    fn large_frames_wait_for_authentication() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = NetTransport::new_with_psk(0, addr.port(), Some([5u8; 32]));
+        let server = transport.clone();
+        let handle = std::thread::spawn(move || {
+            let mut frames = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut channel = PskChannel::new(&server, &mut stream);
+                frames.push(channel.recv().and_then(|_| channel.recv()));
+            }
+            frames
+        });
+        let send = |stream: &mut TcpStream, data: &[u8]| {
+            let sealed = transport.seal(data).unwrap();
+            stream
+                .write_all(&(sealed.len() as u32).to_be_bytes())
+                .and_then(|_| stream.write_all(&sealed))
+        };
+
+        // before anything has opened with the PSK, a large frame is refused by its header
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(&((MAX_UNAUTHENTICATED_FRAME_LEN + 1) as u32).to_be_bytes())
+            .unwrap();
+        // once one has, large frames are read
+        let mut stream = TcpStream::connect(addr).unwrap();
+        send(&mut stream, b"hello").unwrap();
+        send(&mut stream, &vec![7u8; 2 * MAX_UNAUTHENTICATED_FRAME_LEN]).unwrap();
+
+        let frames = handle.join().unwrap();
+        assert!(matches!(frames[0], Err(SyncError::Invalid(_))));
+        assert_eq!(
+            frames[1].as_ref().unwrap().len(),
+            2 * MAX_UNAUTHENTICATED_FRAME_LEN
+        );
+    }
+}
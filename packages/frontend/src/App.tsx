@@ -20,6 +20,7 @@ type Document = {
     updated: string;
     tags?: string[];
     links?: string[];
+    [property: string]: unknown;
   };
   body: string;
 };
@@ -41,6 +42,21 @@ type TrustedDevice = {
   allow_auto_sync: boolean;
 };
 
+type Attachment = {
+  document_id: string;
+  hash: string;
+  name: string;
+  size: number;
+  added: string;
+  available: boolean;
+};
+
+type Pairing = {
+  device_id: string;
+  public_key: string;
+  code: { digits: string; emoji: string[] };
+};
+
 type SyncEvent = {
   timestamp: string;
   direction: string;
@@ -49,9 +65,54 @@ type SyncEvent = {
   detail?: string;
 };
 
+type SyncAck = {
+  accepted: string[];
+  rejected: { op_key: string; reason: { reason: string; detail?: string } }[];
+};
+
+type SyncReport = {
+  sent: number;
+  push_ack?: SyncAck | null;
+  received: number;
+  pull_ack: SyncAck;
+  blobs_sent: number;
+  blobs_received: number;
+};
+
+type RebuildReport = {
+  root: string;
+  replayed: number;
+  failed: { op_key: string; error: string }[];
+  drift: {
+    document_id: string;
+    kind: "missing" | "extra" | "hash_different";
+    live_hash?: string | null;
+    rebuilt_hash?: string | null;
+  }[];
+};
+
+type ReindexReport = {
+  indexed: number;
+  removed: number;
+  unparseable: { path: string; error: string }[];
+};
+
+type CompactionReport = {
+  documents: number;
+  removed: number;
+};
+
+type TrashEntry = {
+  document_id: string;
+  title?: string | null;
+  deleted_at: string;
+  op_key?: string | null;
+};
+
 export function App() {
   const [status, setStatus] = useState("checking...");
   const [docs, setDocs] = useState<DocumentSummary[]>([]);
+  const [selectedFrontmatter, setSelectedFrontmatter] = useState<Document["frontmatter"] | null>(null);
   const [selectedId, setSelectedId] = useState<string | null>(null);
   const [docBody, setDocBody] = useState("");
   const [title, setTitle] = useState("");
@@ -75,6 +136,11 @@ export function App() {
   const [syncPort, setSyncPort] = useState<number>(53334);
   const [transportSecret, setTransportSecret] = useState<string>("");
   const [syncEvents, setSyncEvents] = useState<SyncEvent[]>([]);
+  const [pairings, setPairings] = useState<Pairing[]>([]);
+  const [attachments, setAttachments] = useState<Attachment[]>([]);
+  const [vaultCheck, setVaultCheck] = useState<string>("");
+  const [trash, setTrash] = useState<TrashEntry[]>([]);
+  const [trashRetention, setTrashRetention] = useState<number>(30);
 
   useEffect(() => {
     invoke<string>("health_check")
@@ -86,6 +152,7 @@ export function App() {
     loadAutoSync();
     loadNetworkConfig();
     loadSyncEvents();
+    loadTrash();
   }, []);
 
   async function refreshList() {
@@ -140,9 +207,11 @@ export function App() {
     try {
       const doc = await invoke<Document>("get_document", { id });
       setSelectedId(id);
+      setSelectedFrontmatter(doc.frontmatter);
       setDocBody(doc.body);
       setTitle(doc.frontmatter.title || "");
       setTagsInput((doc.frontmatter.tags || []).join(", "));
+      await loadAttachments(id);
     } catch (err: any) {
       setError(String(err));
     } finally {
@@ -187,6 +256,41 @@ export function App() {
     }
   }
 
+  async function loadPairings() {
+    try {
+      const list = await invoke<Pairing[]>("list_pairing_requests");
+      setPairings(list);
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
+  async function pairPeer(peer: Peer) {
+    setError(null);
+    setPeerMessage(`pairing with ${peer.device_id || peer.addr}...`);
+    try {
+      await invoke<Pairing>("start_pairing", { addr: peer.addr });
+      await loadPairings();
+      setPeerMessage("compare the code with the other device");
+    } catch (err: any) {
+      setPeerMessage("pairing failed");
+      setError(String(err));
+    }
+  }
+
+  async function confirmPairing(deviceId: string, accept: boolean) {
+    setError(null);
+    try {
+      const paired = await invoke<boolean>("confirm_pairing", { device_id: deviceId, accept });
+      setPeerMessage(paired ? `paired with ${deviceId}` : `pairing with ${deviceId} cancelled`);
+      await loadPairings();
+      await loadTrusted();
+    } catch (err: any) {
+      setError(String(err));
+      await loadPairings();
+    }
+  }
+
   async function removeTrust(deviceId: string) {
     setError(null);
     try {
@@ -256,8 +360,14 @@ export function App() {
     setError(null);
     setSyncStatus(`syncing ${peer}...`);
     try {
-      await invoke("sync_now", { target_device: peer });
-      setSyncStatus(`synced ${peer}`);
+      const report = await invoke<SyncReport>("sync_now", { target_device: peer });
+      const rejected =
+        (report.push_ack?.rejected.length ?? 0) + report.pull_ack.rejected.length;
+      setSyncStatus(
+        `synced ${peer}: sent ${report.sent}, received ${report.received}` +
+          (report.blobs_received > 0 ? `, ${report.blobs_received} attachment(s)` : "") +
+          (rejected > 0 ? `, ${rejected} rejected (see sync log)` : "")
+      );
     } catch (err: any) {
       setSyncStatus(`error syncing ${peer}`);
       setError(String(err));
@@ -296,18 +406,21 @@ export function App() {
         .split(",")
         .map((t) => t.trim())
         .filter((t) => t.length > 0);
+      // keep the type, links and custom properties the note already has
       const frontmatter = {
-        id: selectedId,
         type: "note",
-        title: title || undefined,
-        tags,
         links: [],
         created: "",
+        ...selectedFrontmatter,
+        id: selectedId,
+        title: title || undefined,
+        tags,
         updated: "",
       };
-      await invoke<Document>("update_document", {
+      const saved = await invoke<Document>("update_document", {
         req: { id: selectedId, frontmatter, body: docBody, before_hash: null },
       });
+      setSelectedFrontmatter(saved.frontmatter);
       await refreshList();
       setError(null);
     } catch (err: any) {
@@ -317,6 +430,40 @@ export function App() {
     }
   }
 
+  async function loadAttachments(id: string) {
+    try {
+      const list = await invoke<Attachment[]>("list_attachments", { document_id: id });
+      setAttachments(list);
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
+  async function attachFiles(files: FileList | null) {
+    if (!selectedId || !files) return;
+    setError(null);
+    try {
+      for (const file of Array.from(files)) {
+        const data = Array.from(new Uint8Array(await file.arrayBuffer()));
+        await invoke<Attachment>("attach_file", { document_id: selectedId, name: file.name, data });
+      }
+      await loadAttachments(selectedId);
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
+  async function detachFile(hash: string) {
+    if (!selectedId) return;
+    setError(null);
+    try {
+      await invoke("detach_file", { document_id: selectedId, hash });
+      await loadAttachments(selectedId);
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
   async function changeVault() {
     if (!vaultInput) return;
     setLoading(true);
@@ -335,6 +482,78 @@ export function App() {
     }
   }
 
+  async function reindexVault() {
+    setLoading(true);
+    setError(null);
+    try {
+      const report = await invoke<ReindexReport>("reindex_vault");
+      setVaultCheck(
+        `reindexed ${report.indexed} note(s), dropped ${report.removed}` +
+          (report.unparseable.length > 0
+            ? `; unreadable: ${report.unparseable.map((f) => `${f.path} (${f.error})`).join(", ")}`
+            : "")
+      );
+      await refreshList();
+    } catch (err: any) {
+      setError(String(err));
+    } finally {
+      setLoading(false);
+    }
+  }
+
+  async function verifyVault() {
+    setLoading(true);
+    setError(null);
+    try {
+      const report = await invoke<RebuildReport>("rebuild_vault");
+      const drift = report.drift.map((d) => `${d.document_id} (${d.kind.replace("_", " ")})`);
+      setVaultCheck(
+        drift.length === 0 && report.failed.length === 0
+          ? `matches history (${report.replayed} ops replayed)`
+          : `drift: ${drift.join(", ") || "none"}` +
+              (report.failed.length > 0 ? `; ${report.failed.length} op(s) failed to replay` : "") +
+              ` — rebuilt copy in ${report.root}`
+      );
+    } catch (err: any) {
+      setError(String(err));
+    } finally {
+      setLoading(false);
+    }
+  }
+
+  async function loadTrash() {
+    try {
+      setTrash(await invoke<TrashEntry[]>("list_trash"));
+      setTrashRetention(await invoke<number>("get_trash_retention_days"));
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
+  async function restoreFromTrash(id: string) {
+    setError(null);
+    try {
+      await invoke("restore_from_trash", { id });
+      await refreshList();
+      await loadTrash();
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
+  async function saveTrashRetention() {
+    setError(null);
+    try {
+      const purged = await invoke<number>("set_trash_retention_days", { days: Number(trashRetention) });
+      if (purged > 0) {
+        setVaultCheck(`purged ${purged} note(s) from the trash`);
+      }
+      await loadTrash();
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
   async function loadTrusted() {
     try {
       const list = await invoke<TrustedDevice[]>("list_trusted_devices");
@@ -353,6 +572,16 @@ export function App() {
     }
   }
 
+  async function compactHistory() {
+    setError(null);
+    try {
+      const report = await invoke<CompactionReport>("compact_op_log", { older_than_days: 30 });
+      setSyncStatus(`compacted ${report.documents} document(s), dropped ${report.removed} op(s)`);
+    } catch (err: any) {
+      setError(String(err));
+    }
+  }
+
   async function loadDevice() {
     try {
       const ident = await invoke<DeviceIdentity>("get_device_identity");
@@ -422,6 +651,13 @@ export function App() {
           <button onClick={changeVault} disabled={loading} style={{ marginTop: "0.25rem" }}>
             Set Vault
           </button>
+          <button onClick={verifyVault} disabled={loading} style={{ marginTop: "0.25rem", marginLeft: "0.25rem" }}>
+            Verify against history
+          </button>
+          <button onClick={reindexVault} disabled={loading} style={{ marginTop: "0.25rem", marginLeft: "0.25rem" }}>
+            Reindex
+          </button>
+          {vaultCheck && <div style={{ fontSize: "0.75rem", color: "#555", marginTop: "0.25rem" }}>{vaultCheck}</div>}
           <div style={{ fontSize: "0.75rem", color: "#888", marginTop: "0.25rem" }}>{vault}</div>
         </div>
         <button onClick={createNote} disabled={creating}>
@@ -475,6 +711,39 @@ export function App() {
             ))
           )}
         </ul>
+        <details style={{ marginTop: "0.5rem", fontSize: "0.85rem" }} onToggle={() => loadTrash()}>
+          <summary style={{ cursor: "pointer", color: "#555" }}>Trash ({trash.length})</summary>
+          {trash.length === 0 ? (
+            <div style={{ color: "#999", fontSize: "0.8rem" }}>Trash is empty.</div>
+          ) : (
+            <ul style={{ listStyle: "none", padding: 0, margin: "0.25rem 0" }}>
+              {trash.map((t) => (
+                <li key={t.document_id} style={{ marginBottom: "0.25rem" }}>
+                  <span>{t.title && t.title.length > 0 ? t.title : t.document_id.slice(0, 8)}</span>
+                  <span style={{ color: "#888", fontSize: "0.75rem" }}>
+                    {" "}
+                    • {new Date(t.deleted_at).toLocaleString()}
+                  </span>
+                  <button onClick={() => restoreFromTrash(t.document_id)} style={{ marginLeft: "0.25rem" }}>
+                    Restore
+                  </button>
+                </li>
+              ))}
+            </ul>
+          )}
+          <div style={{ color: "#555", fontSize: "0.8rem" }}>
+            Keep for{" "}
+            <input
+              type="number"
+              min={0}
+              value={trashRetention}
+              onChange={(e) => setTrashRetention(Number(e.target.value))}
+              style={{ width: "3.5rem" }}
+            />{" "}
+            days{" "}
+            <button onClick={saveTrashRetention}>Save</button>
+          </div>
+        </details>
         <div style={{ marginTop: "1rem" }}>
           <button onClick={discoverPeers} disabled={loading}>
             Discover Peers
@@ -510,10 +779,34 @@ export function App() {
                   <button style={{ marginLeft: "0.5rem" }} onClick={() => syncPeer(p.addr)}>
                     Sync
                   </button>
+                  <button style={{ marginLeft: "0.5rem" }} onClick={() => pairPeer(p)}>
+                    Pair
+                  </button>
                 </li>
               ))}
             </ul>
           )}
+          <div style={{ marginTop: "0.5rem" }}>
+            <button onClick={loadPairings}>Show Pairing Requests</button>
+            {pairings.length > 0 && (
+              <ul style={{ listStyle: "none", padding: 0, marginTop: "0.25rem" }}>
+                {pairings.map((p) => (
+                  <li key={p.device_id} style={{ fontSize: "0.8rem", marginBottom: "0.35rem" }}>
+                    <div>{p.device_id} ({p.public_key.slice(0, 10)}...)</div>
+                    <div style={{ fontSize: "1.1rem", letterSpacing: "0.15rem" }}>{p.code.digits}</div>
+                    <div style={{ fontSize: "1.1rem" }}>{p.code.emoji.join(" ")}</div>
+                    <div style={{ fontSize: "0.75rem", color: "#666" }}>
+                      Confirm only if the other device shows the same code.
+                    </div>
+                    <button onClick={() => confirmPairing(p.device_id, true)}>Codes match</button>
+                    <button style={{ marginLeft: "0.4rem" }} onClick={() => confirmPairing(p.device_id, false)}>
+                      Reject
+                    </button>
+                  </li>
+                ))}
+              </ul>
+            )}
+          </div>
           <div style={{ marginTop: "0.5rem" }}>
             <button onClick={loadTrusted}>Show Trusted Devices</button>
             {trusted.length > 0 && (
@@ -614,6 +907,9 @@ export function App() {
               <div style={{ fontSize: "0.9rem", fontWeight: 600 }}>Sync Log</div>
               <div style={{ display: "flex", gap: "0.5rem", alignItems: "center", marginBottom: "0.35rem" }}>
                 <button onClick={loadSyncEvents}>Refresh</button>
+                <button onClick={compactHistory} title="Fold each note's ops older than 30 days into one snapshot">
+                  Compact history
+                </button>
                 <span style={{ fontSize: "0.8rem", color: "#666" }}>showing last {Math.min(syncEvents.length, 8)}</span>
               </div>
               <ul style={{ listStyle: "none", padding: 0, margin: 0, fontSize: "0.8rem", color: "#333" }}>
@@ -669,6 +965,21 @@ export function App() {
                   Save
                 </button>
               </div>
+              <div style={{ marginTop: "0.75rem" }}>
+                <div style={{ fontSize: "0.85rem", color: "#666" }}>Attachments</div>
+                <input type="file" multiple onChange={(e) => attachFiles(e.target.files)} />
+                <ul style={{ listStyle: "none", padding: 0, marginTop: "0.25rem" }}>
+                  {attachments.map((a) => (
+                    <li key={a.hash} style={{ fontSize: "0.8rem", marginBottom: "0.25rem" }}>
+                      {a.name} ({Math.ceil(a.size / 1024)} KB)
+                      {!a.available && <span style={{ color: "#999" }}> — waiting for sync</span>}
+                      <button style={{ marginLeft: "0.4rem" }} onClick={() => detachFile(a.hash)}>
+                        Remove
+                      </button>
+                    </li>
+                  ))}
+                </ul>
+              </div>
             </div>
             <div
               style={{